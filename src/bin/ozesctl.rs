@@ -0,0 +1,476 @@
+use std::{env, fs, path::PathBuf, process, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ozes::{client::Transport, connection::BoxedStream, server::ErrorCode};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time,
+};
+
+const USAGE: &str = "usage: ozesctl [options] <command>
+
+commands:
+    queues                      list queues
+    groups [queue]              list groups and their consumers
+    connections                 list connections
+    create <queue> [partitions] create a queue
+    delete <queue>              delete a queue and drop its consumers
+    purge <queue>               drop the messages waiting in a queue
+    move <from> <to> [limit]    move waiting messages to another queue
+    kick <session>              close a connection
+    peek <queue> [offset] [count]
+                                show waiting messages without consuming them
+
+options:
+    --host <host>               broker host, default localhost
+    --port <port>               broker port, default 7656
+    --tls                       connect with TLS, the host is the server name
+    --ca <path>                 PEM CA that signed the broker certificate
+    --unix <path>               connect to a unix socket instead
+    --user <user>               authenticate with user and password, the
+                                password is read from OZES_PASSWORD, from
+                                --password-file or asked
+    --password-file <path>
+    --token <token>             authenticate with a token, or OZES_TOKEN
+    --json                      print JSON instead of tables";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const PAYLOAD_PREVIEW: usize = 48;
+
+struct Options {
+    host: String,
+    port: u16,
+    tls: bool,
+    ca: Option<PathBuf>,
+    unix: Option<PathBuf>,
+    user: Option<String>,
+    password_file: Option<PathBuf>,
+    token: Option<String>,
+    json: bool,
+    command: Vec<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        host: String::from("localhost"),
+        port: 7656,
+        tls: false,
+        ca: None,
+        unix: None,
+        user: None,
+        password_file: None,
+        token: env::var("OZES_TOKEN").ok(),
+        json: false,
+        command: vec![],
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("missing value of {name}"));
+        match arg.as_str() {
+            "--host" => options.host = value("--host")?,
+            "--port" => {
+                options.port = value("--port")?
+                    .parse()
+                    .map_err(|_| String::from("invalid port"))?
+            }
+            "--tls" => options.tls = true,
+            "--ca" => options.ca = Some(PathBuf::from(value("--ca")?)),
+            "--unix" => options.unix = Some(PathBuf::from(value("--unix")?)),
+            "--user" => options.user = Some(value("--user")?),
+            "--password-file" => {
+                options.password_file = Some(PathBuf::from(value("--password-file")?))
+            }
+            "--password" => {
+                return Err(String::from(
+                    "--password is not accepted, set OZES_PASSWORD, use --password-file or type it when asked",
+                ))
+            }
+            "--token" => options.token = Some(value("--token")?),
+            "--json" => options.json = true,
+            "-h" | "--help" => return Err(String::new()),
+            _ => options.command.push(arg),
+        }
+    }
+    if options.command.is_empty() {
+        return Err(String::new());
+    }
+    Ok(options)
+}
+
+/// Password of `--user`, from `OZES_PASSWORD`, the password file or asked,
+/// never from the arguments that other users can see.
+fn password(options: &Options, user: &str) -> Result<String, String> {
+    if let Ok(password) = env::var("OZES_PASSWORD") {
+        return Ok(password);
+    }
+    match &options.password_file {
+        Some(path) => fs::read_to_string(path)
+            .map(|password| password.trim_end_matches(['\r', '\n']).to_string())
+            .map_err(|error| format!("cannot read {}: {error}", path.display())),
+        None => rpassword::prompt_password(format!("password of {user}: "))
+            .map_err(|error| format!("cannot read the password: {error}")),
+    }
+}
+
+fn transport(options: &Options) -> Result<Transport, String> {
+    match (&options.unix, options.tls, &options.ca) {
+        (Some(path), _, _) => Ok(Transport::Unix(path.clone())),
+        (None, true, Some(ca)) => Transport::tls_with_ca(ca).map_err(|error| error.to_string()),
+        (None, true, None) => Err(String::from("--tls needs --ca <path>")),
+        (None, false, _) => Ok(Transport::Tcp),
+    }
+}
+
+struct Client {
+    stream: BoxedStream,
+}
+
+impl Client {
+    async fn connect(options: &Options) -> Result<Self, String> {
+        let address = format!("{}:{}", options.host, options.port);
+        let stream = transport(options)?
+            .connect(&address, TIMEOUT)
+            .await
+            .map_err(|error| error.to_string())?;
+        let mut client = Self { stream };
+        client
+            .request(&format!(
+                "CLIENT NAME ozesctl VERSION {};",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .await?;
+        match (&options.user, &options.token) {
+            (Some(user), _) => {
+                let password = password(options, user)?;
+                client
+                    .request(&format!("AUTH USER {user} PASSWORD {password};"))
+                    .await?;
+            }
+            (None, Some(token)) => {
+                client.request(&format!("AUTH TOKEN {token};")).await?;
+            }
+            _ => {}
+        }
+        Ok(client)
+    }
+
+    /// Send the command and return the body of the `ok` reply.
+    async fn request(&mut self, command: &str) -> Result<String, String> {
+        self.stream
+            .write_all(command.as_bytes())
+            .await
+            .map_err(|error| error.to_string())?;
+        let mut reply = vec![];
+        let mut buffer = vec![0; 4096];
+        loop {
+            let size = time::timeout(TIMEOUT, self.stream.read(&mut buffer))
+                .await
+                .map_err(|_| String::from("timeout waiting the broker"))?
+                .map_err(|error| error.to_string())?;
+            if size == 0 {
+                return Err(String::from("connection closed by the broker"));
+            }
+            reply.extend_from_slice(&buffer[..size]);
+            let text = String::from_utf8_lossy(&reply);
+            if let Some(error) = text.strip_prefix("error #") {
+                return Err(match ErrorCode::from_reply(error) {
+                    (Some(code), message) => format!("{message} ({code})"),
+                    (None, message) => message.to_string(),
+                });
+            }
+            let body = match text.strip_prefix("ok ") {
+                Some(body) => body.split_once(' ').map(|(_, body)| body).unwrap_or(""),
+                None => return Err(format!("unexpected reply: {text}")),
+            };
+            // documents can come in more than one read, their len says when
+            // all of them is read
+            let Some((len, _)) = body
+                .strip_prefix("+l")
+                .and_then(|body| body.split_once(' '))
+            else {
+                return Ok(body.to_string());
+            };
+            let header_len = text.len() - body.len() + len.len() + 3;
+            let len: usize = len
+                .parse()
+                .map_err(|_| format!("invalid document len in reply: {text}"))?;
+            if reply.len() >= header_len + len {
+                let document = &reply[header_len..header_len + len];
+                return Ok(String::from_utf8_lossy(document).into_owned());
+            }
+        }
+    }
+
+    async fn request_json(&mut self, command: &str) -> Result<Value, String> {
+        let body = self.request(command).await?;
+        serde_json::from_str(&body).map_err(|error| error.to_string())
+    }
+}
+
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |cells: Vec<&str>| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(headers.to_vec());
+    for row in rows {
+        print_row(row.iter().map(String::as_str).collect());
+    }
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::from("-"),
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn print_queues(stats: &Value) {
+    let rows: Vec<Vec<String>> = stats["queues"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|queue| {
+            vec![
+                text(&queue["name"]),
+                text(&queue["partitions"]),
+                text(&queue["messages"]),
+                text(&queue["published"]),
+                queue["groups"]
+                    .as_array()
+                    .map(Vec::len)
+                    .unwrap_or_default()
+                    .to_string(),
+            ]
+        })
+        .collect();
+    print_table(
+        &["QUEUE", "PARTITIONS", "MESSAGES", "PUBLISHED", "GROUPS"],
+        &rows,
+    );
+}
+
+fn print_groups(stats: &Value) {
+    let mut rows = vec![];
+    for queue in stats["queues"].as_array().into_iter().flatten() {
+        for group in queue["groups"].as_array().into_iter().flatten() {
+            let consumers: Vec<String> = group["consumers"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|consumer| {
+                    format!("{}@{}", consumer["session_id"], text(&consumer["address"]))
+                })
+                .collect();
+            rows.push(vec![
+                text(&queue["name"]),
+                text(&group["name"]),
+                text(&group["mode"]),
+                text(&group["strategy"]),
+                text(&group["members"]),
+                text(&group["delivered"]),
+                consumers.join(","),
+            ]);
+        }
+    }
+    print_table(
+        &[
+            "QUEUE",
+            "GROUP",
+            "MODE",
+            "STRATEGY",
+            "MEMBERS",
+            "DELIVERED",
+            "CONSUMERS",
+        ],
+        &rows,
+    );
+}
+
+fn print_connections(connections: &Value) {
+    let rows: Vec<Vec<String>> = connections["connections"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|connection| {
+            vec![
+                text(&connection["session_id"]),
+                text(&connection["address"]),
+                text(&connection["role"]),
+                text(&connection["name"]),
+                text(&connection["protocol"]),
+                text(&connection["user"]),
+                text(&connection["messages_in"]),
+                text(&connection["messages_out"]),
+            ]
+        })
+        .collect();
+    print_table(
+        &[
+            "SESSION", "ADDRESS", "ROLE", "NAME", "PROTOCOL", "USER", "IN", "OUT",
+        ],
+        &rows,
+    );
+}
+
+fn print_messages(peek: &Value) {
+    let rows: Vec<Vec<String>> = peek["messages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|message| {
+            let payload = STANDARD
+                .decode(message["payload"].as_str().unwrap_or_default())
+                .unwrap_or_default();
+            let mut preview: String = String::from_utf8_lossy(&payload)
+                .chars()
+                .map(|c| if c.is_control() { '.' } else { c })
+                .collect();
+            if preview.chars().count() > PAYLOAD_PREVIEW {
+                preview = preview.chars().take(PAYLOAD_PREVIEW).collect();
+                preview.push_str("...");
+            }
+            vec![
+                text(&message["offset"]),
+                text(&message["size"]),
+                text(&message["key"]),
+                text(&message["partition"]),
+                text(&message["published_at"]),
+                preview,
+            ]
+        })
+        .collect();
+    print_table(
+        &["OFFSET", "SIZE", "KEY", "PARTITION", "PUBLISHED", "PAYLOAD"],
+        &rows,
+    );
+}
+
+async fn run(options: &Options) -> Result<(), String> {
+    let mut client = Client::connect(options).await?;
+    let command: Vec<&str> = options.command.iter().map(String::as_str).collect();
+    let (list, result): (Option<fn(&Value)>, Value) = match command[..] {
+        ["queues"] => (Some(print_queues), client.request_json("STATS;").await?),
+        ["groups"] => (Some(print_groups), client.request_json("STATS;").await?),
+        ["groups", queue] => (
+            Some(print_groups),
+            client
+                .request_json(&format!("STATS QUEUE {queue};"))
+                .await?,
+        ),
+        ["connections"] => (
+            Some(print_connections),
+            client.request_json("CONNECTIONS;").await?,
+        ),
+        ["create", queue] => {
+            client.request(&format!("CREATE QUEUE {queue};")).await?;
+            (None, json!({ "status": "created", "queue": queue }))
+        }
+        ["create", queue, partitions] => {
+            client
+                .request(&format!("CREATE QUEUE {queue} PARTITIONS {partitions};"))
+                .await?;
+            (None, json!({ "status": "created", "queue": queue }))
+        }
+        ["delete", queue] => {
+            client.request(&format!("DELETE QUEUE {queue};")).await?;
+            (None, json!({ "status": "deleted", "queue": queue }))
+        }
+        ["purge", queue] => {
+            let purged = client.request(&format!("PURGE QUEUE {queue};")).await?;
+            (
+                None,
+                json!({ "status": "purged", "queue": queue, "messages": count(&purged) }),
+            )
+        }
+        ["move", from, to] | ["move", from, to, _] => {
+            let limit = match command.get(3) {
+                Some(limit) => format!(" LIMIT {limit}"),
+                None => String::new(),
+            };
+            let moved = client
+                .request(&format!("MOVE QUEUE {from} TO {to}{limit};"))
+                .await?;
+            (
+                None,
+                json!({ "status": "moved", "from": from, "to": to, "messages": count(&moved) }),
+            )
+        }
+        ["peek", queue] | ["peek", queue, _] | ["peek", queue, _, _] => {
+            let peek = format!("PEEK {queue} {}", command[2..].join(" "));
+            (
+                Some(print_messages),
+                client
+                    .request_json(&format!("{};", peek.trim_end()))
+                    .await?,
+            )
+        }
+        ["kick", session] => {
+            client.request(&format!("KICK {session};")).await?;
+            (None, json!({ "status": "kicked", "session": session }))
+        }
+        _ => return Err(String::new()),
+    };
+    match list {
+        Some(print) if !options.json => print(&result),
+        _ if options.json => println!(
+            "{}",
+            serde_json::to_string_pretty(&result).unwrap_or_default()
+        ),
+        _ => println!("{}", summary(&result)),
+    }
+    Ok(())
+}
+
+fn summary(result: &Value) -> String {
+    match result["status"].as_str().unwrap_or_default() {
+        "purged" => format!(
+            "{} messages purged from queue {}",
+            result["messages"],
+            text(&result["queue"])
+        ),
+        "moved" => format!(
+            "{} messages moved from queue {} to {}",
+            result["messages"],
+            text(&result["from"]),
+            text(&result["to"])
+        ),
+        "kicked" => format!("session {} kicked", text(&result["session"])),
+        status => format!("queue {} {}", text(&result["queue"]), status),
+    }
+}
+
+fn count(body: &str) -> u64 {
+    body.trim().parse().unwrap_or_default()
+}
+
+#[tokio::main]
+async fn main() {
+    let result = match parse_args() {
+        Ok(options) => run(&options).await,
+        Err(error) => Err(error),
+    };
+    match result {
+        Ok(()) => {}
+        Err(error) if error.is_empty() => {
+            eprintln!("{USAGE}");
+            process::exit(2);
+        }
+        Err(error) => {
+            eprintln!("error: {error}");
+            process::exit(1);
+        }
+    }
+}
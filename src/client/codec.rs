@@ -0,0 +1,187 @@
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_util::{Stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{ClientError, ClientResult, Consumer, Delivery, Publisher};
+
+/// Header with the codec of the payload.
+pub const CONTENT_TYPE: &str = "content-type";
+
+/// Serialization of the messages of a `TypedPublisher` and `TypedConsumer`.
+pub trait Codec {
+    /// Value of the `content-type` header of the encoded messages.
+    const CONTENT_TYPE: &'static str;
+
+    fn encode<T: Serialize>(value: &T) -> ClientResult<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> ClientResult<T>;
+}
+
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    const CONTENT_TYPE: &'static str = "application/json";
+
+    fn encode<T: Serialize>(value: &T) -> ClientResult<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|error| ClientError::Codec(error.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> ClientResult<T> {
+        serde_json::from_slice(payload).map_err(|error| ClientError::Codec(error.to_string()))
+    }
+}
+
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    const CONTENT_TYPE: &'static str = "application/x-bincode";
+
+    fn encode<T: Serialize>(value: &T) -> ClientResult<Vec<u8>> {
+        bincode::serialize(value).map_err(|error| ClientError::Codec(error.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> ClientResult<T> {
+        bincode::deserialize(payload).map_err(|error| ClientError::Codec(error.to_string()))
+    }
+}
+
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    const CONTENT_TYPE: &'static str = "application/msgpack";
+
+    fn encode<T: Serialize>(value: &T) -> ClientResult<Vec<u8>> {
+        rmp_serde::to_vec_named(value).map_err(|error| ClientError::Codec(error.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> ClientResult<T> {
+        rmp_serde::from_slice(payload).map_err(|error| ClientError::Codec(error.to_string()))
+    }
+}
+
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    const CONTENT_TYPE: &'static str = "application/cbor";
+
+    fn encode<T: Serialize>(value: &T) -> ClientResult<Vec<u8>> {
+        let mut payload = vec![];
+        ciborium::into_writer(value, &mut payload)
+            .map_err(|error| ClientError::Codec(error.to_string()))?;
+        Ok(payload)
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> ClientResult<T> {
+        ciborium::from_reader(payload).map_err(|error| ClientError::Codec(error.to_string()))
+    }
+}
+
+/// Publisher of values of `T` encoded by `C`.
+pub struct TypedPublisher<T, C> {
+    publisher: Publisher,
+    _marker: PhantomData<fn(&T, C)>,
+}
+
+impl<T: Serialize, C: Codec> TypedPublisher<T, C> {
+    pub(super) fn new(publisher: Publisher) -> Self {
+        Self {
+            publisher,
+            _marker: PhantomData,
+        }
+    }
+
+    pub async fn publish(&mut self, value: &T) -> ClientResult<()> {
+        self.publish_with_key(value, None).await
+    }
+
+    pub async fn publish_with_key(&mut self, value: &T, key: Option<&str>) -> ClientResult<()> {
+        let payload = C::encode(value)?;
+        self.publisher
+            .publish_with_headers(payload, key, &[(CONTENT_TYPE, C::CONTENT_TYPE)])
+            .await
+    }
+
+    /// Publish the value to `queue_name` instead of the queue of the
+    /// publisher.
+    pub async fn publish_to(
+        &mut self,
+        queue_name: &str,
+        value: &T,
+        key: Option<&str>,
+    ) -> ClientResult<()> {
+        let payload = C::encode(value)?;
+        self.publisher
+            .publish_to(queue_name, payload, key, &[(CONTENT_TYPE, C::CONTENT_TYPE)])
+            .await
+    }
+
+    pub fn into_inner(self) -> Publisher {
+        self.publisher
+    }
+}
+
+/// Consumer of values of `T` decoded by `C`, a stream of the values with
+/// the delivery to answer.
+///
+/// Messages that cannot be decoded are rejected, they would fail again if
+/// delivered again.
+pub struct TypedConsumer<T, C> {
+    consumer: Consumer,
+    _marker: PhantomData<fn() -> (T, C)>,
+}
+
+impl<T: DeserializeOwned, C: Codec> TypedConsumer<T, C> {
+    pub(super) fn new(consumer: Consumer) -> Self {
+        Self {
+            consumer,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Wait the next message of the queue.
+    pub async fn recv(&mut self) -> ClientResult<(T, Delivery)> {
+        self.next().await.unwrap_or(Err(ClientError::Disconnected))
+    }
+
+    pub fn into_inner(self) -> Consumer {
+        self.consumer
+    }
+}
+
+impl<T: DeserializeOwned, C: Codec> Stream for TypedConsumer<T, C> {
+    type Item = ClientResult<(T, Delivery)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let delivery = match ready!(this.consumer.poll_next_unpin(cx)) {
+            Some(Ok(delivery)) => delivery,
+            Some(Err(error)) => return Poll::Ready(Some(Err(error))),
+            None => return Poll::Ready(None),
+        };
+        Poll::Ready(Some(match delivery.decode::<T, C>() {
+            Ok(value) => Ok((value, delivery)),
+            Err(error) => {
+                log::error!(
+                    "reject message of queue {} that cannot be decoded: {}",
+                    delivery.metadata().queue_name,
+                    error
+                );
+                delivery.reject_later();
+                Err(error)
+            }
+        }))
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use tokio::runtime::Handle;
+
+use crate::{
+    compression::{Compression, CONTENT_ENCODING},
+    connection::{Connection, OzesConnection},
+    server::{GroupMode, Strategy, SubscribeOptions},
+};
+
+use super::{
+    publisher::message_frame, ClientConfig, ClientConnection, ClientError, ClientResult, Codec,
+    Multiplexer, Reply, TypedConsumer, CONTENT_TYPE,
+};
+
+type Reconnecting = Pin<Box<dyn Future<Output = ClientResult<ClientConnection>> + Send>>;
+
+/// Consumer of a queue in a group, a stream of the delivered messages.
+///
+/// The broker does not deliver other messages of the queue while it waits
+/// the answer to a delivery, up to the `ack_timeout` of the subscribe
+/// options, so deliveries should be answered as soon as possible. When the
+/// connection is lost the consumer connects again with the backoff of the
+/// config and subscribes with the same options, deliveries not answered are
+/// sent again by the broker. Compressed payloads are decompressed before
+/// they are delivered.
+pub struct Consumer {
+    config: ClientConfig,
+    /// Set when the consumer is a channel of a `Client`.
+    multiplexer: Option<Arc<Multiplexer>>,
+    subscribe: String,
+    metadata: Metadata,
+    connection: ClientConnection,
+    reconnecting: Option<Reconnecting>,
+}
+
+/// Where a delivery comes from and the headers it was published with.
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    pub queue_name: String,
+    pub group_name: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Metadata {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header(CONTENT_TYPE)
+    }
+}
+
+/// Message delivered to a consumer, it has to be answered with `ack`, `nack`
+/// or `reject`. Dropping it without an answer is a `nack(true)`.
+pub struct Delivery {
+    payload: Bytes,
+    len: usize,
+    metadata: Metadata,
+    connection: Arc<OzesConnection>,
+    answered: bool,
+}
+
+impl Consumer {
+    pub async fn subscribe(
+        config: ClientConfig,
+        queue_name: &str,
+        group_name: &str,
+        options: SubscribeOptions,
+    ) -> ClientResult<Self> {
+        Self::register(config, None, queue_name, group_name, options).await
+    }
+
+    pub(super) async fn register(
+        config: ClientConfig,
+        multiplexer: Option<Arc<Multiplexer>>,
+        queue_name: &str,
+        group_name: &str,
+        options: SubscribeOptions,
+    ) -> ClientResult<Self> {
+        let subscribe = subscribe_command(queue_name, group_name, options);
+        let connection =
+            ClientConnection::register(&config, multiplexer.as_deref(), &subscribe, "subscribed")
+                .await?;
+        Ok(Self {
+            config,
+            multiplexer,
+            subscribe,
+            metadata: Metadata {
+                queue_name: queue_name.to_string(),
+                group_name: group_name.to_string(),
+                headers: vec![],
+            },
+            connection,
+            reconnecting: None,
+        })
+    }
+
+    pub fn queue_name(&self) -> &str {
+        &self.metadata.queue_name
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.metadata.group_name
+    }
+
+    /// Wait the next message of the queue.
+    pub async fn recv(&mut self) -> ClientResult<Delivery> {
+        self.next().await.unwrap_or(Err(ClientError::Disconnected))
+    }
+
+    /// Decode the messages as `T` with `C`.
+    pub fn with_codec<T: DeserializeOwned, C: Codec>(self) -> TypedConsumer<T, C> {
+        TypedConsumer::new(self)
+    }
+
+    /// Begin a transaction, the answers to the deliveries and the messages
+    /// of `publish_to` take effect on `commit`. The broker waits the answer
+    /// to a delivery up to the ack timeout, the commit has to come before,
+    /// and losing the connection rolls back.
+    pub async fn begin(&mut self) -> ClientResult<()> {
+        self.connection.request("TX BEGIN;", "begin").await
+    }
+
+    /// Publish a message to `queue_name` in the transaction.
+    pub async fn publish_to(
+        &mut self,
+        queue_name: &str,
+        payload: impl Into<Bytes>,
+        key: Option<&str>,
+        headers: &[(&str, &str)],
+    ) -> ClientResult<()> {
+        let frame = message_frame(&payload.into(), Some(queue_name), key, headers)?;
+        self.connection.request(frame, "message").await
+    }
+
+    /// Publish the messages and answer the delivery, `CommitOutcomeUnknown`
+    /// when the connection is lost before the answer of the broker.
+    pub async fn commit(&mut self) -> ClientResult<()> {
+        match self.connection.request("TX COMMIT;", "commit").await {
+            Err(error) if error.is_transient() => {
+                Err(ClientError::CommitOutcomeUnknown(error.to_string()))
+            }
+            result => result,
+        }
+    }
+
+    /// Drop the messages of the transaction, its delivery is sent again.
+    pub async fn rollback(&mut self) -> ClientResult<()> {
+        self.connection.request("TX ROLLBACK;", "rollback").await
+    }
+
+    fn reconnect(&mut self) {
+        let config = self.config.clone();
+        let multiplexer = self.multiplexer.clone();
+        let subscribe = self.subscribe.clone();
+        self.reconnecting = Some(Box::pin(async move {
+            ClientConnection::reconnect(&config, multiplexer.as_deref(), &subscribe, "subscribed")
+                .await
+        }));
+    }
+}
+
+impl Stream for Consumer {
+    type Item = ClientResult<Delivery>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(reconnecting) = this.reconnecting.as_mut() {
+                let result = ready!(reconnecting.as_mut().poll(cx));
+                this.reconnecting = None;
+                match result {
+                    Ok(connection) => this.connection = connection,
+                    Err(error) => return Poll::Ready(Some(Err(error))),
+                }
+            }
+            let reply = match this.connection.deliveries.pop_front() {
+                Some(delivery) => Ok(delivery),
+                None => ready!(this.connection.replies.poll_recv(cx))
+                    .unwrap_or(Err(ClientError::Disconnected)),
+            };
+            match reply {
+                Ok(Reply::Delivery {
+                    len,
+                    headers,
+                    payload,
+                }) => {
+                    let mut delivery = Delivery {
+                        payload,
+                        len,
+                        metadata: Metadata {
+                            headers,
+                            ..this.metadata.clone()
+                        },
+                        connection: Arc::clone(&this.connection.connection),
+                        answered: false,
+                    };
+                    if let Err(error) = delivery.decompress() {
+                        log::error!(
+                            "reject message of queue {} that cannot be decompressed: {}",
+                            delivery.metadata.queue_name,
+                            error
+                        );
+                        delivery.reject_later();
+                        return Poll::Ready(Some(Err(error)));
+                    }
+                    return Poll::Ready(Some(Ok(delivery)));
+                }
+                Ok(Reply::Error(error)) => return Poll::Ready(Some(Err(error))),
+                Ok(reply) => log::warn!("consumer discard unexpected reply {:?}", reply),
+                Err(error) if error.is_transient() => {
+                    log::info!(
+                        "consumer of {} in group {} reconnecting: {}",
+                        this.metadata.queue_name,
+                        this.metadata.group_name,
+                        error
+                    );
+                    this.reconnect();
+                }
+                Err(error) => return Poll::Ready(Some(Err(error))),
+            }
+        }
+    }
+}
+
+impl Delivery {
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// The message was processed, it is not delivered again.
+    pub async fn ack(mut self) -> ClientResult<()> {
+        let ack = format!("ok +l{};", self.len);
+        self.answer(ack).await
+    }
+
+    /// The message was not processed, with `requeue` it is delivered again,
+    /// to this consumer or another of the group, otherwise it is dropped.
+    pub async fn nack(mut self, requeue: bool) -> ClientResult<()> {
+        let nack = if requeue {
+            format!("NACK +l{};", self.len)
+        } else {
+            format!("REJECT +l{};", self.len)
+        };
+        self.answer(nack).await
+    }
+
+    /// The message cannot be processed, it is dropped.
+    pub async fn reject(self) -> ClientResult<()> {
+        self.nack(false).await
+    }
+
+    /// Decode the payload with `C`, when the message has a content type it
+    /// has to be the one of the codec.
+    pub fn decode<T: DeserializeOwned, C: Codec>(&self) -> ClientResult<T> {
+        match self.metadata.content_type() {
+            Some(content_type) if content_type != C::CONTENT_TYPE => Err(ClientError::Codec(
+                format!("content type {content_type} is not {}", C::CONTENT_TYPE),
+            )),
+            _ => C::decode(&self.payload),
+        }
+    }
+
+    /// Decompress the payload of the `content-encoding` header, that is
+    /// removed from the metadata.
+    fn decompress(&mut self) -> ClientResult<()> {
+        let Some(position) = self
+            .metadata
+            .headers
+            .iter()
+            .position(|(name, _)| name.eq_ignore_ascii_case(CONTENT_ENCODING))
+        else {
+            return Ok(());
+        };
+        let (_, encoding) = self.metadata.headers.remove(position);
+        let payload = encoding
+            .parse::<Compression>()
+            .map_err(ClientError::Codec)?
+            .decompress(&self.payload)
+            .map_err(|error| ClientError::Codec(error.to_string()))?;
+        self.payload = Bytes::from(payload);
+        Ok(())
+    }
+
+    async fn answer(&mut self, answer: String) -> ClientResult<()> {
+        self.answered = true;
+        self.connection.send_message(Bytes::from(answer)).await?;
+        Ok(())
+    }
+
+    /// Reject from a new task, for when it cannot be awaited.
+    pub(super) fn reject_later(mut self) {
+        self.nack_later(false);
+    }
+
+    fn nack_later(&mut self, requeue: bool) {
+        self.answered = true;
+        // without a runtime the broker delivers the message again when the
+        // ack timeout expires
+        if let Ok(handle) = Handle::try_current() {
+            let connection = Arc::clone(&self.connection);
+            let nack = if requeue {
+                format!("NACK +l{};", self.len)
+            } else {
+                format!("REJECT +l{};", self.len)
+            };
+            handle.spawn(async move {
+                let _ = connection.send_message(Bytes::from(nack)).await;
+            });
+        }
+    }
+}
+
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        if !self.answered {
+            self.nack_later(true);
+        }
+    }
+}
+
+fn subscribe_command(queue_name: &str, group_name: &str, options: SubscribeOptions) -> String {
+    let mut subscribe = format!("SUBSCRIBE {queue_name} WITH GROUP {group_name} HEADERS");
+    if options.mode == GroupMode::Exclusive {
+        subscribe.push_str(" EXCLUSIVE");
+    }
+    if options.strategy != Strategy::default() {
+        subscribe.push_str(&format!(" BALANCE {}", options.strategy));
+    }
+    if options.weight != 1 {
+        subscribe.push_str(&format!(" WEIGHT {}", options.weight));
+    }
+    if options.ack_timeout != SubscribeOptions::default().ack_timeout {
+        subscribe.push_str(&format!(
+            " ACK TIMEOUT {}",
+            options.ack_timeout.as_millis().max(1)
+        ));
+    }
+    if let Some(partitions) = options.partitions {
+        subscribe.push_str(&format!(" PARTITIONS {partitions}"));
+    }
+    // the consumer decompresses what the features of the crate allow, the
+    // broker decompresses the rest
+    let accept_encoding = Compression::supported()
+        .iter()
+        .map(|compression| compression.to_string())
+        .collect::<Vec<_>>();
+    if !accept_encoding.is_empty() {
+        subscribe.push_str(&format!(" ACCEPT ENCODING {}", accept_encoding.join(",")));
+    }
+    subscribe.push(';');
+    subscribe
+}
@@ -0,0 +1,118 @@
+use std::{error::Error, fmt::Display};
+
+use crate::server::error::{ErrorCode, OzesError};
+
+pub type ClientResult<T> = std::result::Result<T, ClientError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    /// Cannot open a connection to the broker.
+    Connect(String),
+    /// The connection was closed or broken.
+    Disconnected,
+    /// The broker did not answer in time.
+    TimeOut,
+    /// The connection was lost before the broker answered the commit, the
+    /// messages of the transaction may or may not be published.
+    CommitOutcomeUnknown(String),
+    Unauthorized(String),
+    AccessDenied(String),
+    QueueNotFound(String),
+    QueueExists(String),
+    QueueFull(String),
+    /// The message cannot be sent, like a key with whitespaces.
+    InvalidMessage(String),
+    /// The message cannot be encoded or decoded.
+    Codec(String),
+    /// Any other error replied by the broker, brokers before the error
+    /// codes reply without them.
+    Server {
+        code: Option<ErrorCode>,
+        message: String,
+    },
+    /// Reply that is not the one expected.
+    Protocol(String),
+}
+
+impl ClientError {
+    /// Error of an `error #<CODE> <message>` reply.
+    pub(crate) fn from_reply(reply: &str) -> Self {
+        let (code, message) = ErrorCode::from_reply(reply);
+        let queue = || {
+            message
+                .strip_prefix("queue ")
+                .and_then(|message| message.split_once(' '))
+                .map(|(queue, _)| queue.to_string())
+                .unwrap_or_else(|| message.to_string())
+        };
+        match code {
+            Some(ErrorCode::Unauthorized) => Self::Unauthorized(message.to_string()),
+            Some(ErrorCode::AccessDenied) => Self::AccessDenied(message.to_string()),
+            Some(ErrorCode::UnknownQueue) => Self::QueueNotFound(queue()),
+            Some(ErrorCode::QueueExists) => Self::QueueExists(queue()),
+            Some(ErrorCode::QueueFull) => Self::QueueFull(queue()),
+            code => Self::Server {
+                code,
+                message: message.to_string(),
+            },
+        }
+    }
+
+    /// Code of the error replied by the broker.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::Unauthorized(_) => Some(ErrorCode::Unauthorized),
+            Self::AccessDenied(_) => Some(ErrorCode::AccessDenied),
+            Self::QueueNotFound(_) => Some(ErrorCode::UnknownQueue),
+            Self::QueueExists(_) => Some(ErrorCode::QueueExists),
+            Self::QueueFull(_) => Some(ErrorCode::QueueFull),
+            Self::Server { code, .. } => *code,
+            _ => None,
+        }
+    }
+
+    /// Errors that are solved by connecting again.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Connect(_) | Self::Disconnected | Self::TimeOut)
+    }
+}
+
+impl From<OzesError> for ClientError {
+    fn from(error: OzesError) -> Self {
+        match error {
+            OzesError::TimeOut => Self::TimeOut,
+            _ => Self::Disconnected,
+        }
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connect(error) => write!(f, "cannot connect: {}", error),
+            Self::Disconnected => write!(f, "disconnected from the broker"),
+            Self::TimeOut => write!(f, "timed out"),
+            Self::CommitOutcomeUnknown(error) => {
+                write!(f, "commit outcome unknown: {}", error)
+            }
+            Self::Unauthorized(error) => write!(f, "unauthorized: {}", error),
+            Self::AccessDenied(error) => write!(f, "access denied: {}", error),
+            Self::QueueNotFound(queue) => write!(f, "queue {} not found", queue),
+            Self::QueueExists(queue) => write!(f, "queue {} already exists", queue),
+            Self::QueueFull(queue) => write!(f, "queue {} is full", queue),
+            Self::InvalidMessage(error) => write!(f, "invalid message: {}", error),
+            Self::Codec(error) => write!(f, "codec error: {}", error),
+            Self::Server {
+                code: Some(code),
+                message,
+            } => write!(f, "broker error {}: {}", code, message),
+            Self::Server {
+                code: None,
+                message,
+            } => write!(f, "broker error: {}", message),
+            Self::Protocol(reply) => write!(f, "unexpected reply: {}", reply),
+        }
+    }
+}
+
+impl Error for ClientError {}
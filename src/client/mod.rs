@@ -0,0 +1,489 @@
+//! Async client of the broker, speaking the same protocol and using the same
+//! `OzesConnection` as the server side.
+
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::{sync::mpsc, task::JoinHandle, time};
+
+use crate::{
+    connection::{Connection, OzesConnection, ProtocolVersion},
+    delivery_header, delivery_len,
+    server::{Credentials, DEFAULT_PORT},
+};
+
+pub use crate::server::ErrorCode;
+
+pub use self::{
+    codec::{Codec, TypedConsumer, TypedPublisher, CONTENT_TYPE},
+    consumer::{Consumer, Delivery, Metadata},
+    error::{ClientError, ClientResult},
+    multiplexer::Client,
+    publisher::Publisher,
+    transport::{TlsClientConfig, Transport},
+};
+
+use self::multiplexer::Multiplexer;
+
+#[cfg(feature = "bincode")]
+pub use self::codec::Bincode;
+#[cfg(feature = "cbor")]
+pub use self::codec::Cbor;
+#[cfg(feature = "json")]
+pub use self::codec::Json;
+#[cfg(feature = "msgpack")]
+pub use self::codec::MessagePack;
+
+mod codec;
+mod consumer;
+mod error;
+mod multiplexer;
+mod publisher;
+mod transport;
+
+/// Replies of the broker without a length, they are recognized by prefix.
+const LITERAL_REPLIES: [&[u8]; 11] = [
+    b"ping",
+    b"ok subscribed",
+    b"ok publisher",
+    b"ok message",
+    b"ok client",
+    b"ok auth",
+    b"ok queue",
+    b"ok channel",
+    b"ok begin",
+    b"ok commit",
+    b"ok rollback",
+];
+
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Address of the broker, `host:port`.
+    pub address: String,
+    pub transport: Transport,
+    /// Name sent with `CLIENT`, shown by the broker in logs and `ozesctl`.
+    pub client_name: Option<String>,
+    pub credentials: Option<Credentials>,
+    /// Time to wait for a reply of the broker.
+    pub timeout: Duration,
+    pub reconnect: ReconnectConfig,
+    /// Protocol asked with `HELLO`, the client falls back to the version 1
+    /// when the broker does not know the version 2.
+    pub protocol: ProtocolVersion,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            address: format!("localhost:{DEFAULT_PORT}"),
+            transport: Transport::default(),
+            client_name: None,
+            credentials: None,
+            timeout: Duration::from_secs(5),
+            reconnect: ReconnectConfig::default(),
+            protocol: ProtocolVersion::V2,
+        }
+    }
+}
+
+/// Exponential backoff between attempts to connect again after the
+/// connection is lost.
+#[derive(Clone, Debug)]
+pub struct ReconnectConfig {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    /// Fraction of the delay randomly added or removed, so clients dropped
+    /// together do not connect again all at once.
+    pub jitter: f64,
+    /// Attempts before giving up with the last error, `None` to never give up,
+    /// so a call can wait forever while the broker is down.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.initial_delay.as_secs_f64() * self.multiplier.powi(attempt as i32);
+        let delay = delay.min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0) * (fastrand::f64() * 2.0 - 1.0);
+        Duration::from_secs_f64((delay * (1.0 + jitter)).max(0.0))
+    }
+
+    fn gives_up(&self, attempt: u32) -> bool {
+        self.max_attempts
+            .map(|max_attempts| attempt >= max_attempts)
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug)]
+enum Reply {
+    /// `ok <reply>`, without the `ok`.
+    Ok(String),
+    Error(ClientError),
+    Delivery {
+        len: usize,
+        headers: Vec<(String, String)>,
+        payload: Bytes,
+    },
+    Ping,
+}
+
+/// Take the first reply of the buffer, `None` when it is not complete yet.
+///
+/// Deliveries carry their length, the other replies are not delimited, so
+/// the known ones are split by prefix and anything else takes the rest of
+/// the buffer.
+fn split_reply(buffer: &mut BytesMut) -> Option<Reply> {
+    if buffer.is_empty() {
+        return None;
+    }
+    if buffer.starts_with(b"+l") {
+        let (len, header_len) = delivery_header(buffer)?;
+        if buffer.len() < delivery_len(len) {
+            return None;
+        }
+        let mut delivery = buffer.split_to(delivery_len(len));
+        let headers = String::from_utf8_lossy(&delivery[..header_len - 2])
+            .split_whitespace()
+            .skip(1)
+            .filter_map(|header| header.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        delivery.advance(header_len);
+        return Some(Reply::Delivery {
+            len,
+            headers,
+            payload: delivery.freeze(),
+        });
+    }
+    if let Some(literal) = LITERAL_REPLIES
+        .iter()
+        .find(|literal| buffer.starts_with(literal))
+    {
+        let reply = buffer.split_to(literal.len());
+        return Some(match &reply[..] {
+            b"ping" => Reply::Ping,
+            reply => Reply::Ok(String::from_utf8_lossy(&reply[3..]).into_owned()),
+        });
+    }
+    if LITERAL_REPLIES
+        .iter()
+        .any(|literal| literal.starts_with(buffer))
+        || b"+l".starts_with(buffer)
+    {
+        return None;
+    }
+    let reply = buffer.split();
+    let reply = String::from_utf8_lossy(&reply);
+    Some(
+        match (reply.strip_prefix("error #"), reply.strip_prefix("ok ")) {
+            (Some(error), _) => Reply::Error(ClientError::from_reply(error)),
+            (None, Some(ok)) => Reply::Ok(ok.to_string()),
+            (None, None) => Reply::Error(ClientError::Protocol(reply.into_owned())),
+        },
+    )
+}
+
+/// Connection to the broker with a task reading it, so heartbeats are
+/// answered even when the application is not reading.
+struct ClientConnection {
+    connection: Arc<OzesConnection>,
+    replies: mpsc::UnboundedReceiver<ClientResult<Reply>>,
+    /// Deliveries read while waiting the reply of a request, taken by the
+    /// consumer before the next replies.
+    deliveries: VecDeque<Reply>,
+    reader: JoinHandle<()>,
+    timeout: Duration,
+}
+
+impl ClientConnection {
+    /// Connect and send `CLIENT` and `AUTH` when configured.
+    async fn open(config: &ClientConfig) -> ClientResult<Self> {
+        let (stream, peer_address) = config
+            .transport
+            .open(&config.address, config.timeout)
+            .await?;
+        let connection = Arc::new(OzesConnection::new(stream, peer_address));
+        if config.protocol == ProtocolVersion::V2 {
+            negotiate(&connection, config.timeout).await?;
+        }
+        let mut client = Self::start(connection, config.timeout);
+        if let Some(name) = &config.client_name {
+            client
+                .request(
+                    format!(
+                        "CLIENT NAME {name} VERSION {} HEARTBEAT;",
+                        env!("CARGO_PKG_VERSION")
+                    ),
+                    "client",
+                )
+                .await?;
+        }
+        match &config.credentials {
+            Some(Credentials::Password { username, password }) => {
+                client
+                    .request(format!("AUTH USER {username} PASSWORD {password};"), "auth")
+                    .await?;
+            }
+            Some(Credentials::Token(token)) => {
+                client
+                    .request(format!("AUTH TOKEN {token};"), "auth")
+                    .await?;
+            }
+            None => {}
+        }
+        Ok(client)
+    }
+
+    /// Start reading the connection.
+    fn start(connection: Arc<OzesConnection>, timeout: Duration) -> Self {
+        let (sender, replies) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read_replies(Arc::clone(&connection), sender));
+        Self {
+            connection,
+            replies,
+            deliveries: VecDeque::new(),
+            reader,
+            timeout,
+        }
+    }
+
+    /// Connect, or open a channel of the multiplexer, and send the command
+    /// that gives the role to the connection, `PUBLISHER` or `SUBSCRIBE`.
+    async fn register(
+        config: &ClientConfig,
+        multiplexer: Option<&Multiplexer>,
+        command: &str,
+        expected: &str,
+    ) -> ClientResult<Self> {
+        let mut client = match multiplexer {
+            Some(multiplexer) => multiplexer.open_channel().await?,
+            None => Self::open(config).await?,
+        };
+        client.request(command.to_string(), expected).await?;
+        Ok(client)
+    }
+
+    /// Connect again after the connection is lost, waiting the backoff
+    /// before each attempt, until the broker accepts the role again or fails
+    /// with an error that is not transient.
+    async fn reconnect(
+        config: &ClientConfig,
+        multiplexer: Option<&Multiplexer>,
+        command: &str,
+        expected: &str,
+    ) -> ClientResult<Self> {
+        let mut attempt = 0;
+        loop {
+            time::sleep(config.reconnect.delay(attempt)).await;
+            attempt += 1;
+            match Self::register(config, multiplexer, command, expected).await {
+                Err(error) if error.is_transient() && !config.reconnect.gives_up(attempt) => {
+                    log::info!(
+                        "reconnect attempt {} to {} failed: {}",
+                        attempt,
+                        config.address,
+                        error
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn send(&self, command: impl Into<Bytes>) -> ClientResult<()> {
+        self.connection.send_message(command.into()).await?;
+        Ok(())
+    }
+
+    /// Send the command and wait the `ok <expected>` reply.
+    async fn request(&mut self, command: impl Into<Bytes>, expected: &str) -> ClientResult<()> {
+        self.send(command).await?;
+        let timeout = self.timeout;
+        match time::timeout(timeout, self.next_answer()).await {
+            Ok(Ok(Reply::Ok(reply))) if reply == expected => Ok(()),
+            Ok(Ok(Reply::Error(error))) => Err(error),
+            Ok(Ok(reply)) => Err(ClientError::Protocol(format!("{reply:?}"))),
+            Ok(Err(error)) => Err(error),
+            Err(_) => Err(ClientError::TimeOut),
+        }
+    }
+
+    /// Next reply that is not a delivery, deliveries are kept for the
+    /// consumer.
+    async fn next_answer(&mut self) -> ClientResult<Reply> {
+        loop {
+            match self.next_reply().await? {
+                delivery @ Reply::Delivery { .. } => self.deliveries.push_back(delivery),
+                reply => return Ok(reply),
+            }
+        }
+    }
+
+    async fn next_reply(&mut self) -> ClientResult<Reply> {
+        self.replies
+            .recv()
+            .await
+            .unwrap_or(Err(ClientError::Disconnected))
+    }
+}
+
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        self.connection.close();
+        self.reader.abort();
+    }
+}
+
+/// Ask the binary protocol before the reader task starts, so the reply is
+/// the last thing read as text.
+async fn negotiate(connection: &OzesConnection, timeout: Duration) -> ClientResult<()> {
+    connection
+        .send_message(Bytes::from_static(b"HELLO VERSION 2;"))
+        .await?;
+    let reply = time::timeout(timeout, connection.read())
+        .await
+        .map_err(|_| ClientError::TimeOut)??;
+    match &reply[..] {
+        b"ok hello 2" => connection.upgrade(),
+        reply if reply.starts_with(b"error #") => log::info!(
+            "broker {} does not speak the protocol version 2: {}",
+            connection,
+            String::from_utf8_lossy(&reply[7..])
+        ),
+        reply => {
+            return Err(ClientError::Protocol(
+                String::from_utf8_lossy(reply).into_owned(),
+            ))
+        }
+    }
+    Ok(())
+}
+
+async fn read_replies(
+    connection: Arc<OzesConnection>,
+    sender: mpsc::UnboundedSender<ClientResult<Reply>>,
+) {
+    let mut buffer = BytesMut::new();
+    loop {
+        match connection.read().await {
+            Ok(bytes) => {
+                buffer.extend_from_slice(&bytes);
+                while let Some(reply) = split_reply(&mut buffer) {
+                    if let Reply::Ping = reply {
+                        if connection
+                            .send_message(Bytes::from_static(b"PONG;"))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                    if sender.send(Ok(reply)).is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(error) => {
+                log::info!("connection {} closed: {}", connection, error);
+                connection.close();
+                let _ = sender.send(Err(ClientError::Disconnected));
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delivery_statement;
+
+    fn split_all(input: &[u8]) -> (Vec<Reply>, BytesMut) {
+        let mut buffer = BytesMut::from(input);
+        let mut replies = vec![];
+        while let Some(reply) = split_reply(&mut buffer) {
+            replies.push(reply);
+        }
+        (replies, buffer)
+    }
+
+    #[test]
+    fn literal_replies_and_deliveries() {
+        let delivery = delivery_statement("trace=abc", b"hello #world");
+        let input = [&b"ok subscribed"[..], &delivery, b"ping", b"ok message"].concat();
+        let (replies, rest) = split_all(&input);
+        assert!(rest.is_empty());
+        assert!(matches!(&replies[0], Reply::Ok(reply) if reply == "subscribed"));
+        match &replies[1] {
+            Reply::Delivery {
+                len,
+                headers,
+                payload,
+            } => {
+                assert_eq!(*len, delivery.len());
+                assert_eq!(headers, &vec![(String::from("trace"), String::from("abc"))]);
+                assert_eq!(&payload[..], b"hello #world");
+            }
+            reply => panic!("expected a delivery, got {reply:?}"),
+        }
+        assert!(matches!(replies[2], Reply::Ping));
+        assert!(matches!(&replies[3], Reply::Ok(reply) if reply == "message"));
+    }
+
+    #[test]
+    fn deliveries_longer_than_their_len() {
+        let payload = [b'a'; 95];
+        let delivery = delivery_statement("", &payload);
+        assert!(delivery.starts_with(b"+l101 #"));
+        let input = [&delivery[..], b"ping"].concat();
+        let (replies, rest) = split_all(&input);
+        assert!(rest.is_empty());
+        assert!(
+            matches!(&replies[0], Reply::Delivery { len: 101, payload: p, .. } if p[..] == payload)
+        );
+        assert!(matches!(replies[1], Reply::Ping));
+    }
+
+    #[test]
+    fn incomplete_replies_wait() {
+        let delivery = delivery_statement("", b"hello");
+        let (replies, rest) = split_all(&delivery[..delivery.len() - 1]);
+        assert!(replies.is_empty());
+        assert_eq!(rest.len(), delivery.len() - 1);
+
+        let (replies, rest) = split_all(b"ok subscr");
+        assert!(replies.is_empty());
+        assert_eq!(&rest[..], b"ok subscr");
+    }
+
+    #[test]
+    fn errors_and_other_replies() {
+        let (replies, _) = split_all(b"error #QUEUE_FULL queue orders is full");
+        assert!(matches!(
+            &replies[..],
+            [Reply::Error(ClientError::QueueFull(queue))] if queue == "orders"
+        ));
+        let (replies, _) = split_all(b"ok +l12 {\"queues\":[]}");
+        assert!(matches!(&replies[..], [Reply::Ok(reply)] if reply == "+l12 {\"queues\":[]}"));
+        let (replies, _) = split_all(b"unknown");
+        assert!(matches!(
+            &replies[..],
+            [Reply::Error(ClientError::Protocol(_))]
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{
+    connection::{self, ProtocolVersion},
+    server::SubscribeOptions,
+};
+
+use super::{ClientConfig, ClientConnection, ClientError, ClientResult, Consumer, Publisher};
+
+/// One connection to the broker shared by any number of publishers and
+/// consumers, each of them in a channel of its own.
+///
+/// The broker has to speak the protocol version 2. When the connection is
+/// lost every channel is lost with it, the publishers and consumers connect
+/// again as usual and the first of them opens the connection again.
+#[derive(Clone)]
+pub struct Client {
+    multiplexer: Arc<Multiplexer>,
+}
+
+impl Client {
+    pub async fn connect(config: ClientConfig) -> ClientResult<Self> {
+        let multiplexer = Multiplexer {
+            config,
+            connection: Mutex::default(),
+        };
+        drop(multiplexer.connection().await?);
+        Ok(Self {
+            multiplexer: Arc::new(multiplexer),
+        })
+    }
+
+    pub async fn publisher(&self, queue_name: &str) -> ClientResult<Publisher> {
+        Publisher::register(
+            self.multiplexer.config.clone(),
+            Some(Arc::clone(&self.multiplexer)),
+            queue_name,
+        )
+        .await
+    }
+
+    pub async fn subscribe(
+        &self,
+        queue_name: &str,
+        group_name: &str,
+        options: SubscribeOptions,
+    ) -> ClientResult<Consumer> {
+        Consumer::register(
+            self.multiplexer.config.clone(),
+            Some(Arc::clone(&self.multiplexer)),
+            queue_name,
+            group_name,
+            options,
+        )
+        .await
+    }
+}
+
+/// Opens the channels of a `Client`, connecting again when the connection
+/// was lost.
+pub(crate) struct Multiplexer {
+    config: ClientConfig,
+    connection: Mutex<Option<(ClientConnection, u16)>>,
+}
+
+impl Multiplexer {
+    /// Open a channel with `OPEN CHANNEL`, the returned connection reads and
+    /// writes only the frames of the channel.
+    pub(crate) async fn open_channel(&self) -> ClientResult<ClientConnection> {
+        let mut connection = self.connection().await?;
+        let (parent, last_channel) = connection.as_mut().ok_or(ClientError::Disconnected)?;
+        let channel = (1..=u16::MAX)
+            .map(|offset| last_channel.wrapping_add(offset))
+            .find(|channel| *channel != 0 && !parent.connection.has_channel(*channel))
+            .ok_or_else(|| ClientError::Protocol(String::from("no free channel")))?;
+        *last_channel = channel;
+        // the channel is routed before it is opened, the broker can write
+        // on it as soon as it replies
+        let channel_connection = Arc::new(connection::open_channel(&parent.connection, channel));
+        parent
+            .request(format!("OPEN CHANNEL {channel};"), "channel")
+            .await?;
+        Ok(ClientConnection::start(
+            channel_connection,
+            self.config.timeout,
+        ))
+    }
+
+    /// The shared connection, opened again when it is not alive.
+    async fn connection(&self) -> ClientResult<MutexGuard<'_, Option<(ClientConnection, u16)>>> {
+        let mut connection = self.connection.lock().await;
+        let alive = matches!(&*connection, Some((parent, _)) if parent.connection.is_alive());
+        if !alive {
+            let parent = ClientConnection::open(&self.config).await?;
+            if parent.connection.protocol_version() != ProtocolVersion::V2 {
+                return Err(ClientError::Protocol(format!(
+                    "broker {} does not speak the protocol version 2 needed by channels",
+                    self.config.address
+                )));
+            }
+            *connection = Some((parent, 0));
+        }
+        Ok(connection)
+    }
+}
@@ -0,0 +1,234 @@
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::{
+    compression::{Compression, CONTENT_ENCODING},
+    message_statement,
+};
+
+use std::sync::Arc;
+
+use super::{
+    ClientConfig, ClientConnection, ClientError, ClientResult, Codec, Multiplexer, TypedPublisher,
+};
+
+/// Publisher of a queue, that can also publish to other queues with
+/// `publish_to`.
+///
+/// When the connection is lost the publisher connects again with the backoff
+/// of the config and sends again the message that was not confirmed, so a
+/// message can be published twice if the broker received it before the
+/// connection was broken. Only that message is sent again, there is no
+/// buffer of the messages before it, and with `max_attempts: None` the
+/// publish waits until the broker is back, however long it takes.
+pub struct Publisher {
+    config: ClientConfig,
+    /// Set when the publisher is a channel of a `Client`.
+    multiplexer: Option<Arc<Multiplexer>>,
+    queue_name: String,
+    register: String,
+    connection: ClientConnection,
+    compression: Option<Compression>,
+    /// Messages are not sent again after a reconnect in a transaction, the
+    /// broker rolled it back.
+    in_transaction: bool,
+}
+
+impl Publisher {
+    pub async fn connect(config: ClientConfig, queue_name: &str) -> ClientResult<Self> {
+        Self::register(config, None, queue_name).await
+    }
+
+    pub(super) async fn register(
+        config: ClientConfig,
+        multiplexer: Option<Arc<Multiplexer>>,
+        queue_name: &str,
+    ) -> ClientResult<Self> {
+        let register = format!("PUBLISHER {queue_name};");
+        let connection =
+            ClientConnection::register(&config, multiplexer.as_deref(), &register, "publisher")
+                .await?;
+        Ok(Self {
+            config,
+            multiplexer,
+            queue_name: queue_name.to_string(),
+            register,
+            connection,
+            compression: None,
+            in_transaction: false,
+        })
+    }
+
+    pub fn queue_name(&self) -> &str {
+        &self.queue_name
+    }
+
+    /// Compress the payloads with `compression`, consumers decompress them
+    /// or, when they do not support it, the broker does.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Begin a transaction, the messages are published on `commit` and
+    /// dropped on `rollback` or when the connection is lost.
+    pub async fn begin(&mut self) -> ClientResult<()> {
+        self.connection.request("TX BEGIN;", "begin").await?;
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    /// Publish the messages of the transaction. When the connection is lost
+    /// before the answer the error is `CommitOutcomeUnknown`, the broker may
+    /// have published them or rolled them back.
+    pub async fn commit(&mut self) -> ClientResult<()> {
+        let result = self.connection.request("TX COMMIT;", "commit").await;
+        self.in_transaction = false;
+        match result {
+            Err(error) if error.is_transient() => {
+                Err(ClientError::CommitOutcomeUnknown(error.to_string()))
+            }
+            result => result,
+        }
+    }
+
+    pub async fn rollback(&mut self) -> ClientResult<()> {
+        let result = self.connection.request("TX ROLLBACK;", "rollback").await;
+        self.in_transaction = false;
+        result
+    }
+
+    /// Encode the messages from `T` with `C`.
+    pub fn with_codec<T: Serialize, C: Codec>(self) -> TypedPublisher<T, C> {
+        TypedPublisher::new(self)
+    }
+
+    pub async fn publish(&mut self, payload: impl Into<Bytes>) -> ClientResult<()> {
+        self.publish_with_key(payload, None).await
+    }
+
+    /// Publish the message with a partition key, messages with the same key
+    /// go to the same partition and consumer.
+    pub async fn publish_with_key(
+        &mut self,
+        payload: impl Into<Bytes>,
+        key: Option<&str>,
+    ) -> ClientResult<()> {
+        self.publish_with_headers(payload, key, &[]).await
+    }
+
+    /// Publish the message with headers, given to consumers in the metadata
+    /// of the delivery.
+    pub async fn publish_with_headers(
+        &mut self,
+        payload: impl Into<Bytes>,
+        key: Option<&str>,
+        headers: &[(&str, &str)],
+    ) -> ClientResult<()> {
+        self.send(None, payload.into(), key, headers).await
+    }
+
+    /// Publish the message to `queue_name` instead of the queue of the
+    /// publisher.
+    pub async fn publish_to(
+        &mut self,
+        queue_name: &str,
+        payload: impl Into<Bytes>,
+        key: Option<&str>,
+        headers: &[(&str, &str)],
+    ) -> ClientResult<()> {
+        self.send(Some(queue_name), payload.into(), key, headers)
+            .await
+    }
+
+    async fn send(
+        &mut self,
+        queue_name: Option<&str>,
+        payload: Bytes,
+        key: Option<&str>,
+        headers: &[(&str, &str)],
+    ) -> ClientResult<()> {
+        let frame = match self.compression {
+            Some(compression) => {
+                let compressed = compression
+                    .compress(&payload)
+                    .map_err(|error| ClientError::Codec(error.to_string()))?;
+                let encoding = compression.to_string();
+                let mut headers = headers.to_vec();
+                headers.push((CONTENT_ENCODING, &encoding));
+                message_frame(&compressed, queue_name, key, &headers)?
+            }
+            None => message_frame(&payload, queue_name, key, headers)?,
+        };
+        loop {
+            match self.connection.request(frame.clone(), "message").await {
+                Err(error) if error.is_transient() && self.in_transaction => {
+                    self.in_transaction = false;
+                    return Err(error);
+                }
+                Err(error) if error.is_transient() => {
+                    log::info!("publisher of {} reconnecting: {}", self.queue_name, error);
+                    self.connection = ClientConnection::reconnect(
+                        &self.config,
+                        self.multiplexer.as_deref(),
+                        &self.register,
+                        "publisher",
+                    )
+                    .await?;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// `message +l<len> #<payload>` or, with a queue, a key or headers,
+/// `MESSAGE TO <queue> KEY <key> HEADER <name> <value> +l<len> #<payload>`.
+pub(super) fn message_frame(
+    payload: &[u8],
+    queue_name: Option<&str>,
+    key: Option<&str>,
+    headers: &[(&str, &str)],
+) -> ClientResult<Bytes> {
+    if let Some(queue_name) = queue_name {
+        check_token("queue name", queue_name)?;
+    }
+    if let Some(key) = key {
+        check_token("key", key)?;
+    }
+    for (name, value) in headers {
+        check_token("header name", name)?;
+        check_token("header value", value)?;
+        if name.contains('=') {
+            return Err(ClientError::InvalidMessage(format!(
+                "header name {name:?} cannot have '='"
+            )));
+        }
+    }
+    let mut options = vec![];
+    if let Some(queue_name) = queue_name {
+        options.push(format!("TO {queue_name}"));
+    }
+    if let Some(key) = key {
+        options.push(format!("KEY {key}"));
+    }
+    for (name, value) in headers {
+        options.push(format!("HEADER {name} {value}"));
+    }
+    Ok(Bytes::from(message_statement(&options.join(" "), payload)))
+}
+
+/// Queues, keys and headers are tokens of the command, they cannot have
+/// whitespaces or `;`, and cannot start with `#` that is the start of the
+/// payload.
+fn check_token(name: &str, token: &str) -> ClientResult<()> {
+    if token.is_empty()
+        || token.starts_with('#')
+        || token.contains(|c: char| c.is_whitespace() || c == ';')
+    {
+        return Err(ClientError::InvalidMessage(format!(
+            "{name} {token:?} cannot be empty, start with '#' or have whitespaces or ';'"
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{net::TcpStream, time};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, RootCertStore},
+    TlsConnector,
+};
+
+pub use tokio_rustls::rustls::ClientConfig as TlsClientConfig;
+
+use crate::connection::{BoxedStream, PeerAddress};
+
+use super::{ClientError, ClientResult};
+
+/// How the client reaches the broker.
+#[derive(Clone, Debug, Default)]
+pub enum Transport {
+    /// Plain TCP to the address of the config.
+    #[default]
+    Tcp,
+    /// TLS over TCP to the address of the config, its host is the name
+    /// checked in the broker certificate.
+    Tls(Arc<TlsClientConfig>),
+    /// Unix socket at the path, the address of the config is not used.
+    Unix(PathBuf),
+}
+
+impl Transport {
+    /// TLS trusting the certificates of the PEM file, like the private CA
+    /// that signed the broker certificate.
+    pub fn tls_with_ca(ca_path: impl AsRef<Path>) -> ClientResult<Self> {
+        let ca_path = ca_path.as_ref();
+        let invalid = |error: &dyn std::fmt::Display| {
+            ClientError::Connect(format!("invalid ca {}: {error}", ca_path.display()))
+        };
+        let file = File::open(ca_path).map_err(|error| invalid(&error))?;
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+            roots
+                .add(cert.map_err(|error| invalid(&error))?)
+                .map_err(|error| invalid(&error))?;
+        }
+        let config = TlsClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self::Tls(Arc::new(config)))
+    }
+
+    /// Open a stream to the broker at `address`, `host:port`.
+    pub async fn connect(&self, address: &str, timeout: Duration) -> ClientResult<BoxedStream> {
+        self.open(address, timeout).await.map(|(stream, _)| stream)
+    }
+
+    pub(crate) async fn open(
+        &self,
+        address: &str,
+        timeout: Duration,
+    ) -> ClientResult<(BoxedStream, PeerAddress)> {
+        let connect = |target: String, error: &dyn std::fmt::Display| {
+            ClientError::Connect(format!("cannot connect to {target}: {error}"))
+        };
+        let timed_out =
+            |target: String| ClientError::Connect(format!("timeout connecting to {target}"));
+        #[cfg(unix)]
+        if let Self::Unix(path) = self {
+            let stream = time::timeout(timeout, UnixStream::connect(path))
+                .await
+                .map_err(|_| timed_out(path.display().to_string()))?
+                .map_err(|error| connect(path.display().to_string(), &error))?;
+            return Ok((Box::new(stream), PeerAddress::Unix(Some(path.clone()))));
+        }
+        #[cfg(not(unix))]
+        if let Self::Unix(path) = self {
+            return Err(connect(
+                path.display().to_string(),
+                &"unix sockets are not supported on this platform",
+            ));
+        }
+        let stream = time::timeout(timeout, TcpStream::connect(address))
+            .await
+            .map_err(|_| timed_out(address.to_string()))?
+            .map_err(|error| connect(address.to_string(), &error))?;
+        let peer_address = stream
+            .peer_addr()
+            .map_err(|error| connect(address.to_string(), &error))?;
+        let Self::Tls(config) = self else {
+            return Ok((Box::new(stream), peer_address.into()));
+        };
+        let host = address
+            .rsplit_once(':')
+            .map_or(address, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|error| connect(address.to_string(), &error))?;
+        let stream = time::timeout(
+            timeout,
+            TlsConnector::from(Arc::clone(config)).connect(server_name, stream),
+        )
+        .await
+        .map_err(|_| timed_out(address.to_string()))?
+        .map_err(|error| connect(address.to_string(), &error))?;
+        Ok((Box::new(stream), peer_address.into()))
+    }
+}
@@ -0,0 +1,226 @@
+//! Compression of message payloads, recorded in the `content-encoding`
+//! header. The broker forwards compressed payloads untouched and only
+//! decompresses them for consumers that do not accept the encoding.
+
+use std::{fmt::Display, io, str::FromStr};
+
+/// Header with the compression of the payload.
+pub const CONTENT_ENCODING: &str = "content-encoding";
+
+/// Payloads bigger than this once decompressed are refused, so a small
+/// message cannot blow the memory of the broker.
+#[cfg(any(feature = "zstd", feature = "lz4", feature = "gzip"))]
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+    Gzip,
+}
+
+impl Compression {
+    const ALL: [Compression; 3] = [Self::Zstd, Self::Lz4, Self::Gzip];
+
+    /// Compressions enabled by the features of the crate.
+    pub fn supported() -> Encodings {
+        Self::ALL
+            .into_iter()
+            .filter(|compression| compression.is_supported())
+            .fold(Encodings::default(), Encodings::with)
+    }
+
+    pub fn is_supported(&self) -> bool {
+        match self {
+            Self::Zstd => cfg!(feature = "zstd"),
+            Self::Lz4 => cfg!(feature = "lz4"),
+            Self::Gzip => cfg!(feature = "gzip"),
+        }
+    }
+
+    #[cfg_attr(
+        not(any(feature = "zstd", feature = "lz4", feature = "gzip")),
+        allow(unused_variables)
+    )]
+    pub fn compress(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::encode_all(payload, 0),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => Ok(lz4_flex::compress_prepend_size(payload)),
+            #[cfg(feature = "gzip")]
+            Self::Gzip => {
+                use std::io::Write;
+                let mut encoder =
+                    flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(payload)?;
+                encoder.finish()
+            }
+            #[cfg(not(all(feature = "zstd", feature = "lz4", feature = "gzip")))]
+            _ => Err(self.unsupported()),
+        }
+    }
+
+    #[cfg_attr(
+        not(any(feature = "zstd", feature = "lz4", feature = "gzip")),
+        allow(unused_variables)
+    )]
+    pub fn decompress(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => read_limited(zstd::Decoder::new(payload)?),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => {
+                let size = payload
+                    .get(..4)
+                    .map(|size| u32::from_le_bytes([size[0], size[1], size[2], size[3]]))
+                    .ok_or_else(|| io::Error::other("lz4 payload without size"))?;
+                if size as usize > MAX_DECOMPRESSED_SIZE {
+                    return Err(too_big());
+                }
+                lz4_flex::decompress_size_prepended(payload).map_err(io::Error::other)
+            }
+            #[cfg(feature = "gzip")]
+            Self::Gzip => read_limited(flate2::read::GzDecoder::new(payload)),
+            #[cfg(not(all(feature = "zstd", feature = "lz4", feature = "gzip")))]
+            _ => Err(self.unsupported()),
+        }
+    }
+
+    #[cfg(not(all(feature = "zstd", feature = "lz4", feature = "gzip")))]
+    fn unsupported(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{self} compression is not enabled"),
+        )
+    }
+
+    fn bit(&self) -> u8 {
+        match self {
+            Self::Zstd => 1,
+            Self::Lz4 => 2,
+            Self::Gzip => 4,
+        }
+    }
+}
+
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+fn read_limited(reader: impl io::Read) -> io::Result<Vec<u8>> {
+    let mut payload = vec![];
+    io::Read::read_to_end(
+        &mut io::Read::take(reader, MAX_DECOMPRESSED_SIZE as u64 + 1),
+        &mut payload,
+    )?;
+    if payload.len() > MAX_DECOMPRESSED_SIZE {
+        return Err(too_big());
+    }
+    Ok(payload)
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4", feature = "gzip"))]
+fn too_big() -> io::Error {
+    io::Error::other(format!(
+        "decompressed payload is bigger than {MAX_DECOMPRESSED_SIZE} bytes"
+    ))
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let compression = match self {
+            Self::Zstd => "zstd",
+            Self::Lz4 => "lz4",
+            Self::Gzip => "gzip",
+        };
+        write!(f, "{}", compression)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(compression: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|known| known.to_string().eq_ignore_ascii_case(compression))
+            .ok_or_else(|| format!("unknown compression {compression}"))
+    }
+}
+
+/// Set of compressions a consumer can decompress by itself.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Encodings(u8);
+
+impl Encodings {
+    pub fn with(self, compression: Compression) -> Self {
+        Self(self.0 | compression.bit())
+    }
+
+    pub fn contains(&self, compression: Compression) -> bool {
+        self.0 & compression.bit() != 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Compression> + '_ {
+        Compression::ALL
+            .into_iter()
+            .filter(|compression| self.contains(*compression))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        for compression in Compression::ALL {
+            assert_eq!(
+                compression
+                    .to_string()
+                    .to_uppercase()
+                    .parse::<Compression>(),
+                Ok(compression)
+            );
+        }
+        assert!("br".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn encodings_set() {
+        let encodings = Encodings::default()
+            .with(Compression::Zstd)
+            .with(Compression::Gzip);
+        assert!(encodings.contains(Compression::Zstd));
+        assert!(!encodings.contains(Compression::Lz4));
+        assert_eq!(
+            encodings.iter().collect::<Vec<_>>(),
+            [Compression::Zstd, Compression::Gzip]
+        );
+    }
+
+    #[test]
+    fn supported_compressions_round_trip() {
+        let payload = b"hello hello hello hello hello hello".repeat(100);
+        for compression in Compression::ALL {
+            if !compression.is_supported() {
+                assert!(compression.compress(&payload).is_err());
+                continue;
+            }
+            let compressed = compression.compress(&payload).unwrap();
+            assert!(compressed.len() < payload.len());
+            assert_eq!(compression.decompress(&compressed).unwrap(), payload);
+            assert!(compression.decompress(b"not compressed").is_err());
+        }
+    }
+
+    #[cfg(any(feature = "zstd", feature = "lz4", feature = "gzip"))]
+    #[test]
+    fn decompressed_size_is_limited() {
+        let payload = vec![0; MAX_DECOMPRESSED_SIZE + 1];
+        for compression in Compression::ALL {
+            if compression.is_supported() {
+                let compressed = compression.compress(&payload).unwrap();
+                assert!(compression.decompress(&compressed).is_err());
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Channels of a multiplexed connection, opened with `OPEN CHANNEL`.
+//!
+//! Each channel is a connection of its own, speaking frames over an in
+//! memory stream: the parent routes the frames it reads for the channel into
+//! the stream, and a pump writes the frames of the channel to the parent with
+//! the channel id patched in.
+
+use std::sync::{Arc, Weak};
+
+use tokio::{
+    io::{AsyncWriteExt, DuplexStream},
+    sync::mpsc,
+};
+
+use crate::BUFFER_SIZE;
+
+/// Frames routed to a channel that did not read them yet, when it is full
+/// the parent waits before reading more.
+const ROUTE_CAPACITY: usize = 64;
+
+use super::{
+    protocol::{self, close_frame, FrameHeader},
+    BoxedStream, OzesConnection, PeerAddress,
+};
+
+/// Open the channel `channel` of `parent`, the returned connection already
+/// speaks the protocol version 2. Dropping it closes the channel.
+pub(crate) fn open_channel(parent: &Arc<OzesConnection>, channel: u16) -> OzesConnection {
+    let (near, far) = tokio::io::duplex(BUFFER_SIZE);
+    let (far_reader, far_writer) = tokio::io::split(far);
+    let (sender, routed) = mpsc::channel(ROUTE_CAPACITY);
+    let route = Arc::new(sender);
+    let weak_route = Arc::downgrade(&route);
+    parent.channels.lock().unwrap().insert(channel, route);
+    tokio::spawn(write_routed(routed, far_writer));
+    tokio::spawn(pump(parent.clone(), channel, weak_route, far_reader));
+
+    let peer_address = PeerAddress::Channel {
+        session_id: parent.session_id(),
+        channel,
+    };
+    let connection = OzesConnection::new(Box::new(near) as BoxedStream, peer_address);
+    connection.upgrade();
+    connection
+}
+
+/// Move the frames routed to the channel into its stream, until the route is
+/// removed and the channel reads the end of its stream.
+async fn write_routed(
+    mut routed: mpsc::Receiver<bytes::Bytes>,
+    mut writer: tokio::io::WriteHalf<DuplexStream>,
+) {
+    while let Some(frame) = routed.recv().await {
+        if writer.write_all(&frame).await.is_err() {
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Send the frames written by the channel through the parent. When the
+/// channel is dropped its route is removed and the peer is told, unless the
+/// peer closed it first or the id was given to another channel since.
+async fn pump(
+    parent: Arc<OzesConnection>,
+    channel: u16,
+    route: Weak<mpsc::Sender<bytes::Bytes>>,
+    mut reader: tokio::io::ReadHalf<DuplexStream>,
+) {
+    while let Ok((header, body)) = protocol::read_frame(&mut reader).await {
+        let frame = FrameHeader { channel, ..header }.frame(&body);
+        if parent.write(&frame).await.is_err() {
+            return;
+        }
+    }
+    let removed = {
+        let mut channels = parent.channels.lock().unwrap();
+        match channels.get(&channel) {
+            Some(current) if std::ptr::eq(Arc::as_ptr(current), route.as_ptr()) => {
+                channels.remove(&channel);
+                true
+            }
+            _ => false,
+        }
+    };
+    if removed && parent.is_alive() {
+        let _ = parent.write(&close_frame(channel)).await;
+    }
+}
@@ -1,82 +1,439 @@
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::SystemTime,
+};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use tokio::{
-    net::TcpStream,
-    sync::RwLock,
-    time::{self, Duration},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::{mpsc, watch, Mutex, MutexGuard, RwLock},
+    time::{self, Duration, Instant},
 };
 
 use crate::{
-    server::error::{OzResult, OzesError},
+    server::{
+        error::{ErrorCode, OzResult, OzesError},
+        metrics::METRICS,
+    },
     BUFFER_SIZE,
 };
 
-enum ConnectionType {
+pub use self::{
+    protocol::{FrameHeader, Opcode, ProtocolVersion},
+    stats::ConnectionStats,
+    websocket::WebSocketStream,
+};
+
+use self::protocol::{FrameCodec, HEADER_LEN};
+
+pub(crate) use self::channel::open_channel;
+
+mod channel;
+mod protocol;
+mod stats;
+mod websocket;
+
+static SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Time to wait the writer taken by another write.
+const LOCK_TIMEOUT: Duration = Duration::from_millis(500);
+/// Time a write can take before the connection is closed.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Transport of a connection, plain TCP or any stream wrapped around it.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Stream for T {}
+
+/// Stream type shared by every connection in queues and groups, so the
+/// listeners with different transports can run side by side.
+pub type BoxedStream = Box<dyn Stream>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionType {
+    Unknown,
     Publisher,
     Consumer,
+    /// Connection whose channels take the roles.
+    Multiplexed,
+}
+
+impl Display for ConnectionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ty = match self {
+            Self::Unknown => "unknown",
+            Self::Publisher => "publisher",
+            Self::Consumer => "consumer",
+            Self::Multiplexed => "multiplexed",
+        };
+        write!(f, "{}", ty)
+    }
+}
+
+/// Address of the peer, Unix socket peers usually have no path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerAddress {
+    Tcp(SocketAddr),
+    Unix(Option<PathBuf>),
+    /// Connection made inside the server, like the HTTP gateway ones.
+    Internal(String),
+    /// Channel opened in a multiplexed connection.
+    Channel {
+        session_id: u64,
+        channel: u16,
+    },
+}
+
+impl From<SocketAddr> for PeerAddress {
+    fn from(address: SocketAddr) -> Self {
+        Self::Tcp(address)
+    }
 }
 
-pub struct OzesConnection {
-    stream: TcpStream,
-    socket_address: SocketAddr,
+#[cfg(unix)]
+impl From<tokio::net::unix::SocketAddr> for PeerAddress {
+    fn from(address: tokio::net::unix::SocketAddr) -> Self {
+        Self::Unix(address.as_pathname().map(PathBuf::from))
+    }
+}
+
+impl Display for PeerAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{}", address),
+            Self::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            Self::Unix(None) => write!(f, "unix socket"),
+            Self::Internal(name) => write!(f, "{}", name),
+            Self::Channel {
+                session_id,
+                channel,
+            } => write!(f, "channel {} of session {}", channel, session_id),
+        }
+    }
+}
+
+/// Name and library version sent by the client with `CLIENT`.
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    /// The client answers `ping`, asked with `CLIENT ... HEARTBEAT`.
+    pub heartbeat: bool,
+}
+
+pub struct OzesConnection<S = BoxedStream> {
+    session_id: u64,
+    reader: Mutex<ReadHalf<S>>,
+    writer: Mutex<WriteHalf<S>>,
+    peer_address: PeerAddress,
+    connected_at: SystemTime,
+    client: StdMutex<ClientInfo>,
+    user: StdMutex<Option<String>>,
+    stats: ConnectionStats,
     ty: RwLock<ConnectionType>,
+    last_seen: StdMutex<Instant>,
+    closed: watch::Sender<bool>,
+    replies: Mutex<mpsc::UnboundedReceiver<Bytes>>,
+    replies_sender: mpsc::UnboundedSender<Bytes>,
+    /// Set while a deliver waits a reply in `read_reply`.
+    awaiting_reply: StdMutex<bool>,
+    /// Number of the last delivery sent to the connection.
+    delivery: AtomicU64,
+    /// Set when the connection speaks the binary protocol.
+    frames: StdMutex<Option<FrameCodec>>,
+    /// Routes of the frames of the open channels.
+    channels: StdMutex<HashMap<u16, Arc<mpsc::Sender<Bytes>>>>,
 }
 
-impl OzesConnection {
-    pub fn new(stream: TcpStream, socket_address: SocketAddr) -> Self {
+impl<S: Stream> OzesConnection<S> {
+    pub fn new(stream: S, peer_address: impl Into<PeerAddress>) -> Self {
+        let (closed, _) = watch::channel(false);
+        let (replies_sender, replies) = mpsc::unbounded_channel();
+        let (reader, writer) = tokio::io::split(stream);
         Self {
-            stream,
-            socket_address,
-            ty: RwLock::new(ConnectionType::Consumer),
+            session_id: SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+            peer_address: peer_address.into(),
+            connected_at: SystemTime::now(),
+            client: StdMutex::default(),
+            user: StdMutex::default(),
+            stats: ConnectionStats::default(),
+            ty: RwLock::new(ConnectionType::Unknown),
+            last_seen: StdMutex::new(Instant::now()),
+            closed,
+            replies: Mutex::new(replies),
+            replies_sender,
+            awaiting_reply: StdMutex::new(false),
+            delivery: AtomicU64::default(),
+            frames: StdMutex::default(),
+            channels: StdMutex::default(),
+        }
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    pub fn connected_at(&self) -> SystemTime {
+        self.connected_at
+    }
+
+    pub fn client(&self) -> ClientInfo {
+        self.client.lock().unwrap().clone()
+    }
+
+    pub fn set_client(&self, client: ClientInfo) {
+        *self.client.lock().unwrap() = client;
+    }
+
+    /// User authenticated with `AUTH`.
+    pub fn user(&self) -> Option<String> {
+        self.user.lock().unwrap().clone()
+    }
+
+    pub fn set_user(&self, user: String) {
+        *self.user.lock().unwrap() = Some(user);
+    }
+
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    pub async fn ty(&self) -> ConnectionType {
+        *self.ty.read().await
+    }
+
+    /// Mark the connection as dead, pending and future reads fail with
+    /// `OzesError::WithouConnection`.
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+        self.close_channels();
+    }
+
+    pub fn is_alive(&self) -> bool {
+        !*self.closed.borrow()
+    }
+
+    /// Wait until the connection is closed.
+    pub async fn closed(&self) {
+        let mut closed = self.closed.subscribe();
+        while !*closed.borrow() {
+            if closed.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Time since the last message received from the connection.
+    pub fn idle_time(&self) -> Duration {
+        self.last_seen.lock().unwrap().elapsed()
+    }
+
+    /// The server pings the connection when it is idle, the client asked for
+    /// it with `CLIENT` or speaks the protocol version 2 that has ping frames.
+    pub fn heartbeat(&self) -> bool {
+        self.client.lock().unwrap().heartbeat || self.protocol_version() == ProtocolVersion::V2
+    }
+
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        match *self.frames.lock().unwrap() {
+            Some(_) => ProtocolVersion::V2,
+            None => ProtocolVersion::V1,
+        }
+    }
+
+    /// Switch to the binary protocol, after `HELLO` was answered.
+    pub(crate) fn upgrade(&self) {
+        self.frames
+            .lock()
+            .unwrap()
+            .get_or_insert_with(FrameCodec::default);
+    }
+
+    pub(crate) fn has_channel(&self, channel: u16) -> bool {
+        self.channels.lock().unwrap().contains_key(&channel)
+    }
+
+    pub(crate) fn channel_count(&self) -> usize {
+        self.channels.lock().unwrap().len()
+    }
+
+    /// Stop routing the frames of the channel, that sees the end of its
+    /// stream.
+    pub(crate) fn close_channel(&self, channel: u16) {
+        self.channels.lock().unwrap().remove(&channel);
+    }
+
+    fn close_channels(&self) {
+        self.channels.lock().unwrap().clear();
+    }
+
+    /// Route the frame to its channel, waiting while the channel has not
+    /// read the frames routed before.
+    async fn route(&self, header: FrameHeader, body: &[u8]) {
+        if header.opcode == Opcode::Close {
+            self.close_channel(header.channel);
+            return;
+        }
+        let channel = self.channels.lock().unwrap().get(&header.channel).cloned();
+        match channel {
+            Some(channel) => {
+                let _ = channel
+                    .send(
+                        FrameHeader {
+                            channel: 0,
+                            ..header
+                        }
+                        .frame(body),
+                    )
+                    .await;
+            }
+            None => log::warn!(
+                "discard frame of unknown channel {} from {}",
+                header.channel,
+                self
+            ),
         }
     }
 
+    /// Queue a reply read by the consumer loop, to be taken by `read_reply`.
+    pub(crate) fn push_reply(&self, reply: Bytes) {
+        let _ = self.replies_sender.send(reply);
+    }
+
+    /// Count a delivery about to be sent, its replies are the ones read
+    /// until the next.
+    pub(crate) fn next_delivery(&self) {
+        self.delivery.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn delivery(&self) -> u64 {
+        self.delivery.load(Ordering::Relaxed)
+    }
+
+    /// Whether the group still waits the reply to the delivery.
+    pub(crate) fn awaits_reply(&self, delivery: u64) -> bool {
+        *self.awaiting_reply.lock().unwrap() && self.delivery() == delivery
+    }
+
+    /// Queue the reply to `delivery` only when a deliver still waits it, so
+    /// it cannot be taken for the reply of another delivery. Return if it
+    /// was queued.
+    pub(crate) fn push_awaited_reply(&self, delivery: u64, reply: Bytes) -> bool {
+        let awaiting_reply = self.awaiting_reply.lock().unwrap();
+        let awaited = *awaiting_reply && self.delivery() == delivery;
+        if awaited {
+            self.push_reply(reply);
+        }
+        awaited
+    }
+
+    /// Drop replies that arrived after the deliver that waited them.
+    pub(crate) async fn discard_replies(&self) {
+        let mut replies = self.replies.lock().await;
+        while replies.try_recv().is_ok() {}
+    }
+
+    /// Write the statement and return its length, that is the one of the
+    /// text even when it is sent in a frame.
     async fn send(&self, message: Bytes) -> OzResult<usize> {
-        loop {
-            self.stream.writable().await?;
-            match self.stream.try_write(&message) {
-                Ok(n) => break Ok(n),
-                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
-                    continue;
-                }
-                Err(error) => {
-                    log::error!(
-                        "error on read message from connection {}: {}",
-                        self.socket_address(),
-                        error
-                    );
-                    return Err(error)?;
-                }
+        let len = message.len();
+        let message = match self.frames.lock().unwrap().as_mut() {
+            Some(frames) => frames.encode(&message),
+            None => message,
+        };
+        self.write(&message).await?;
+        Ok(len)
+    }
+
+    /// Only waiting the writer can time out, a write cancelled in the middle
+    /// would leave half a frame in the stream, so the connection is closed
+    /// when the write itself does not end.
+    async fn write(&self, message: &[u8]) -> OzResult<()> {
+        let mut writer = match time::timeout(LOCK_TIMEOUT, self.writer.lock()).await {
+            Ok(writer) => writer,
+            Err(_) => {
+                log::error!("write message time out");
+                METRICS.timeout();
+                return Err(OzesError::TimeOut);
+            }
+        };
+        let written = time::timeout(WRITE_TIMEOUT, async {
+            writer.write_all(message).await?;
+            writer.flush().await
+        })
+        .await;
+        let written = match written {
+            Ok(written) => written,
+            Err(_) => {
+                log::error!(
+                    "write to connection {} time out, closing it",
+                    self.peer_address()
+                );
+                METRICS.timeout();
+                self.close();
+                return Err(OzesError::TimeOut);
+            }
+        };
+        match written {
+            Ok(()) => {
+                self.stats.written(message.len());
+                Ok(())
+            }
+            Err(error) => {
+                log::error!(
+                    "error on read message from connection {}: {}",
+                    self.peer_address(),
+                    error
+                );
+                Err(error)?
             }
         }
     }
 
-    async fn read(&self) -> OzResult<Bytes> {
+    pub(crate) async fn read(&self) -> OzResult<Bytes> {
+        let mut closed = self.closed.subscribe();
+        let mut reader = self.reader.lock().await;
+        if self.protocol_version() == ProtocolVersion::V2 {
+            return self.read_frame(&mut reader, &mut closed).await;
+        }
+        let mut buffer = vec![0; BUFFER_SIZE];
         loop {
-            self.stream.readable().await?;
-            let mut buffer = vec![0; BUFFER_SIZE];
-            match self.stream.try_read(&mut buffer) {
+            if *closed.borrow() {
+                return Err(OzesError::WithouConnection);
+            }
+            let read = tokio::select! {
+                read = reader.read(&mut buffer) => read,
+                _ = closed.changed() => continue,
+            };
+            match read {
                 Ok(size) => {
                     if size == 0 {
-                        log::info!("connection from {} is closed", self.socket_address());
+                        log::info!("connection from {} is closed", self.peer_address());
                         return Err(OzesError::WithouConnection);
                     }
                     if size > BUFFER_SIZE {
                         return Err(OzesError::ToLongMessage);
                     }
                     buffer.truncate(size);
+                    *self.last_seen.lock().unwrap() = Instant::now();
+                    self.stats.read(size);
                     let bytes = Bytes::copy_from_slice(&buffer[..]);
                     break Ok(bytes);
                 }
-                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
-                    continue;
-                }
                 Err(error) => {
                     log::error!(
                         "error on read message from connection {}: {}",
-                        self.socket_address(),
+                        self.peer_address(),
                         error
                     );
                     return Err(error)?;
@@ -85,45 +442,116 @@ impl OzesConnection {
         }
     }
 
-    pub fn socket_address(&self) -> &SocketAddr {
-        &self.socket_address
+    /// Read the next frame of the channel 0 and translate it to text, frames
+    /// of other channels are routed to them.
+    async fn read_frame(
+        &self,
+        reader: &mut ReadHalf<S>,
+        closed: &mut watch::Receiver<bool>,
+    ) -> OzResult<Bytes> {
+        loop {
+            // the connection is only ever closed, any change is the close
+            let read = tokio::select! {
+                read = protocol::read_frame(reader) => read,
+                _ = closed.changed() => Err(OzesError::WithouConnection),
+            };
+            let (header, body) = match read {
+                Ok(frame) => frame,
+                Err(error) => {
+                    self.close_channels();
+                    if let OzesError::WithouConnection = error {
+                        log::info!("connection from {} is closed", self.peer_address());
+                    }
+                    return Err(error);
+                }
+            };
+            *self.last_seen.lock().unwrap() = Instant::now();
+            self.stats.read(HEADER_LEN + body.len());
+            if header.channel != 0 {
+                tokio::select! {
+                    _ = self.route(header, &body) => continue,
+                    _ = closed.changed() => {
+                        self.close_channels();
+                        return Err(OzesError::WithouConnection);
+                    }
+                }
+            }
+            return match self.frames.lock().unwrap().as_mut() {
+                Some(frames) => frames.decode(header, &body),
+                None => Err(OzesError::WithouConnection),
+            };
+        }
+    }
+
+    pub fn peer_address(&self) -> &PeerAddress {
+        &self.peer_address
+    }
+
+    #[deprecated(note = "use `peer_address`, the peer can be a unix socket")]
+    pub fn socket_address(&self) -> &PeerAddress {
+        self.peer_address()
+    }
+
+    /// Write half of the stream, writing to it directly bypasses the frames
+    /// of the protocol version 2 and the stats.
+    #[deprecated(note = "the stream is split in halves, use `send_message` and `read_message`")]
+    pub async fn stream(&self) -> MutexGuard<'_, WriteHalf<S>> {
+        self.writer.lock().await
     }
+}
 
-    pub fn stream(&self) -> &TcpStream {
-        &self.stream
+impl<S> Display for OzesConnection<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "session {}", self.session_id)?;
+        if let Some(name) = &self.client.lock().unwrap().name {
+            write!(f, " ({})", name)?;
+        }
+        write!(f, " from {}", self.peer_address)
     }
 }
 
 #[async_trait]
 pub trait Connection {
     async fn send_message(&self, message: Bytes) -> OzResult<usize>;
-    async fn send_error_message(&self, message: Bytes) -> OzResult<usize>;
+    async fn send_error_message(&self, code: ErrorCode, message: Bytes) -> OzResult<usize>;
+    async fn send_error(&self, error: &OzesError) -> OzResult<usize>;
     async fn ok_subscribed(&self) -> OzResult<usize>;
     async fn ok_publisher(&self) -> OzResult<usize>;
     async fn ok_message(&self) -> OzResult<usize>;
+    async fn ok_queue(&self) -> OzResult<usize>;
+    async fn ok_client(&self) -> OzResult<usize>;
+    async fn ok_auth(&self) -> OzResult<usize>;
+    async fn ok_channel(&self) -> OzResult<usize>;
+    async fn ok_reply(&self, reply: &str, body: &[u8]) -> OzResult<usize>;
+    async fn ok_document(&self, reply: &str, document: &[u8]) -> OzResult<usize>;
+    async fn ping(&self) -> OzResult<usize>;
     async fn read_message(&self) -> OzResult<Bytes>;
+    async fn read_reply(&self, timeout: Duration) -> OzResult<Bytes>;
 }
 
 #[async_trait]
-impl Connection for OzesConnection {
+impl<S: Stream> Connection for OzesConnection<S> {
     async fn send_message(&self, message: Bytes) -> OzResult<usize> {
-        tokio::select! {
-            res = self.send(message) => {res},
-            _ = time::sleep(Duration::from_millis(500)) => {
-                log::error!("write message time out");
-                Err(OzesError::TimeOut)
-            }
-        }
+        self.send(message).await
     }
 
-    async fn send_error_message(&self, message: Bytes) -> OzResult<usize> {
-        let mut vec = Vec::with_capacity(message.len() + 7);
+    async fn send_error_message(&self, code: ErrorCode, message: Bytes) -> OzResult<usize> {
+        let code = code.as_str();
+        let mut vec = Vec::with_capacity(message.len() + code.len() + 8);
         vec.extend_from_slice(b"error #");
+        vec.extend_from_slice(code.as_bytes());
+        vec.push(b' ');
         vec.extend_from_slice(&message);
         self.send_message(Bytes::copy_from_slice(&vec)).await
     }
 
+    async fn send_error(&self, error: &OzesError) -> OzResult<usize> {
+        self.send_error_message(error.code(), Bytes::from(error.to_string()))
+            .await
+    }
+
     async fn ok_subscribed(&self) -> OzResult<usize> {
+        *self.ty.write().await = ConnectionType::Consumer;
         self.send_message(Bytes::from_static(b"ok subscribed"))
             .await
     }
@@ -137,17 +565,86 @@ impl Connection for OzesConnection {
         self.send_message(Bytes::from_static(b"ok message")).await
     }
 
+    async fn ok_queue(&self) -> OzResult<usize> {
+        self.send_message(Bytes::from_static(b"ok queue")).await
+    }
+
+    async fn ok_client(&self) -> OzResult<usize> {
+        self.send_message(Bytes::from_static(b"ok client")).await
+    }
+
+    async fn ok_auth(&self) -> OzResult<usize> {
+        self.send_message(Bytes::from_static(b"ok auth")).await
+    }
+
+    async fn ok_channel(&self) -> OzResult<usize> {
+        *self.ty.write().await = ConnectionType::Multiplexed;
+        self.send_message(Bytes::from_static(b"ok channel")).await
+    }
+
+    async fn ok_reply(&self, reply: &str, body: &[u8]) -> OzResult<usize> {
+        let mut vec = Vec::with_capacity(reply.len() + body.len() + 4);
+        vec.extend_from_slice(b"ok ");
+        vec.extend_from_slice(reply.as_bytes());
+        if !body.is_empty() {
+            vec.push(b' ');
+            vec.extend_from_slice(body);
+        }
+        self.send_message(Bytes::from(vec)).await
+    }
+
+    /// `ok <reply> +l<len> <document>`, the len is the one of the document
+    /// so clients know when they read all of it.
+    async fn ok_document(&self, reply: &str, document: &[u8]) -> OzResult<usize> {
+        let mut vec = Vec::with_capacity(reply.len() + document.len() + 16);
+        vec.extend_from_slice(b"ok ");
+        vec.extend_from_slice(reply.as_bytes());
+        vec.extend_from_slice(format!(" +l{} ", document.len()).as_bytes());
+        vec.extend_from_slice(document);
+        self.send_message(Bytes::from(vec)).await
+    }
+
+    async fn ping(&self) -> OzResult<usize> {
+        self.send_message(Bytes::from_static(b"ping")).await
+    }
+
     async fn read_message(&self) -> OzResult<Bytes> {
-        if let ConnectionType::Publisher = *self.ty.read().await {
+        if let ConnectionType::Publisher | ConnectionType::Multiplexed = *self.ty.read().await {
             Ok(self.read().await?)
         } else {
             tokio::select! {
                 res = self.read() => {Ok(res?)}
                 _ = time::sleep(Duration::from_millis(500)) => {
                     log::error!("write message time out");
+                    METRICS.timeout();
                     Err(OzesError::TimeOut)
                 }
             }
         }
     }
+
+    async fn read_reply(&self, timeout: Duration) -> OzResult<Bytes> {
+        let mut replies = self.replies.lock().await;
+        let mut closed = self.closed.subscribe();
+        if *closed.borrow() {
+            return Err(OzesError::WithouConnection);
+        }
+        *self.awaiting_reply.lock().unwrap() = true;
+        let reply = tokio::select! {
+            reply = replies.recv() => reply.ok_or(OzesError::WithouConnection),
+            _ = closed.changed() => Err(OzesError::WithouConnection),
+            _ = time::sleep(timeout) => Err(OzesError::TimeOut),
+        };
+        let mut awaiting_reply = self.awaiting_reply.lock().unwrap();
+        *awaiting_reply = false;
+        match reply {
+            // an awaited reply pushed as the time ran out is still taken
+            Err(OzesError::TimeOut) => replies.try_recv().map_err(|_| {
+                log::error!("read reply time out");
+                METRICS.timeout();
+                OzesError::TimeOut
+            }),
+            reply => reply,
+        }
+    }
 }
@@ -0,0 +1,440 @@
+//! Binary framing of the protocol version 2, negotiated with `HELLO`.
+//!
+//! Every frame is a fixed header, `opcode: u8`, `flags: u8`, `channel: u16`,
+//! `stream id: u32` and `length: u32` in big endian, followed by `length`
+//! bytes of body. The connection translates the frames of the channel 0 from
+//! and to the text statements of the version 1, so the broker and the client
+//! above it only deal with text, and routes the frames of other channels.
+
+use std::{fmt::Display, str::FromStr};
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    delivery_header, delivery_statement, message_statement,
+    server::error::{OzResult, OzesError},
+};
+
+pub const HEADER_LEN: usize = 12;
+/// Biggest body of a frame.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// Text statements, `message +l17 #foo`.
+    #[default]
+    V1,
+    /// Binary frames.
+    V2,
+}
+
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let version = match self {
+            Self::V1 => "1",
+            Self::V2 => "2",
+        };
+        write!(f, "{}", version)
+    }
+}
+
+impl FromStr for ProtocolVersion {
+    type Err = OzesError;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        match version {
+            "1" => Ok(Self::V1),
+            "2" => Ok(Self::V2),
+            _ => Err(OzesError::InvalidCommand(format!(
+                "unsupported protocol version {version}"
+            ))),
+        }
+    }
+}
+
+/// Kind of a frame, the body of `Message` and `Delivery` is the length of
+/// the options as `u16`, the options, `KEY <key> HEADER <name> <value>` or
+/// `<name>=<value>`, and the payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Opcode {
+    /// Any statement, in text.
+    Command = 1,
+    Ok = 2,
+    Error = 3,
+    Message = 4,
+    Delivery = 5,
+    Ack = 6,
+    Nack = 7,
+    Reject = 8,
+    Ping = 9,
+    Pong = 10,
+    /// The channel of the frame is closed.
+    Close = 11,
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = OzesError;
+
+    fn try_from(opcode: u8) -> Result<Self, OzesError> {
+        let opcode = match opcode {
+            1 => Self::Command,
+            2 => Self::Ok,
+            3 => Self::Error,
+            4 => Self::Message,
+            5 => Self::Delivery,
+            6 => Self::Ack,
+            7 => Self::Nack,
+            8 => Self::Reject,
+            9 => Self::Ping,
+            10 => Self::Pong,
+            11 => Self::Close,
+            _ => return Err(OzesError::InvalidCommand(format!("opcode {opcode}"))),
+        };
+        Ok(opcode)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FrameHeader {
+    pub opcode: Opcode,
+    /// Reserved, sent as zero.
+    pub flags: u8,
+    /// Channel of the frame, 0 is the connection itself.
+    pub channel: u16,
+    /// Replies have the stream id of their request, and acks the one of
+    /// their delivery.
+    pub stream_id: u32,
+    pub len: usize,
+}
+
+impl FrameHeader {
+    pub fn parse(header: &[u8; HEADER_LEN]) -> OzResult<Self> {
+        let len = u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(OzesError::ToLongMessage);
+        }
+        Ok(Self {
+            opcode: Opcode::try_from(header[0])?,
+            flags: header[1],
+            channel: u16::from_be_bytes([header[2], header[3]]),
+            stream_id: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+            len,
+        })
+    }
+
+    /// The frame with this header and `body`, the len of the header is not
+    /// used.
+    pub fn frame(&self, body: &[u8]) -> Bytes {
+        let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+        frame.push(self.opcode as u8);
+        frame.push(self.flags);
+        frame.extend_from_slice(&self.channel.to_be_bytes());
+        frame.extend_from_slice(&self.stream_id.to_be_bytes());
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(body);
+        Bytes::from(frame)
+    }
+}
+
+/// Read a whole frame, the end of the stream in the middle of a frame is
+/// the peer closing.
+pub(crate) async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> OzResult<(FrameHeader, Vec<u8>)> {
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header).await.map_err(read_error)?;
+    let header = FrameHeader::parse(&header)?;
+    let mut body = vec![0; header.len];
+    reader.read_exact(&mut body).await.map_err(read_error)?;
+    Ok((header, body))
+}
+
+fn read_error(error: std::io::Error) -> OzesError {
+    match error.kind() {
+        std::io::ErrorKind::UnexpectedEof => OzesError::WithouConnection,
+        _ => error.into(),
+    }
+}
+
+/// Frame telling the peer the channel is closed.
+pub(crate) fn close_frame(channel: u16) -> Bytes {
+    FrameHeader {
+        opcode: Opcode::Close,
+        flags: 0,
+        channel,
+        stream_id: 0,
+        len: 0,
+    }
+    .frame(&[])
+}
+
+/// Translation between the frames of one connection and the text
+/// statements, keeping the stream ids that tie replies to their requests.
+#[derive(Debug, Default)]
+pub(crate) struct FrameCodec {
+    next_stream_id: u32,
+    /// Stream of the last request read, its replies are sent in it.
+    request_stream_id: u32,
+    /// Stream and text length of the last delivery, the one answered by
+    /// acks, nacks and rejects.
+    delivery: Option<(u32, usize)>,
+}
+
+impl FrameCodec {
+    /// Frame of a text statement about to be written.
+    pub(crate) fn encode(&mut self, text: &[u8]) -> Bytes {
+        let (opcode, stream_id, body) = if let Some(delivery) = split_delivery(text) {
+            let (len, options, payload) = delivery;
+            let stream_id = self.next_stream_id();
+            self.delivery = Some((stream_id, len));
+            (Opcode::Delivery, stream_id, with_options(options, payload))
+        } else if let Some(message) = split_message(text) {
+            let (options, payload) = message;
+            let stream_id = self.next_stream_id();
+            (Opcode::Message, stream_id, with_options(options, payload))
+        } else if let Some(opcode) = answer_opcode(text) {
+            let stream_id = self.delivery.map(|(stream_id, _)| stream_id).unwrap_or(0);
+            (opcode, stream_id, Some(vec![]))
+        } else if text == b"ping" {
+            (Opcode::Ping, 0, Some(vec![]))
+        } else if text.eq_ignore_ascii_case(b"pong;") {
+            (Opcode::Pong, 0, Some(vec![]))
+        } else if let Some(error) = text.strip_prefix(b"error #") {
+            (Opcode::Error, self.request_stream_id, Some(error.to_vec()))
+        } else if let Some(ok) = text.strip_prefix(b"ok ") {
+            (Opcode::Ok, self.request_stream_id, Some(ok.to_vec()))
+        } else {
+            (Opcode::Command, self.next_stream_id(), Some(text.to_vec()))
+        };
+        // options too long for the frame go as text, that is valid in a
+        // command frame
+        let (opcode, body) = match body {
+            Some(body) => (opcode, body),
+            None => (Opcode::Command, text.to_vec()),
+        };
+        FrameHeader {
+            opcode,
+            flags: 0,
+            channel: 0,
+            stream_id,
+            len: body.len(),
+        }
+        .frame(&body)
+    }
+
+    /// Text statement of a frame just read.
+    pub(crate) fn decode(&mut self, header: FrameHeader, body: &[u8]) -> OzResult<Bytes> {
+        let text = match header.opcode {
+            Opcode::Command => {
+                self.request_stream_id = header.stream_id;
+                body.to_vec()
+            }
+            Opcode::Message => {
+                self.request_stream_id = header.stream_id;
+                let (options, payload) = split_options(body)?;
+                message_statement(options, payload)
+            }
+            Opcode::Delivery => {
+                let (options, payload) = split_options(body)?;
+                let delivery = delivery_statement(options, payload);
+                self.delivery = Some((header.stream_id, delivery.len()));
+                delivery
+            }
+            Opcode::Ack | Opcode::Nack | Opcode::Reject => {
+                // an answer to another delivery has a len the group refuses
+                let len = match self.delivery {
+                    Some((stream_id, len)) if stream_id == header.stream_id => len,
+                    _ => 0,
+                };
+                let answer = match header.opcode {
+                    Opcode::Ack => "ok",
+                    Opcode::Nack => "NACK",
+                    _ => "REJECT",
+                };
+                format!("{answer} +l{len};").into_bytes()
+            }
+            Opcode::Ok => [b"ok ", body].concat(),
+            Opcode::Error => [b"error #", body].concat(),
+            Opcode::Ping => b"ping".to_vec(),
+            Opcode::Pong => b"PONG;".to_vec(),
+            Opcode::Close => {
+                return Err(OzesError::InvalidCommand(String::from(
+                    "close frame without channel",
+                )))
+            }
+        };
+        Ok(Bytes::from(text))
+    }
+
+    fn next_stream_id(&mut self) -> u32 {
+        self.next_stream_id = self.next_stream_id.wrapping_add(1);
+        self.next_stream_id
+    }
+}
+
+/// Body of a `Message` or `Delivery` frame, `None` when the options do not
+/// fit in it.
+fn with_options(options: &str, payload: &[u8]) -> Option<Vec<u8>> {
+    let options_len = u16::try_from(options.len()).ok()?;
+    let mut body = Vec::with_capacity(2 + options.len() + payload.len());
+    body.extend_from_slice(&options_len.to_be_bytes());
+    body.extend_from_slice(options.as_bytes());
+    body.extend_from_slice(payload);
+    Some(body)
+}
+
+fn split_options(body: &[u8]) -> OzResult<(&str, &[u8])> {
+    let invalid = || OzesError::InvalidCommand(String::from("frame without options length"));
+    let options_len = body.get(..2).ok_or_else(invalid)?;
+    let options_len = u16::from_be_bytes([options_len[0], options_len[1]]) as usize;
+    let options = body.get(2..2 + options_len).ok_or_else(invalid)?;
+    let options = std::str::from_utf8(options)
+        .map_err(|_| OzesError::InvalidCommand(String::from("options are not utf-8")))?;
+    Ok((options, &body[2 + options_len..]))
+}
+
+/// Length, headers and payload of `+l<len> <name>=<value> #<payload>`.
+fn split_delivery(text: &[u8]) -> Option<(usize, &str, &[u8])> {
+    let (len, header_len) = delivery_header(text)?;
+    let header = std::str::from_utf8(&text[..header_len - 2]).ok()?;
+    let headers = header.split_once(' ').map(|(_, headers)| headers.trim());
+    Some((len, headers.unwrap_or_default(), &text[header_len..]))
+}
+
+/// Options and payload of `MESSAGE <options> +l<len> #<payload>`.
+fn split_message(text: &[u8]) -> Option<(&str, &[u8])> {
+    let separator = text.windows(2).position(|window| window == b" #")?;
+    let head = std::str::from_utf8(&text[..separator]).ok()?;
+    let (keyword, rest) = head.split_once(' ')?;
+    let (options, len) = rest.rsplit_once(' ').unwrap_or(("", rest));
+    if !keyword.eq_ignore_ascii_case("message") || !is_len(len) {
+        return None;
+    }
+    Some((options.trim(), &text[separator + 2..]))
+}
+
+/// `ok +l<len>;`, `NACK +l<len>;` or `REJECT +l<len>;`.
+fn answer_opcode(text: &[u8]) -> Option<Opcode> {
+    let text = std::str::from_utf8(text).ok()?;
+    let (answer, len) = text.trim_end().trim_end_matches(';').split_once(' ')?;
+    if !is_len(len) {
+        return None;
+    }
+    [
+        ("ok", Opcode::Ack),
+        ("nack", Opcode::Nack),
+        ("reject", Opcode::Reject),
+    ]
+    .into_iter()
+    .find(|(keyword, _)| answer.eq_ignore_ascii_case(keyword))
+    .map(|(_, opcode)| opcode)
+}
+
+fn is_len(token: &str) -> bool {
+    token
+        .strip_prefix("+l")
+        .is_some_and(|digits| !digits.is_empty() && digits.bytes().all(|c| c.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(codec: &mut FrameCodec, text: &[u8]) -> (Opcode, Bytes) {
+        let frame = codec.encode(text);
+        let header = FrameHeader::parse(frame[..HEADER_LEN].try_into().unwrap()).unwrap();
+        assert_eq!(header.len, frame.len() - HEADER_LEN);
+        let decoded = FrameCodec::default()
+            .decode(header, &frame[HEADER_LEN..])
+            .unwrap();
+        (header.opcode, decoded)
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = FrameHeader {
+            opcode: Opcode::Nack,
+            flags: 0,
+            channel: 7,
+            stream_id: 42,
+            len: 0,
+        };
+        let frame = header.frame(b"body");
+        let parsed = FrameHeader::parse(frame[..HEADER_LEN].try_into().unwrap()).unwrap();
+        assert_eq!(parsed.opcode, Opcode::Nack);
+        assert_eq!(parsed.channel, 7);
+        assert_eq!(parsed.stream_id, 42);
+        assert_eq!(parsed.len, 4);
+        assert_eq!(&frame[HEADER_LEN..], b"body");
+    }
+
+    #[test]
+    fn header_limits() {
+        let mut header = [0; HEADER_LEN];
+        header[0] = Opcode::Command as u8;
+        header[8..].copy_from_slice(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes());
+        assert!(matches!(
+            FrameHeader::parse(&header),
+            Err(OzesError::ToLongMessage)
+        ));
+        header[0] = 99;
+        header[8..].copy_from_slice(&0u32.to_be_bytes());
+        assert!(FrameHeader::parse(&header).is_err());
+    }
+
+    #[test]
+    fn statements_round_trip() {
+        let mut codec = FrameCodec::default();
+        let message = message_statement("KEY 42 HEADER trace abc", b"hello #world");
+        let delivery = delivery_statement("trace=abc", b"hello");
+        for (text, opcode) in [
+            (&message[..], Opcode::Message),
+            (&message_statement("", b"hello")[..], Opcode::Message),
+            (&delivery[..], Opcode::Delivery),
+            (
+                &b"SUBSCRIBE orders WITH GROUP billing;"[..],
+                Opcode::Command,
+            ),
+            (&b"ok subscribed"[..], Opcode::Ok),
+            (&b"error #PARSE_ERROR invalid"[..], Opcode::Error),
+            (&b"ping"[..], Opcode::Ping),
+            (&b"PONG;"[..], Opcode::Pong),
+        ] {
+            let (encoded, decoded) = round_trip(&mut codec, text);
+            assert_eq!(encoded, opcode);
+            assert_eq!(&decoded[..], text);
+        }
+    }
+
+    #[test]
+    fn answers_take_the_delivery_stream() {
+        let mut server = FrameCodec::default();
+        let mut client = FrameCodec::default();
+        let delivery = server.encode(&delivery_statement("", b"hello"));
+        let header = FrameHeader::parse(delivery[..HEADER_LEN].try_into().unwrap()).unwrap();
+        let text = client.decode(header, &delivery[HEADER_LEN..]).unwrap();
+        let (len, _) = delivery_header(&text).unwrap();
+
+        let answer = client.encode(format!("NACK +l{len};").as_bytes());
+        let answer_header = FrameHeader::parse(answer[..HEADER_LEN].try_into().unwrap()).unwrap();
+        assert_eq!(answer_header.opcode, Opcode::Nack);
+        assert_eq!(answer_header.stream_id, header.stream_id);
+        let answer = server.decode(answer_header, &[]).unwrap();
+        assert_eq!(answer, format!("NACK +l{len};"));
+
+        let stale = FrameHeader {
+            stream_id: header.stream_id + 1,
+            ..answer_header
+        };
+        assert_eq!(server.decode(stale, &[]).unwrap(), "NACK +l0;");
+    }
+
+    #[test]
+    fn protocol_versions() {
+        assert_eq!("2".parse::<ProtocolVersion>().unwrap(), ProtocolVersion::V2);
+        assert_eq!(ProtocolVersion::V1.to_string(), "1");
+        assert!("3".parse::<ProtocolVersion>().is_err());
+    }
+}
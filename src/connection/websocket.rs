@@ -0,0 +1,103 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+/// Byte stream over WebSocket frames, so a WebSocket client is served by the
+/// same `OzesConnection` as a TCP one.
+///
+/// Every received text or binary frame is read as is, and the bytes written
+/// between two flushes are sent in a single frame, text when they are valid
+/// UTF-8, which is the case of all ozes commands.
+pub struct WebSocketStream<S> {
+    inner: tokio_tungstenite::WebSocketStream<S>,
+    read_buffer: Vec<u8>,
+    read_position: usize,
+    write_buffer: Vec<u8>,
+}
+
+impl<S> WebSocketStream<S> {
+    pub fn new(inner: tokio_tungstenite::WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buffer: vec![],
+            read_position: 0,
+            write_buffer: vec![],
+        }
+    }
+}
+
+fn to_io_error(error: tungstenite::Error) -> io::Error {
+    match error {
+        tungstenite::Error::Io(error) => error,
+        error => io::Error::other(error),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let remaining = &self.read_buffer[self.read_position..];
+            if !remaining.is_empty() {
+                let size = remaining.len().min(buf.remaining());
+                buf.put_slice(&remaining[..size]);
+                self.read_position += size;
+                return Poll::Ready(Ok(()));
+            }
+            let frame = match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => data,
+                Some(Ok(Message::Text(text))) => text.into_bytes(),
+                // ping and pong are answered by tungstenite
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(error)) => return Poll::Ready(Err(to_io_error(error))),
+            };
+            self.read_buffer = frame;
+            self.read_position = 0;
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.write_buffer.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write_buffer.is_empty() {
+            ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(to_io_error)?;
+            let data = std::mem::take(&mut self.write_buffer);
+            let message = match String::from_utf8(data) {
+                Ok(text) => Message::Text(text),
+                Err(error) => Message::Binary(error.into_bytes()),
+            };
+            Pin::new(&mut self.inner)
+                .start_send(message)
+                .map_err(to_io_error)?;
+        }
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(to_io_error)
+    }
+}
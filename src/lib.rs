@@ -1,3 +1,5 @@
+pub mod client;
+pub mod compression;
 pub mod connection;
 pub mod server;
 
@@ -7,3 +9,76 @@ const BASE_MESSAGE_LEN: usize = "message +l #".len();
 pub(crate) fn number_len(number: usize) -> usize {
     number.to_string().len()
 }
+
+/// Total length and header length of the delivery, `+l<len> #<message>` or
+/// `+l<len> <name>=<value> #<message>`, in the start of the buffer.
+pub(crate) fn delivery_header(buffer: &[u8]) -> Option<(usize, usize)> {
+    let rest = buffer.strip_prefix(b"+l")?;
+    let digits = rest.iter().position(|c| !c.is_ascii_digit())?;
+    let len = std::str::from_utf8(&rest[..digits]).ok()?.parse().ok()?;
+    let separator = rest.windows(2).position(|window| window == b" #")?;
+    Some((len, separator + 4))
+}
+
+/// Length of a statement with `len` in its header, `fixed` bytes besides the
+/// len and the message. The len counts the digits of the message len, not
+/// its own, so near a power of ten the statement is one byte longer.
+fn statement_len(len: usize, fixed: usize) -> usize {
+    (1..=number_len(len))
+        .filter_map(|digits| len.checked_sub(fixed + digits))
+        .find(|message_len| message_len + number_len(*message_len) + fixed == len)
+        .map_or(len, |message_len| message_len + number_len(len) + fixed)
+}
+
+/// Length of the delivery with `len` in its header, `+l<len> #`.
+pub(crate) fn delivery_len(len: usize) -> usize {
+    statement_len(len, "+l #".len())
+}
+
+/// Length of the published message with `len`, `message +l<len> #`.
+pub(crate) fn message_len(len: usize) -> usize {
+    statement_len(len, BASE_MESSAGE_LEN)
+}
+
+/// Statement of a published message, `message +l<len> #<payload>` or, with
+/// options like the key and headers, `MESSAGE <options> +l<len> #<payload>`.
+pub(crate) fn message_statement(options: &str, payload: &[u8]) -> Vec<u8> {
+    let mut statement = Vec::with_capacity(payload.len() + options.len() + 32);
+    if options.is_empty() {
+        let len = payload.len() + number_len(payload.len()) + BASE_MESSAGE_LEN;
+        statement.extend_from_slice(format!("message +l{len} #").as_bytes());
+    } else {
+        let head = format!("MESSAGE {options} +l");
+        // the len goes from the start of the statement to the end of the
+        // payload, counting its own digits
+        let fixed_len = head.len() + " #".len() + payload.len();
+        let len = fixed_len + number_len(fixed_len);
+        let len = fixed_len + number_len(len);
+        statement.extend_from_slice(format!("{head}{len} #").as_bytes());
+    }
+    statement.extend_from_slice(payload);
+    statement
+}
+
+/// Message as sent to consumers, `+l<len> #<payload>` or, with headers,
+/// `+l<len> <name>=<value> #<payload>`.
+pub(crate) fn delivery_statement(headers: &str, payload: &[u8]) -> Vec<u8> {
+    const SIZE_INFO: usize = 4;
+    let headers_len = if headers.is_empty() {
+        0
+    } else {
+        headers.len() + 1
+    };
+    let message_len = payload.len() + headers_len;
+    let final_size = message_len + number_len(message_len) + SIZE_INFO;
+    let mut statement = Vec::with_capacity(final_size + 1);
+    statement.extend_from_slice(b"+l");
+    statement.extend_from_slice(final_size.to_string().as_bytes());
+    if !headers.is_empty() {
+        statement.push(b' ');
+        statement.extend_from_slice(headers.as_bytes());
+    }
+    statement.extend_from_slice(b" #");
+    statement.extend_from_slice(payload);
+    statement
+}
@@ -1,10 +1,9 @@
-use fast_log::Config;
-use ozes::server;
+use ozes::server::{self, Config};
 
 #[tokio::main]
 async fn main() {
-    fast_log::init(Config::new().console()).unwrap();
-    if let Err(e) = server::start_server(7656).await {
+    fast_log::init(fast_log::Config::new().console()).unwrap();
+    if let Err(e) = server::start_server_with_config(Config::from_env()).await {
         log::error!("error on startup server {}", e)
     }
 }
@@ -0,0 +1,232 @@
+use std::{fmt::Display, fs, path::Path, str::FromStr};
+
+use super::error::{OzResult, OzesError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Publish,
+    Consume,
+    Create,
+    Delete,
+    /// Manage connections, checked against the `*` queue.
+    Admin,
+}
+
+impl FromStr for Permission {
+    type Err = OzesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "publish" => Ok(Self::Publish),
+            "consume" => Ok(Self::Consume),
+            "create" => Ok(Self::Create),
+            "delete" => Ok(Self::Delete),
+            "admin" => Ok(Self::Admin),
+            _ => Err(OzesError::UnknownError(format!("unknown permission {}", s))),
+        }
+    }
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let permission = match self {
+            Self::Publish => "publish",
+            Self::Consume => "consume",
+            Self::Create => "create",
+            Self::Delete => "delete",
+            Self::Admin => "admin",
+        };
+        write!(f, "{}", permission)
+    }
+}
+
+struct Rule {
+    user: String,
+    permissions: Vec<Permission>,
+    queue: String,
+    group: String,
+}
+
+/// Access control list loaded from a file with one rule by line:
+///
+/// `<user> <permission>[,<permission>...] <queue> [group]`
+///
+/// User, queue and group accept glob patterns with `*` and `?`, a missing
+/// group match every group. Anything not allowed by a rule is denied.
+pub(crate) struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub(crate) fn load(path: &Path) -> OzResult<Self> {
+        let mut rules = vec![];
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let (user, permissions, queue, group) = match tokens[..] {
+                [user, permissions, queue] => (user, permissions, queue, "*"),
+                [user, permissions, queue, group] => (user, permissions, queue, group),
+                _ => {
+                    return Err(OzesError::UnknownError(format!(
+                        "invalid acl rule at line {} of {}",
+                        number + 1,
+                        path.display()
+                    )))
+                }
+            };
+            rules.push(Rule {
+                user: user.to_string(),
+                permissions: permissions
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<OzResult<Vec<Permission>>>()?,
+                queue: queue.to_string(),
+                group: group.to_string(),
+            });
+        }
+        log::info!("access control enabled with {} rules", rules.len());
+        Ok(Self { rules })
+    }
+
+    /// Check the permission and write denials to the audit log.
+    pub(crate) fn check(
+        &self,
+        user: Option<&str>,
+        permission: Permission,
+        queue: &str,
+        group: Option<&str>,
+    ) -> OzResult<()> {
+        if self.allows(user, permission, queue, group) {
+            return Ok(());
+        }
+        let user = user.unwrap_or("anonymous");
+        let target = match group {
+            Some(group) => format!("queue {} group {}", queue, group),
+            None => format!("queue {}", queue),
+        };
+        log::warn!(target: "audit", "denied {} on {} to user {}", permission, target, user);
+        Err(OzesError::AccessDenied(format!(
+            "user {} cannot {} on {}",
+            user, permission, target
+        )))
+    }
+
+    /// Whether a rule allows the permission, without audit.
+    pub(crate) fn allows(
+        &self,
+        user: Option<&str>,
+        permission: Permission,
+        queue: &str,
+        group: Option<&str>,
+    ) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.permissions.contains(&permission)
+                && match user {
+                    Some(user) => glob_match(&rule.user, user),
+                    None => rule.user == "*",
+                }
+                && glob_match(&rule.queue, queue)
+                && match group {
+                    Some(group) => glob_match(&rule.group, group),
+                    None => true,
+                }
+        })
+    }
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let text = text.as_bytes();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(rules: &str) -> Acl {
+        let path = std::env::temp_dir().join(format!("ozes-acl-{}", std::process::id()));
+        fs::write(&path, rules).unwrap();
+        let acl = Acl::load(&path);
+        let _ = fs::remove_file(&path);
+        acl.unwrap()
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("orders.*", "orders.eu"));
+        assert!(glob_match("orders.*", "orders."));
+        assert!(!glob_match("orders.*", "orders"));
+        assert!(glob_match("*.dlq", "orders.eu.dlq"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+        assert!(glob_match("user-??", "user-42"));
+        assert!(!glob_match("user-??", "user-4"));
+        assert!(!glob_match("orders", "orders.eu"));
+    }
+
+    #[test]
+    fn rules_allow_and_deny() {
+        let acl = acl("# comment\n\
+             alice publish,consume orders.*\n\
+             bob consume orders billing-*\n\
+             * consume public\n\
+             admin admin *\n");
+        assert!(acl
+            .check(Some("alice"), Permission::Publish, "orders.eu", None)
+            .is_ok());
+        assert!(acl
+            .check(Some("alice"), Permission::Create, "orders.eu", None)
+            .is_err());
+        assert!(acl.allows(
+            Some("bob"),
+            Permission::Consume,
+            "orders",
+            Some("billing-1")
+        ));
+        assert!(!acl.allows(Some("bob"), Permission::Consume, "orders", Some("audit")));
+        assert!(acl.allows(None, Permission::Consume, "public", None));
+        assert!(!acl.allows(None, Permission::Consume, "orders", None));
+        assert!(acl.allows(Some("admin"), Permission::Admin, "*", None));
+        assert!(matches!(
+            acl.check(Some("bob"), Permission::Publish, "orders", None),
+            Err(OzesError::AccessDenied(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let path = std::env::temp_dir().join(format!("ozes-acl-invalid-{}", std::process::id()));
+        for rules in ["alice publish", "alice read orders"] {
+            fs::write(&path, rules).unwrap();
+            assert!(Acl::load(&path).is_err());
+        }
+        let _ = fs::remove_file(&path);
+    }
+}
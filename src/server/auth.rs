@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use sha2::Sha256;
+use tokio::sync::Semaphore;
+
+use super::error::{OzResult, OzesError};
+
+/// Rounds of PBKDF2 of the passwords hashed by `hash_password`.
+pub const PBKDF2_ROUNDS: u32 = 600_000;
+
+const HASH_LEN: usize = 32;
+
+/// Failed authentications from an address before its credentials are
+/// refused without checking them, until `FAILURE_WINDOW` passes.
+const MAX_FAILURES: u32 = 10;
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+/// Addresses with failures kept before the ones out of their window are
+/// forgotten.
+const MAX_TRACKED_ADDRESSES: usize = 10_000;
+/// Key derivations running at once, each one holds a blocking thread for a
+/// while.
+const MAX_DERIVATIONS: usize = 4;
+
+#[derive(Clone, Debug, Default)]
+pub struct AuthConfig {
+    /// File with one `username:rounds:salt:hash` per line, the hash being the
+    /// PBKDF2-HMAC-SHA256 of the password in hex, see `hash_password`. Lines
+    /// starting with `#` are ignored.
+    pub credentials_path: Option<PathBuf>,
+    /// Static tokens and the user they authenticate.
+    pub tokens: HashMap<String, String>,
+}
+
+#[derive(Clone)]
+pub enum Credentials {
+    Password { username: String, password: String },
+    Token(String),
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Password { username, .. } => f
+                .debug_struct("Password")
+                .field("username", username)
+                .finish_non_exhaustive(),
+            Self::Token(_) => f.write_str("Token(..)"),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct HashedPassword {
+    rounds: u32,
+    salt: String,
+    hash: String,
+}
+
+impl HashedPassword {
+    fn verify(&self, password: &str) -> bool {
+        let hash = derive(self.rounds, &self.salt, password);
+        constant_time_eq(hash.as_bytes(), self.hash.as_bytes())
+    }
+}
+
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+pub(crate) struct Authenticator {
+    users: HashMap<String, HashedPassword>,
+    tokens: HashMap<String, String>,
+    /// Checked for unknown users, so they take as long as the known ones.
+    dummy: HashedPassword,
+    derivations: Semaphore,
+    /// Failures by address, `None` for the clients without one like the Unix
+    /// socket ones.
+    failures: Mutex<HashMap<Option<IpAddr>, Failures>>,
+}
+
+impl Authenticator {
+    pub(crate) fn load(config: &AuthConfig) -> OzResult<Self> {
+        let mut users = HashMap::new();
+        if let Some(path) = &config.credentials_path {
+            for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let credential = match line.split(':').collect::<Vec<&str>>()[..] {
+                    [username, rounds, salt, hash] => rounds
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|rounds| *rounds > 0)
+                        .map(|rounds| HashedPassword {
+                            rounds,
+                            salt: salt.to_string(),
+                            hash: hash.to_ascii_lowercase(),
+                        })
+                        .map(|hashed| (username, hashed)),
+                    _ => None,
+                };
+                match credential {
+                    Some((username, hashed)) => {
+                        users.insert(username.to_string(), hashed);
+                    }
+                    None => {
+                        return Err(OzesError::UnknownError(format!(
+                            "line {} of {} is not username:rounds:salt:hash",
+                            number + 1,
+                            path.display()
+                        )))
+                    }
+                }
+            }
+        }
+        log::info!(
+            "authentication enabled with {} users and {} tokens",
+            users.len(),
+            config.tokens.len()
+        );
+        Ok(Self {
+            users,
+            tokens: config.tokens.clone(),
+            dummy: HashedPassword {
+                rounds: PBKDF2_ROUNDS,
+                salt: String::from("dummy"),
+                hash: String::new(),
+            },
+            derivations: Semaphore::new(MAX_DERIVATIONS),
+            failures: Mutex::default(),
+        })
+    }
+
+    /// Return the authenticated user, `None` for invalid credentials and for
+    /// every credential of an address with too many failures.
+    pub(crate) async fn authenticate(
+        &self,
+        credentials: &Credentials,
+        address: Option<IpAddr>,
+    ) -> Option<String> {
+        if self.is_locked_out(address) {
+            log::warn!(
+                "authentication from {} refused after too many failures",
+                address.map_or_else(
+                    || String::from("local client"),
+                    |address| address.to_string()
+                )
+            );
+            return None;
+        }
+        let user = self.check(credentials).await;
+        if user.is_none() {
+            self.fail(address);
+        }
+        user
+    }
+
+    /// The key derivation of passwords is slow on purpose, so it runs out of
+    /// the runtime threads and only a few at once.
+    async fn check(&self, credentials: &Credentials) -> Option<String> {
+        match credentials {
+            Credentials::Password { username, password } => {
+                let (hashed, known) = match self.users.get(username) {
+                    Some(hashed) => (hashed.clone(), true),
+                    None => (self.dummy.clone(), false),
+                };
+                let password = password.clone();
+                let _permit = self.derivations.acquire().await.ok()?;
+                let valid = tokio::task::spawn_blocking(move || hashed.verify(&password))
+                    .await
+                    .ok()?;
+                (known && valid).then(|| username.clone())
+            }
+            Credentials::Token(token) => self
+                .tokens
+                .iter()
+                .find(|(known, _)| constant_time_eq(known.as_bytes(), token.as_bytes()))
+                .map(|(_, username)| username.clone()),
+        }
+    }
+
+    fn is_locked_out(&self, address: Option<IpAddr>) -> bool {
+        let failures = self.failures.lock().unwrap();
+        failures.get(&address).is_some_and(|failures| {
+            failures.count >= MAX_FAILURES && failures.since.elapsed() < FAILURE_WINDOW
+        })
+    }
+
+    fn fail(&self, address: Option<IpAddr>) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED_ADDRESSES {
+            failures.retain(|_, failures| failures.since.elapsed() < FAILURE_WINDOW);
+        }
+        let failures = failures.entry(address).or_insert(Failures {
+            count: 0,
+            since: Instant::now(),
+        });
+        if failures.since.elapsed() >= FAILURE_WINDOW {
+            failures.count = 0;
+            failures.since = Instant::now();
+        }
+        failures.count += 1;
+    }
+}
+
+/// Password as stored in the credentials file after the username,
+/// `rounds:salt:hash`.
+pub fn hash_password(salt: &str, password: &str) -> String {
+    format!(
+        "{PBKDF2_ROUNDS}:{salt}:{}",
+        derive(PBKDF2_ROUNDS, salt, password)
+    )
+}
+
+fn derive(rounds: u32, salt: &str, password: &str) -> String {
+    let mut hash = [0; HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), rounds, &mut hash);
+    hex::encode(hash)
+}
+
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0, |diff, (left, right)| diff | (left ^ right))
+            == 0
+}
@@ -0,0 +1,355 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    fmt::Display,
+    hash::{Hash, Hasher},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{Mutex as AsyncMutex, MutexGuard};
+
+use crate::{compression::Encodings, connection::OzesConnection};
+
+use super::{error::OzesError, group::SubscribeOptions};
+
+static MEMBER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A connection inside a group, the id is stable while the member lives so
+/// strategies never depend on the position of the member in the group.
+pub struct Member {
+    id: u64,
+    connection: Arc<OzesConnection>,
+    weight: u32,
+    ack_timeout: Duration,
+    headers: bool,
+    accept_encoding: Encodings,
+    outstanding: AtomicUsize,
+    /// Held while a delivery waits its reply, the replies of a connection
+    /// do not say which delivery they answer.
+    turn: AsyncMutex<()>,
+}
+
+impl Member {
+    pub fn new(connection: Arc<OzesConnection>, options: SubscribeOptions) -> Self {
+        Self {
+            id: MEMBER_ID.fetch_add(1, Ordering::Relaxed),
+            connection,
+            weight: options.weight.max(1),
+            ack_timeout: options.ack_timeout,
+            headers: options.headers,
+            accept_encoding: options.accept_encoding,
+            outstanding: AtomicUsize::new(0),
+            turn: AsyncMutex::new(()),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn connection(&self) -> &Arc<OzesConnection> {
+        &self.connection
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// Time the group waits the reply to a message sent to this member.
+    pub fn ack_timeout(&self) -> Duration {
+        self.ack_timeout
+    }
+
+    /// The member receives the headers of the messages.
+    pub fn headers(&self) -> bool {
+        self.headers
+    }
+
+    pub fn accept_encoding(&self) -> Encodings {
+        self.accept_encoding
+    }
+
+    /// Deliveries given to the member and not answered yet, the ones waiting
+    /// their turn included.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn start_delivery(&self) {
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn finish_delivery(&self) {
+        self.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Wait until the deliveries given before to the member are answered.
+    pub(crate) async fn turn(&self) -> MutexGuard<'_, ()> {
+        self.turn.lock().await
+    }
+}
+
+/// Strategy used by a group to choose which member receives a message.
+pub trait LoadBalancer: Send + Sync {
+    /// Return the id of the member that receives the next message, `key` is
+    /// the message key when the publisher sent one.
+    fn pick(&self, members: &[Arc<Member>], key: Option<&[u8]>) -> Option<u64>;
+
+    fn on_join(&self, _member: &Member) {}
+
+    fn on_leave(&self, _member: &Member) {}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    LeastOutstanding,
+    Weighted,
+    ConsistentHash,
+}
+
+impl Strategy {
+    pub fn balancer(&self) -> Box<dyn LoadBalancer> {
+        match self {
+            Self::RoundRobin => Box::<RoundRobin>::default(),
+            Self::LeastOutstanding => Box::new(LeastOutstanding),
+            Self::Weighted => Box::<Weighted>::default(),
+            Self::ConsistentHash => Box::<ConsistentHash>::default(),
+        }
+    }
+}
+
+impl Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let strategy = match self {
+            Self::RoundRobin => "round-robin",
+            Self::LeastOutstanding => "least-outstanding",
+            Self::Weighted => "weighted",
+            Self::ConsistentHash => "consistent-hash",
+        };
+        write!(f, "{}", strategy)
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = OzesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-outstanding" => Ok(Self::LeastOutstanding),
+            "weighted" => Ok(Self::Weighted),
+            "consistent-hash" => Ok(Self::ConsistentHash),
+            _ => Err(OzesError::InvalidCommand(format!("unknown strategy {}", s))),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl LoadBalancer for RoundRobin {
+    fn pick(&self, members: &[Arc<Member>], _key: Option<&[u8]>) -> Option<u64> {
+        if members.is_empty() {
+            return None;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(members[next % members.len()].id())
+    }
+}
+
+pub struct LeastOutstanding;
+
+impl LoadBalancer for LeastOutstanding {
+    fn pick(&self, members: &[Arc<Member>], _key: Option<&[u8]>) -> Option<u64> {
+        members
+            .iter()
+            .min_by_key(|member| member.outstanding())
+            .map(|member| member.id())
+    }
+}
+
+/// Smooth weighted round-robin, a member with weight 3 receives three
+/// messages for each message of a member with weight 1, interleaved.
+#[derive(Default)]
+pub struct Weighted {
+    current: Mutex<HashMap<u64, i64>>,
+}
+
+impl LoadBalancer for Weighted {
+    fn pick(&self, members: &[Arc<Member>], _key: Option<&[u8]>) -> Option<u64> {
+        let mut current = self.current.lock().unwrap();
+        let total: i64 = members.iter().map(|member| member.weight() as i64).sum();
+        let mut best: Option<(u64, i64)> = None;
+        for member in members {
+            let weight = current.entry(member.id()).or_insert(0);
+            *weight += member.weight() as i64;
+            if !matches!(best, Some((_, best_weight)) if best_weight >= *weight) {
+                best = Some((member.id(), *weight));
+            }
+        }
+        let (id, _) = best?;
+        if let Some(weight) = current.get_mut(&id) {
+            *weight -= total;
+        }
+        Some(id)
+    }
+
+    fn on_leave(&self, member: &Member) {
+        self.current.lock().unwrap().remove(&member.id());
+    }
+}
+
+const VIRTUAL_NODES: u64 = 64;
+
+/// Hash ring with virtual nodes, a key keep going to the same member while it
+/// lives and only the keys of a member that leaves are moved.
+#[derive(Default)]
+pub struct ConsistentHash {
+    ring: Mutex<BTreeMap<u64, u64>>,
+    fallback: RoundRobin,
+}
+
+impl ConsistentHash {
+    fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl LoadBalancer for ConsistentHash {
+    fn pick(&self, members: &[Arc<Member>], key: Option<&[u8]>) -> Option<u64> {
+        let key = match key {
+            Some(key) => key,
+            None => return self.fallback.pick(members, None),
+        };
+        let ring = self.ring.lock().unwrap();
+        let hash = Self::hash(key);
+        ring.range(hash..)
+            .chain(ring.iter())
+            .map(|(_, id)| *id)
+            .find(|id| members.iter().any(|member| member.id() == *id))
+    }
+
+    fn on_join(&self, member: &Member) {
+        let mut ring = self.ring.lock().unwrap();
+        for node in 0..VIRTUAL_NODES {
+            ring.insert(Self::hash(&(member.id(), node)), member.id());
+        }
+    }
+
+    fn on_leave(&self, member: &Member) {
+        self.ring.lock().unwrap().retain(|_, id| *id != member.id());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{BoxedStream, PeerAddress};
+
+    fn member(weight: u32) -> Arc<Member> {
+        let (stream, _) = tokio::io::duplex(64);
+        let connection = OzesConnection::new(
+            Box::new(stream) as BoxedStream,
+            PeerAddress::Internal(String::from("test")),
+        );
+        let options = SubscribeOptions {
+            weight,
+            ..Default::default()
+        };
+        Arc::new(Member::new(Arc::new(connection), options))
+    }
+
+    fn picks(balancer: &dyn LoadBalancer, members: &[Arc<Member>], count: usize) -> Vec<u64> {
+        (0..count)
+            .map(|_| balancer.pick(members, None).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_cycles_members() {
+        let members = vec![member(1), member(1), member(1)];
+        let ids: Vec<u64> = members.iter().map(|member| member.id()).collect();
+        let balancer = RoundRobin::default();
+        assert_eq!(picks(&balancer, &members, 6), [&ids[..], &ids[..]].concat());
+        assert_eq!(balancer.pick(&[], None), None);
+    }
+
+    #[test]
+    fn least_outstanding_picks_the_least_busy() {
+        let members = vec![member(1), member(1), member(1)];
+        members[0].start_delivery();
+        members[0].start_delivery();
+        members[1].start_delivery();
+        let balancer = LeastOutstanding;
+        assert_eq!(balancer.pick(&members, None), Some(members[2].id()));
+        members[2].start_delivery();
+        members[2].start_delivery();
+        members[2].start_delivery();
+        assert_eq!(balancer.pick(&members, None), Some(members[1].id()));
+        members[0].finish_delivery();
+        members[0].finish_delivery();
+        assert_eq!(balancer.pick(&members, None), Some(members[0].id()));
+    }
+
+    #[test]
+    fn weighted_follows_weights_interleaved() {
+        let members = vec![member(3), member(1)];
+        let (heavy, light) = (members[0].id(), members[1].id());
+        let balancer = Weighted::default();
+        let picked = picks(&balancer, &members, 8);
+        assert_eq!(picked.iter().filter(|id| **id == heavy).count(), 6);
+        assert_eq!(picked.iter().filter(|id| **id == light).count(), 2);
+        assert!(picked.windows(4).all(|window| window.contains(&light)));
+    }
+
+    #[test]
+    fn consistent_hash_keeps_keys_on_members() {
+        let members = vec![member(1), member(1), member(1)];
+        let balancer = ConsistentHash::default();
+        for member in &members {
+            balancer.on_join(member);
+        }
+        let keys: Vec<String> = (0..50).map(|key| format!("key-{key}")).collect();
+        let owners: Vec<u64> = keys
+            .iter()
+            .map(|key| balancer.pick(&members, Some(key.as_bytes())).unwrap())
+            .collect();
+        for (key, owner) in keys.iter().zip(&owners) {
+            assert_eq!(balancer.pick(&members, Some(key.as_bytes())), Some(*owner));
+        }
+
+        let gone = &members[0];
+        balancer.on_leave(gone);
+        let rest = &members[1..];
+        for (key, owner) in keys.iter().zip(&owners) {
+            let picked = balancer.pick(rest, Some(key.as_bytes())).unwrap();
+            if *owner != gone.id() {
+                assert_eq!(picked, *owner);
+            }
+            assert_ne!(picked, gone.id());
+        }
+    }
+
+    #[test]
+    fn strategy_names_round_trip() {
+        for strategy in [
+            Strategy::RoundRobin,
+            Strategy::LeastOutstanding,
+            Strategy::Weighted,
+            Strategy::ConsistentHash,
+        ] {
+            assert_eq!(strategy.to_string().parse::<Strategy>().unwrap(), strategy);
+        }
+        assert!("random".parse::<Strategy>().is_err());
+    }
+}
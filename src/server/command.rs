@@ -0,0 +1,779 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::{
+    compression::{Compression, Encodings, CONTENT_ENCODING},
+    connection::ProtocolVersion,
+    message_len,
+};
+
+use super::{
+    auth::Credentials,
+    balancer::Strategy,
+    error::{OzResult, OzesError},
+    group::{GroupMode, SubscribeOptions},
+};
+
+const DEFAULT_PEEK_COUNT: usize = 10;
+const MAX_PEEK_COUNT: usize = 100;
+const MAX_ACK_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub(crate) enum ExtCommand {
+    Subscribe {
+        queue_name: String,
+        group_name: String,
+        options: SubscribeOptions,
+    },
+    CreateQueue {
+        queue_name: String,
+        partitions: Option<usize>,
+    },
+    Message {
+        message: Bytes,
+        key: Option<Bytes>,
+        headers: Vec<(String, String)>,
+        /// Queue of `MESSAGE TO`, otherwise the one of `PUBLISHER`.
+        queue_name: Option<String>,
+    },
+    Pong,
+    /// Negotiate the protocol of the connection.
+    Hello {
+        version: ProtocolVersion,
+    },
+    Client {
+        name: String,
+        version: Option<String>,
+        /// The client answers `ping` and is dropped when it stops answering.
+        heartbeat: bool,
+    },
+    Auth(Credentials),
+    /// Open a channel of a connection speaking the protocol version 2.
+    OpenChannel {
+        channel: u16,
+    },
+    Stats {
+        queue_name: Option<String>,
+    },
+    Connections,
+    DeleteQueue {
+        queue_name: String,
+    },
+    PurgeQueue {
+        queue_name: String,
+    },
+    MoveMessages {
+        from: String,
+        to: String,
+        limit: Option<usize>,
+    },
+    Kick {
+        session_id: u64,
+    },
+    Peek {
+        queue_name: String,
+        offset: usize,
+        count: usize,
+    },
+    /// Reply to a delivered message that was processed, the `ok +l<len>` of
+    /// the base grammar when it comes with extended commands.
+    Ack {
+        len: usize,
+    },
+    /// Reply to a delivered message to receive it again.
+    Nack {
+        len: usize,
+    },
+    /// Reply to a delivered message to drop it.
+    Reject {
+        len: usize,
+    },
+    /// Hold the messages and replies of the connection until `TX COMMIT`.
+    TxBegin,
+    TxCommit,
+    TxRollback,
+}
+
+impl ExtCommand {
+    /// Commands that can be sent before `PUBLISHER` or `SUBSCRIBE`.
+    pub(crate) fn is_handshake(&self) -> bool {
+        matches!(
+            self,
+            Self::Pong | Self::Hello { .. } | Self::Client { .. } | Self::Auth(_)
+        )
+    }
+
+    /// Commands to inspect and manage the broker, they do not give a role to
+    /// the connection.
+    pub(crate) fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Self::Stats { .. }
+                | Self::Connections
+                | Self::DeleteQueue { .. }
+                | Self::PurgeQueue { .. }
+                | Self::MoveMessages { .. }
+                | Self::Kick { .. }
+                | Self::Peek { .. }
+        )
+    }
+
+    pub(crate) fn is_transaction(&self) -> bool {
+        matches!(self, Self::TxBegin | Self::TxCommit | Self::TxRollback)
+    }
+
+    /// Commands of a multiplexed connection, its channels take the roles.
+    pub(crate) fn is_multiplexing(&self) -> bool {
+        matches!(self, Self::OpenChannel { .. })
+    }
+}
+
+/// One statement of a message, the tokens before the payload and the payload
+/// itself for `message` statements.
+struct Statement<'a> {
+    tokens: Vec<&'a str>,
+    payload: Option<Payload<'a>>,
+}
+
+struct Payload<'a> {
+    len: usize,
+    frame_len: usize,
+    bytes: &'a [u8],
+}
+
+/// Commands of the base grammar.
+pub(crate) enum Command {
+    /// `SUBSCRIBE <queue> WITH GROUP <group>;`
+    Subscriber {
+        queue_name: Bytes,
+        group_name: Bytes,
+    },
+    /// `PUBLISHER <queue>;`
+    Publisher { queue_name: Bytes },
+    /// `message +l<len> #<message>`
+    Message { message: Bytes, len: usize },
+    /// `ok +l<len>;`
+    Ok { len: usize },
+    /// `error #<message>;`
+    Error { message: Bytes },
+}
+
+/// Parse the commands of the base grammar, the fallback of `parse`.
+pub(crate) fn parse_base(message: Bytes) -> OzResult<Vec<Command>> {
+    let statements = tokenize(&message)
+        .ok_or_else(|| OzesError::InvalidCommand(String::from("command is not utf-8")))?;
+    if statements.is_empty() {
+        return Err(OzesError::InvalidCommand(String::new()));
+    }
+    statements
+        .iter()
+        .map(|statement| {
+            let tokens = &statement.tokens[..];
+            let command = match (tokens, &statement.payload) {
+                ([keyword], Some(payload)) if keyword.eq_ignore_ascii_case("message") => {
+                    Command::Message {
+                        message: message.slice_ref(payload.bytes),
+                        len: payload.len,
+                    }
+                }
+                ([subscribe, queue_name, with, group, group_name], None)
+                    if subscribe.eq_ignore_ascii_case("subscribe")
+                        && with.eq_ignore_ascii_case("with")
+                        && group.eq_ignore_ascii_case("group") =>
+                {
+                    Command::Subscriber {
+                        queue_name: Bytes::from(queue_name.to_string()),
+                        group_name: Bytes::from(group_name.to_string()),
+                    }
+                }
+                ([publisher, queue_name], None) if publisher.eq_ignore_ascii_case("publisher") => {
+                    Command::Publisher {
+                        queue_name: Bytes::from(queue_name.to_string()),
+                    }
+                }
+                ([ok, len], None) if ok.eq_ignore_ascii_case("ok") => Command::Ok {
+                    len: parse_len(len).ok_or_else(|| invalid_command(tokens))?,
+                },
+                ([error, text @ ..], None)
+                    if error.eq_ignore_ascii_case("error")
+                        && text.first().is_some_and(|text| text.starts_with('#')) =>
+                {
+                    Command::Error {
+                        message: Bytes::from(text.join(" ")[1..].to_string()),
+                    }
+                }
+                _ => return Err(invalid_command(tokens)),
+            };
+            Ok(command)
+        })
+        .collect()
+}
+
+/// Parse commands that are not part of the base grammar.
+///
+/// Return `None` when the message has no extended command, so the caller
+/// can fallback to `parse_base`.
+pub(crate) fn parse(message: &[u8]) -> Option<OzResult<Vec<ExtCommand>>> {
+    let statements = tokenize(message)?;
+    if !statements.iter().any(is_extended) {
+        return None;
+    }
+    Some(statements.iter().map(parse_statement).collect())
+}
+
+fn tokenize(message: &[u8]) -> Option<Vec<Statement<'_>>> {
+    let mut statements = vec![];
+    let mut pos = 0;
+    loop {
+        while pos < message.len() && (message[pos].is_ascii_whitespace() || message[pos] == b';') {
+            pos += 1;
+        }
+        if pos >= message.len() {
+            break;
+        }
+        let start = pos;
+        let mut statement = Statement {
+            tokens: vec![],
+            payload: None,
+        };
+        loop {
+            while pos < message.len() && message[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if pos >= message.len() || message[pos] == b';' {
+                break;
+            }
+            let token_start = pos;
+            while pos < message.len() && !message[pos].is_ascii_whitespace() && message[pos] != b';'
+            {
+                pos += 1;
+            }
+            let token = std::str::from_utf8(&message[token_start..pos]).ok()?;
+            if let Some(len) = token.strip_prefix("+l") {
+                if message[pos..].starts_with(b" #") {
+                    let len = len.parse::<usize>().ok()?;
+                    let payload_start = pos + 2;
+                    // the len of `message +l<len> #` does not count its own
+                    // digits, the one of extended messages does
+                    let frame_len = match &statement.tokens[..] {
+                        [keyword] if keyword.eq_ignore_ascii_case("message") => message_len(len),
+                        _ => len,
+                    };
+                    let payload_end = (start + frame_len).clamp(payload_start, message.len());
+                    statement.payload = Some(Payload {
+                        len,
+                        frame_len: payload_end - start,
+                        bytes: &message[payload_start..payload_end],
+                    });
+                    pos = payload_end;
+                    break;
+                }
+            }
+            statement.tokens.push(token);
+        }
+        statements.push(statement);
+    }
+    Some(statements)
+}
+
+fn is_extended(statement: &Statement) -> bool {
+    match &statement.tokens[..] {
+        [keyword, ..] if keyword.eq_ignore_ascii_case("subscribe") => statement.tokens.len() > 5,
+        [keyword, ..] if keyword.eq_ignore_ascii_case("message") => statement.tokens.len() > 1,
+        [keyword, ..] => [
+            "create",
+            "pong",
+            "hello",
+            "client",
+            "auth",
+            "open",
+            "stats",
+            "connections",
+            "delete",
+            "purge",
+            "move",
+            "kick",
+            "peek",
+            "nack",
+            "reject",
+            "tx",
+        ]
+        .iter()
+        .any(|extended| keyword.eq_ignore_ascii_case(extended)),
+        [] => false,
+    }
+}
+
+fn parse_statement(statement: &Statement) -> OzResult<ExtCommand> {
+    let tokens = &statement.tokens[..];
+    match tokens {
+        [keyword, queue_name, with, group, group_name, options_tokens @ ..]
+            if keyword.eq_ignore_ascii_case("subscribe")
+                && with.eq_ignore_ascii_case("with")
+                && group.eq_ignore_ascii_case("group") =>
+        {
+            let mut options = SubscribeOptions::default();
+            let mut options_tokens = options_tokens.iter();
+            while let Some(option) = options_tokens.next() {
+                if option.eq_ignore_ascii_case("exclusive") {
+                    options.mode = GroupMode::Exclusive;
+                } else if option.eq_ignore_ascii_case("shared") {
+                    options.mode = GroupMode::Shared;
+                } else if option.eq_ignore_ascii_case("balance") {
+                    let strategy = options_tokens
+                        .next()
+                        .ok_or_else(|| invalid_command(tokens))?;
+                    options.strategy = strategy.parse::<Strategy>()?;
+                } else if option.eq_ignore_ascii_case("weight") {
+                    options.weight = options_tokens
+                        .next()
+                        .and_then(|weight| weight.parse::<u32>().ok())
+                        .filter(|weight| *weight > 0)
+                        .ok_or_else(|| invalid_command(tokens))?;
+                } else if option.eq_ignore_ascii_case("headers") {
+                    options.headers = true;
+                } else if option.eq_ignore_ascii_case("accept") {
+                    options.accept_encoding =
+                        match (options_tokens.next(), options_tokens.next()) {
+                            (Some(encoding), Some(encodings))
+                                if encoding.eq_ignore_ascii_case("encoding") =>
+                            {
+                                parse_encodings(encodings)
+                            }
+                            _ => None,
+                        }
+                        .ok_or_else(|| invalid_command(tokens))?;
+                } else if option.eq_ignore_ascii_case("ack") {
+                    let millis = match (options_tokens.next(), options_tokens.next()) {
+                        (Some(timeout), Some(millis))
+                            if timeout.eq_ignore_ascii_case("timeout") =>
+                        {
+                            millis.parse::<u64>().ok().filter(|millis| *millis > 0)
+                        }
+                        _ => None,
+                    }
+                    .ok_or_else(|| invalid_command(tokens))?;
+                    options.ack_timeout = Duration::from_millis(millis).min(MAX_ACK_TIMEOUT);
+                } else if option.eq_ignore_ascii_case("partitions") {
+                    options.partitions = options_tokens
+                        .next()
+                        .and_then(|count| count.parse::<usize>().ok())
+                        .filter(|count| *count > 0)
+                        .map(Some)
+                        .ok_or_else(|| invalid_command(tokens))?;
+                } else {
+                    return Err(invalid_command(tokens));
+                }
+            }
+            Ok(ExtCommand::Subscribe {
+                queue_name: queue_name.to_string(),
+                group_name: group_name.to_string(),
+                options,
+            })
+        }
+        [create, queue, queue_name, options @ ..]
+            if create.eq_ignore_ascii_case("create") && queue.eq_ignore_ascii_case("queue") =>
+        {
+            let partitions = match options {
+                [] => None,
+                [partitions, count] if partitions.eq_ignore_ascii_case("partitions") => {
+                    match count.parse::<usize>() {
+                        Ok(count) if count > 0 => Some(count),
+                        _ => return Err(invalid_command(tokens)),
+                    }
+                }
+                _ => return Err(invalid_command(tokens)),
+            };
+            Ok(ExtCommand::CreateQueue {
+                queue_name: queue_name.to_string(),
+                partitions,
+            })
+        }
+        [keyword, options @ ..] if keyword.eq_ignore_ascii_case("message") => {
+            let payload = statement
+                .payload
+                .as_ref()
+                .ok_or_else(|| invalid_command(tokens))?;
+            if payload.len != payload.frame_len {
+                return Err(OzesError::InvalidLen(payload.len));
+            }
+            let mut key = None;
+            let mut headers = vec![];
+            let mut queue_name = None;
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                if option.eq_ignore_ascii_case("to") && queue_name.is_none() {
+                    let value = options.next().ok_or_else(|| invalid_command(tokens))?;
+                    queue_name = Some(value.to_string());
+                } else if option.eq_ignore_ascii_case("key") && key.is_none() {
+                    let value = options.next().ok_or_else(|| invalid_command(tokens))?;
+                    key = Some(Bytes::copy_from_slice(value.as_bytes()));
+                } else if option.eq_ignore_ascii_case("header") {
+                    match (options.next(), options.next()) {
+                        (Some(name), Some(value)) if is_header(name, value) => {
+                            headers.push((name.to_ascii_lowercase(), value.to_string()))
+                        }
+                        _ => return Err(invalid_command(tokens)),
+                    }
+                } else {
+                    return Err(invalid_command(tokens));
+                }
+            }
+            Ok(ExtCommand::Message {
+                message: Bytes::copy_from_slice(payload.bytes),
+                key,
+                headers,
+                queue_name,
+            })
+        }
+        [pong] if pong.eq_ignore_ascii_case("pong") => Ok(ExtCommand::Pong),
+        [hello, version_keyword, version]
+            if hello.eq_ignore_ascii_case("hello")
+                && version_keyword.eq_ignore_ascii_case("version") =>
+        {
+            Ok(ExtCommand::Hello {
+                version: version.parse()?,
+            })
+        }
+        [open, channel_keyword, channel]
+            if open.eq_ignore_ascii_case("open")
+                && channel_keyword.eq_ignore_ascii_case("channel") =>
+        {
+            let channel = channel
+                .parse::<u16>()
+                .ok()
+                .filter(|channel| *channel > 0)
+                .ok_or_else(|| invalid_command(tokens))?;
+            Ok(ExtCommand::OpenChannel { channel })
+        }
+        [client, name_keyword, name, options @ ..]
+            if client.eq_ignore_ascii_case("client")
+                && name_keyword.eq_ignore_ascii_case("name") =>
+        {
+            let (options, heartbeat) = match options {
+                [options @ .., heartbeat] if heartbeat.eq_ignore_ascii_case("heartbeat") => {
+                    (options, true)
+                }
+                _ => (options, false),
+            };
+            let version = match options {
+                [] => None,
+                [version, value] if version.eq_ignore_ascii_case("version") => {
+                    Some(value.to_string())
+                }
+                _ => return Err(invalid_command(tokens)),
+            };
+            Ok(ExtCommand::Client {
+                name: name.to_string(),
+                version,
+                heartbeat,
+            })
+        }
+        [auth, user, username, password_keyword, password]
+            if auth.eq_ignore_ascii_case("auth")
+                && user.eq_ignore_ascii_case("user")
+                && password_keyword.eq_ignore_ascii_case("password") =>
+        {
+            Ok(ExtCommand::Auth(Credentials::Password {
+                username: username.to_string(),
+                password: password.to_string(),
+            }))
+        }
+        [auth, token_keyword, token]
+            if auth.eq_ignore_ascii_case("auth") && token_keyword.eq_ignore_ascii_case("token") =>
+        {
+            Ok(ExtCommand::Auth(Credentials::Token(token.to_string())))
+        }
+        [stats] if stats.eq_ignore_ascii_case("stats") => {
+            Ok(ExtCommand::Stats { queue_name: None })
+        }
+        [stats, queue, queue_name]
+            if stats.eq_ignore_ascii_case("stats") && queue.eq_ignore_ascii_case("queue") =>
+        {
+            Ok(ExtCommand::Stats {
+                queue_name: Some(queue_name.to_string()),
+            })
+        }
+        [connections] if connections.eq_ignore_ascii_case("connections") => {
+            Ok(ExtCommand::Connections)
+        }
+        [delete, queue, queue_name]
+            if delete.eq_ignore_ascii_case("delete") && queue.eq_ignore_ascii_case("queue") =>
+        {
+            Ok(ExtCommand::DeleteQueue {
+                queue_name: queue_name.to_string(),
+            })
+        }
+        [purge, queue, queue_name]
+            if purge.eq_ignore_ascii_case("purge") && queue.eq_ignore_ascii_case("queue") =>
+        {
+            Ok(ExtCommand::PurgeQueue {
+                queue_name: queue_name.to_string(),
+            })
+        }
+        [move_keyword, queue, from, to_keyword, to, options @ ..]
+            if move_keyword.eq_ignore_ascii_case("move")
+                && queue.eq_ignore_ascii_case("queue")
+                && to_keyword.eq_ignore_ascii_case("to") =>
+        {
+            let limit = match options {
+                [] => None,
+                [limit, count] if limit.eq_ignore_ascii_case("limit") => Some(
+                    count
+                        .parse::<usize>()
+                        .map_err(|_| invalid_command(tokens))?,
+                ),
+                _ => return Err(invalid_command(tokens)),
+            };
+            Ok(ExtCommand::MoveMessages {
+                from: from.to_string(),
+                to: to.to_string(),
+                limit,
+            })
+        }
+        [kick, session_id] if kick.eq_ignore_ascii_case("kick") => Ok(ExtCommand::Kick {
+            session_id: session_id.parse().map_err(|_| invalid_command(tokens))?,
+        }),
+        [peek, queue_name, options @ ..]
+            if peek.eq_ignore_ascii_case("peek") && options.len() <= 2 =>
+        {
+            let mut numbers = options.iter().map(|number| number.parse::<usize>());
+            let offset = numbers
+                .next()
+                .unwrap_or(Ok(0))
+                .map_err(|_| invalid_command(tokens))?;
+            let count = numbers
+                .next()
+                .unwrap_or(Ok(DEFAULT_PEEK_COUNT))
+                .map_err(|_| invalid_command(tokens))?;
+            Ok(ExtCommand::Peek {
+                queue_name: queue_name.to_string(),
+                offset,
+                count: count.min(MAX_PEEK_COUNT),
+            })
+        }
+        [ok, len] if ok.eq_ignore_ascii_case("ok") => Ok(ExtCommand::Ack {
+            len: parse_len(len).ok_or_else(|| invalid_command(tokens))?,
+        }),
+        [nack, len] if nack.eq_ignore_ascii_case("nack") => Ok(ExtCommand::Nack {
+            len: parse_len(len).ok_or_else(|| invalid_command(tokens))?,
+        }),
+        [reject, len] if reject.eq_ignore_ascii_case("reject") => Ok(ExtCommand::Reject {
+            len: parse_len(len).ok_or_else(|| invalid_command(tokens))?,
+        }),
+        [tx, action] if tx.eq_ignore_ascii_case("tx") => {
+            if action.eq_ignore_ascii_case("begin") {
+                Ok(ExtCommand::TxBegin)
+            } else if action.eq_ignore_ascii_case("commit") {
+                Ok(ExtCommand::TxCommit)
+            } else if action.eq_ignore_ascii_case("rollback") {
+                Ok(ExtCommand::TxRollback)
+            } else {
+                Err(invalid_command(tokens))
+            }
+        }
+        _ => Err(invalid_command(tokens)),
+    }
+}
+
+/// Headers are sent to consumers as `<name>=<value>` before the ` #` of the
+/// payload, so the name cannot have `=` and neither can start with `#`.
+fn is_header(name: &str, value: &str) -> bool {
+    !name.contains('=')
+        && !name.starts_with('#')
+        && !value.starts_with('#')
+        // the broker has to know the compression to decompress the payload
+        && (!name.eq_ignore_ascii_case(CONTENT_ENCODING) || value.parse::<Compression>().is_ok())
+}
+
+/// `identity` or a comma separated list of compressions, `zstd,gzip`.
+fn parse_encodings(encodings: &str) -> Option<Encodings> {
+    if encodings.eq_ignore_ascii_case("identity") {
+        return Some(Encodings::default());
+    }
+    encodings
+        .split(',')
+        .try_fold(Encodings::default(), |encodings, compression| {
+            Some(encodings.with(compression.parse().ok()?))
+        })
+}
+
+/// Len of the message replied, `+l<len>`.
+fn parse_len(token: &str) -> Option<usize> {
+    token.strip_prefix("+l")?.parse().ok()
+}
+
+fn invalid_command(tokens: &[&str]) -> OzesError {
+    OzesError::InvalidCommand(tokens.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_statement;
+
+    fn parse_one(message: &[u8]) -> OzResult<ExtCommand> {
+        let mut commands = parse(message).expect("extended command")?;
+        assert_eq!(commands.len(), 1);
+        Ok(commands.remove(0))
+    }
+
+    #[test]
+    fn base_grammar_falls_back() {
+        assert!(parse(b"SUBSCRIBE orders WITH GROUP billing;").is_none());
+        assert!(parse(b"PUBLISHER orders;").is_none());
+        assert!(parse(&message_statement("", b"hello")).is_none());
+        assert!(parse(b"ok +l17;").is_none());
+    }
+
+    #[test]
+    fn base_grammar() {
+        let payload = [b'a'; 95];
+        let input = [
+            &b"SUBSCRIBE orders WITH GROUP billing; PUBLISHER orders;"[..],
+            &message_statement("", &payload),
+            b"ok +l17;",
+        ]
+        .concat();
+        let commands = parse_base(Bytes::from(input)).unwrap();
+        assert!(matches!(
+            &commands[..],
+            [
+                Command::Subscriber { queue_name, group_name },
+                Command::Publisher { .. },
+                Command::Message { message, len: 109 },
+                Command::Ok { len: 17 },
+            ] if &queue_name[..] == b"orders" && &group_name[..] == b"billing" && message[..] == payload
+        ));
+        assert!(parse_base(Bytes::from_static(b"PUBLISHER;")).is_err());
+        assert!(parse_base(Bytes::from_static(b"ok +lx;")).is_err());
+        assert!(parse_base(Bytes::from_static(b" ;")).is_err());
+    }
+
+    #[test]
+    fn subscribe_with_options() {
+        let command = parse_one(
+            b"SUBSCRIBE orders WITH GROUP billing EXCLUSIVE BALANCE weighted WEIGHT 3 HEADERS \
+              ACCEPT ENCODING zstd,gzip ACK TIMEOUT 1000 PARTITIONS 4;",
+        )
+        .unwrap();
+        let ExtCommand::Subscribe {
+            queue_name,
+            group_name,
+            options,
+        } = command
+        else {
+            panic!("expected subscribe");
+        };
+        assert_eq!(queue_name, "orders");
+        assert_eq!(group_name, "billing");
+        assert_eq!(options.mode, GroupMode::Exclusive);
+        assert_eq!(options.strategy, Strategy::Weighted);
+        assert_eq!(options.weight, 3);
+        assert!(options.headers);
+        assert!(options.accept_encoding.contains(Compression::Zstd));
+        assert!(!options.accept_encoding.contains(Compression::Lz4));
+        assert_eq!(options.ack_timeout, Duration::from_secs(1));
+        assert_eq!(options.partitions, Some(4));
+    }
+
+    #[test]
+    fn subscribe_with_invalid_options() {
+        assert!(parse_one(b"SUBSCRIBE orders WITH GROUP billing WEIGHT 0;").is_err());
+        assert!(parse_one(b"SUBSCRIBE orders WITH GROUP billing BALANCE random;").is_err());
+        assert!(parse_one(b"SUBSCRIBE orders WITH GROUP billing ACCEPT ENCODING br;").is_err());
+        assert!(parse_one(b"SUBSCRIBE orders WITH GROUP billing UNKNOWN;").is_err());
+    }
+
+    #[test]
+    fn message_with_options() {
+        let statement = message_statement("TO invoices KEY 42 HEADER Trace abc", b"hello; world");
+        let command = parse_one(&statement).unwrap();
+        let ExtCommand::Message {
+            message,
+            key,
+            headers,
+            queue_name,
+        } = command
+        else {
+            panic!("expected message");
+        };
+        assert_eq!(&message[..], b"hello; world");
+        assert_eq!(key.as_deref(), Some(&b"42"[..]));
+        assert_eq!(headers, vec![(String::from("trace"), String::from("abc"))]);
+        assert_eq!(queue_name.as_deref(), Some("invoices"));
+    }
+
+    #[test]
+    fn message_with_wrong_len() {
+        assert!(matches!(
+            parse_one(b"MESSAGE KEY 42 +l99 #hello"),
+            Err(OzesError::InvalidLen(99))
+        ));
+    }
+
+    #[test]
+    fn message_with_unknown_encoding() {
+        let statement = message_statement("HEADER content-encoding br", b"hello");
+        assert!(parse_one(&statement).is_err());
+    }
+
+    #[test]
+    fn client_and_answers() {
+        assert!(matches!(
+            parse_one(b"CLIENT NAME worker VERSION 1.2 HEARTBEAT;"),
+            Ok(ExtCommand::Client { name, version: Some(version), heartbeat: true })
+                if name == "worker" && version == "1.2"
+        ));
+        assert!(matches!(
+            parse_one(b"CLIENT NAME worker;"),
+            Ok(ExtCommand::Client {
+                version: None,
+                heartbeat: false,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_one(b"NACK +l17;"),
+            Ok(ExtCommand::Nack { len: 17 })
+        ));
+        assert!(matches!(
+            parse_one(b"REJECT +l17;"),
+            Ok(ExtCommand::Reject { len: 17 })
+        ));
+        assert!(parse_one(b"NACK 17;").is_err());
+    }
+
+    #[test]
+    fn ack_with_extended_commands() {
+        let commands = parse(b"ok +l17; TX COMMIT;").unwrap().unwrap();
+        assert!(matches!(
+            &commands[..],
+            [ExtCommand::Ack { len: 17 }, ExtCommand::TxCommit]
+        ));
+    }
+
+    #[test]
+    fn admin_commands() {
+        assert!(matches!(
+            parse_one(b"PEEK orders 5 1000;"),
+            Ok(ExtCommand::Peek {
+                offset: 5,
+                count: MAX_PEEK_COUNT,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_one(b"MOVE QUEUE orders TO retry LIMIT 10;"),
+            Ok(ExtCommand::MoveMessages {
+                limit: Some(10),
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_one(b"OPEN CHANNEL 0;"),
+            Err(OzesError::InvalidCommand(_))
+        ));
+        assert!(parse_one(b"TX ABORT;").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+use super::{auth::AuthConfig, http::HttpConfig, tls::TlsConfig, unix::UnixConfig};
+
+pub const DEFAULT_PORT: u16 = 7656;
+pub const DEFAULT_TLS_PORT: u16 = 7657;
+pub const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o660;
+pub const DEFAULT_MAX_DELIVERIES: u32 = 10;
+pub const DEFAULT_MAX_CHANNELS: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub port: u16,
+    /// Listen on `port`, disable to accept only Unix socket or TLS clients.
+    pub tcp: bool,
+    /// Listen on a Unix domain socket too.
+    pub unix: Option<UnixConfig>,
+    pub heartbeat: HeartbeatConfig,
+    /// Times a message is sent to a group without being acked before the
+    /// group gives it up.
+    pub max_deliveries: u32,
+    /// Suffix of the queue where the messages given up by a group are
+    /// published, like `.dlq`, they are dropped when it is not set.
+    pub dead_letter_suffix: Option<String>,
+    /// Messages a queue keeps waiting before refusing the ones published, it
+    /// has no bound when not set.
+    pub max_queue_messages: Option<usize>,
+    /// Channels a multiplexed connection can have open at once.
+    pub max_channels: usize,
+    /// Listen with TLS on another port, side by side with the plain listener.
+    pub tls: Option<TlsConfig>,
+    /// Port of the WebSocket listener, for browser clients.
+    pub websocket_port: Option<u16>,
+    /// HTTP gateway to publish and fetch messages.
+    pub http: Option<HttpConfig>,
+    /// Port serving `/metrics` in the Prometheus format.
+    pub admin_port: Option<u16>,
+    /// Require `AUTH` before `PUBLISHER` and `SUBSCRIBE`.
+    pub auth: Option<AuthConfig>,
+    /// Access control list file, see `Acl` for the format.
+    pub acl_path: Option<PathBuf>,
+    /// Users that can run admin commands when there is no ACL, clients of the
+    /// Unix socket always can.
+    pub admin_users: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeartbeatConfig {
+    /// Time without hearing from a connection before sending it a `ping`.
+    pub interval: Duration,
+    /// Number of intervals without an answer before the connection is dropped.
+    pub miss_threshold: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            tcp: true,
+            unix: None,
+            heartbeat: HeartbeatConfig::default(),
+            max_deliveries: DEFAULT_MAX_DELIVERIES,
+            dead_letter_suffix: None,
+            max_queue_messages: None,
+            max_channels: DEFAULT_MAX_CHANNELS,
+            tls: None,
+            websocket_port: None,
+            http: None,
+            admin_port: None,
+            auth: None,
+            acl_path: None,
+            admin_users: vec![],
+        }
+    }
+}
+
+impl Config {
+    /// Read the config from `OZES_*` environment variables, using the default
+    /// value of each one that is not set.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let tls = match (env::var("OZES_TLS_CERT"), env::var("OZES_TLS_KEY")) {
+            (Ok(cert_path), Ok(key_path)) => Some(TlsConfig {
+                port: env_or("OZES_TLS_PORT", DEFAULT_TLS_PORT),
+                cert_path: PathBuf::from(cert_path),
+                key_path: PathBuf::from(key_path),
+                client_ca_path: env::var("OZES_TLS_CLIENT_CA").ok().map(PathBuf::from),
+                reload_interval: Duration::from_secs(env_or("OZES_TLS_RELOAD_SECS", 60)),
+            }),
+            _ => None,
+        };
+        let unix = env::var("OZES_UNIX_SOCKET").ok().map(|path| UnixConfig {
+            path: PathBuf::from(path),
+            mode: env::var("OZES_UNIX_SOCKET_MODE")
+                .ok()
+                .and_then(|mode| u32::from_str_radix(&mode, 8).ok())
+                .unwrap_or(DEFAULT_UNIX_SOCKET_MODE),
+        });
+        let credentials_path = env::var("OZES_AUTH_FILE").ok().map(PathBuf::from);
+        let tokens = env::var("OZES_AUTH_TOKENS").ok();
+        let auth = if credentials_path.is_some() || tokens.is_some() {
+            Some(AuthConfig {
+                credentials_path,
+                tokens: tokens
+                    .iter()
+                    .flat_map(|tokens| tokens.split(','))
+                    .filter_map(|token| token.split_once('='))
+                    .map(|(username, token)| (token.to_string(), username.to_string()))
+                    .collect(),
+            })
+        } else {
+            None
+        };
+        Self {
+            port: env_or("OZES_PORT", default.port),
+            tcp: env_or("OZES_TCP", default.tcp),
+            unix,
+            heartbeat: HeartbeatConfig {
+                interval: Duration::from_secs(env_or(
+                    "OZES_HEARTBEAT_SECS",
+                    default.heartbeat.interval.as_secs(),
+                )),
+                miss_threshold: env_or("OZES_HEARTBEAT_MISSES", default.heartbeat.miss_threshold),
+            },
+            max_deliveries: env_or("OZES_MAX_DELIVERIES", default.max_deliveries).max(1),
+            dead_letter_suffix: env::var("OZES_DEAD_LETTER_SUFFIX")
+                .ok()
+                .filter(|suffix| !suffix.is_empty()),
+            max_queue_messages: env::var("OZES_MAX_QUEUE_MESSAGES")
+                .ok()
+                .and_then(|max| max.parse().ok()),
+            max_channels: env_or("OZES_MAX_CHANNELS", default.max_channels),
+            tls,
+            websocket_port: env::var("OZES_WS_PORT")
+                .ok()
+                .and_then(|port| port.parse().ok()),
+            http: env::var("OZES_HTTP_PORT")
+                .ok()
+                .and_then(|port| port.parse().ok())
+                .map(|port| HttpConfig {
+                    port,
+                    ack_timeout: Duration::from_secs(env_or("OZES_HTTP_ACK_SECS", 30)),
+                }),
+            admin_port: env::var("OZES_ADMIN_PORT")
+                .ok()
+                .and_then(|port| port.parse().ok()),
+            auth,
+            acl_path: env::var("OZES_ACL_FILE").ok().map(PathBuf::from),
+            admin_users: env::var("OZES_ADMIN_USERS")
+                .iter()
+                .flat_map(|users| users.split(','))
+                .filter(|user| !user.is_empty())
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            miss_threshold: 3,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    pub(crate) fn timeout(&self) -> Duration {
+        self.interval * self.miss_threshold
+    }
+}
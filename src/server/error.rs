@@ -1,5 +1,5 @@
 use std::io::{Error as IOError, ErrorKind};
-use std::{error::Error, fmt::Display};
+use std::{error::Error, fmt::Display, str::FromStr};
 
 pub type OzResult<T> = std::result::Result<T, OzesError>;
 
@@ -13,9 +13,129 @@ pub enum OzesError {
     AddrInUse,
     PermissionDenied,
     InvalidLen(usize),
+    InvalidCommand(String),
+    QueueAlreadyExists(String),
+    QueueNotFound(String),
+    QueueFull(String),
+    Tls(String),
+    Unauthorized,
+    AccessDenied(String),
+}
+
+/// Stable code of the errors sent to clients, `error #<CODE> <message>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// The command cannot be parsed.
+    ParseError,
+    /// The len of a message or reply is not the expected one.
+    InvalidLen,
+    TooLong,
+    UnknownQueue,
+    QueueExists,
+    /// The queue cannot take more messages.
+    QueueFull,
+    /// The command is only for publishers.
+    NotPublisher,
+    /// The command is only for consumers.
+    NotConsumer,
+    /// The command is not allowed in the current state of the connection,
+    /// like `SUBSCRIBE` from a publisher.
+    InvalidState,
+    Unauthorized,
+    AccessDenied,
+    Timeout,
+    /// The compression of the message is not enabled in the broker.
+    UnsupportedEncoding,
+    Internal,
+}
+
+impl ErrorCode {
+    const ALL: [ErrorCode; 14] = [
+        Self::ParseError,
+        Self::InvalidLen,
+        Self::TooLong,
+        Self::UnknownQueue,
+        Self::QueueExists,
+        Self::QueueFull,
+        Self::NotPublisher,
+        Self::NotConsumer,
+        Self::InvalidState,
+        Self::Unauthorized,
+        Self::AccessDenied,
+        Self::Timeout,
+        Self::UnsupportedEncoding,
+        Self::Internal,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ParseError => "PARSE_ERROR",
+            Self::InvalidLen => "INVALID_LEN",
+            Self::TooLong => "TOO_LONG",
+            Self::UnknownQueue => "UNKNOWN_QUEUE",
+            Self::QueueExists => "QUEUE_EXISTS",
+            Self::QueueFull => "QUEUE_FULL",
+            Self::NotPublisher => "NOT_PUBLISHER",
+            Self::NotConsumer => "NOT_CONSUMER",
+            Self::InvalidState => "INVALID_STATE",
+            Self::Unauthorized => "UNAUTHORIZED",
+            Self::AccessDenied => "ACCESS_DENIED",
+            Self::Timeout => "TIMEOUT",
+            Self::UnsupportedEncoding => "UNSUPPORTED_ENCODING",
+            Self::Internal => "INTERNAL",
+        }
+    }
+
+    /// Code and message of the text after `error #`, replies of brokers
+    /// without codes have only the message.
+    pub fn from_reply(reply: &str) -> (Option<ErrorCode>, &str) {
+        let reply = reply.trim();
+        let (code, message) = reply.split_once(' ').unwrap_or((reply, ""));
+        match code.parse() {
+            Ok(code) => (Some(code), message.trim()),
+            Err(_) => (None, reply),
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ErrorCode {
+    type Err = String;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|known| known.as_str() == code)
+            .ok_or_else(|| format!("unknown error code {code}"))
+    }
 }
 
 impl OzesError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::TimeOut => ErrorCode::Timeout,
+            Self::ToLongMessage => ErrorCode::TooLong,
+            Self::InvalidLen(_) => ErrorCode::InvalidLen,
+            Self::InvalidCommand(_) => ErrorCode::ParseError,
+            Self::QueueAlreadyExists(_) => ErrorCode::QueueExists,
+            Self::QueueNotFound(_) => ErrorCode::UnknownQueue,
+            Self::QueueFull(_) => ErrorCode::QueueFull,
+            Self::Unauthorized => ErrorCode::Unauthorized,
+            Self::AccessDenied(_) => ErrorCode::AccessDenied,
+            Self::WithouConnection
+            | Self::ErrorResponse
+            | Self::UnknownError(_)
+            | Self::AddrInUse
+            | Self::PermissionDenied
+            | Self::Tls(_) => ErrorCode::Internal,
+        }
+    }
+
     pub fn is_error(&self, error: OzesError) -> bool {
         std::mem::discriminant(self) == std::mem::discriminant(&error)
     }
@@ -32,6 +152,13 @@ impl Display for OzesError {
             Self::PermissionDenied => "permission denied".to_owned(),
             Self::UnknownError(error) => format!("unknown error: {}", error),
             Self::InvalidLen(len) => format!("invalid len {}", len),
+            Self::InvalidCommand(command) => format!("invalid command '{}'", command),
+            Self::QueueAlreadyExists(queue) => format!("queue {} already exists", queue),
+            Self::QueueNotFound(queue) => format!("queue {} not found", queue),
+            Self::QueueFull(queue) => format!("queue {} is full", queue),
+            Self::Tls(error) => format!("tls error: {}", error),
+            Self::Unauthorized => "unauthorized".to_owned(),
+            Self::AccessDenied(reason) => format!("access denied: {}", reason),
         };
         write!(f, "{}", error)
     }
@@ -50,3 +177,38 @@ impl From<IOError> for OzesError {
         }
     }
 }
+
+impl From<tokio_rustls::rustls::Error> for OzesError {
+    fn from(e: tokio_rustls::rustls::Error) -> Self {
+        Self::Tls(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_from_replies() {
+        assert_eq!(
+            ErrorCode::from_reply("UNKNOWN_QUEUE queue orders not found\n"),
+            (Some(ErrorCode::UnknownQueue), "queue orders not found")
+        );
+        assert_eq!(
+            ErrorCode::from_reply("TIMEOUT"),
+            (Some(ErrorCode::Timeout), "")
+        );
+        assert_eq!(
+            ErrorCode::from_reply("error without code"),
+            (None, "error without code")
+        );
+    }
+
+    #[test]
+    fn codes_round_trip() {
+        for code in ErrorCode::ALL {
+            assert_eq!(code.as_str().parse::<ErrorCode>(), Ok(code));
+        }
+        assert!("parse_error".parse::<ErrorCode>().is_err());
+    }
+}
@@ -1,27 +1,249 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use bytes::Bytes;
-use ozes_parser::parser::Command;
+use tokio::{sync::RwLock, time::Instant};
 
-use crate::connection::{Connection, OzesConnection};
+use crate::{
+    compression::Encodings,
+    connection::{Connection, OzesConnection},
+    delivery_header,
+};
 
 use super::{
-    error::{OzResult, OzesError},
-    message_queue::OzesConnections,
+    balancer::{LoadBalancer, Member, Strategy},
+    command::{self, Command, ExtCommand},
+    error::{ErrorCode, OzResult, OzesError},
+    message::Message,
+    metrics::{Histogram, METRICS},
+    stats::{ConsumerStats, GroupStats},
 };
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GroupMode {
+    /// Messages are spread across all connections by the group strategy.
+    #[default]
+    Shared,
+    /// Only the oldest connection receives messages, the others are hot
+    /// standbys that take over in order of arrival when it is popped.
+    Exclusive,
+}
+
+impl Display for GroupMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mode = match self {
+            Self::Shared => "shared",
+            Self::Exclusive => "exclusive",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Options sent by a consumer on `SUBSCRIBE`, the mode and strategy are fixed
+/// by the first consumer of the group, the weight and ack timeout are by
+/// consumer.
+#[derive(Clone, Copy, Debug)]
+pub struct SubscribeOptions {
+    pub mode: GroupMode,
+    pub strategy: Strategy,
+    pub weight: u32,
+    /// Time to wait the reply to a message, the consumer receives no other
+    /// message meanwhile.
+    pub ack_timeout: Duration,
+    /// Receive the headers of the messages, `+l<len> <name>=<value> #<payload>`.
+    pub headers: bool,
+    /// Compressions the consumer decompresses by itself, payloads with other
+    /// compressions are decompressed by the broker.
+    pub accept_encoding: Encodings,
+    /// Partitions the queue has to have, it is created with them when it does
+    /// not exist yet.
+    pub partitions: Option<usize>,
+}
+
+impl Default for SubscribeOptions {
+    fn default() -> Self {
+        Self {
+            mode: GroupMode::default(),
+            strategy: Strategy::default(),
+            weight: 1,
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+            headers: false,
+            accept_encoding: Encodings::default(),
+            partitions: None,
+        }
+    }
+}
+
+/// Answer of a consumer to a delivered message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Reply {
+    Ack,
+    /// Send the message again now.
+    Nack,
+    /// Drop the message without delivering it again.
+    Reject,
+}
+
+impl Reply {
+    /// Statement of the answer to the delivery of `len`.
+    pub(crate) fn statement(self, len: usize) -> Bytes {
+        let answer = match self {
+            Self::Ack => "ok",
+            Self::Nack => "NACK",
+            Self::Reject => "REJECT",
+        };
+        Bytes::from(format!("{answer} +l{len};"))
+    }
+}
+
+const NACK_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_NACK_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Deliveries of a message to a group.
+#[derive(Debug, Default)]
+pub(crate) struct Attempts {
+    sent: u32,
+    /// Members that did not answer the message in time or answered it wrong,
+    /// they are skipped while another member can take it.
+    failed: Vec<u64>,
+}
+
+/// Outcome of a delivery of a message to a group.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Delivery {
+    /// Acked or rejected.
+    Done,
+    /// The group has no member, the message waits until one joins.
+    Waiting,
+    /// Nacked or not answered, it is sent again after the delay.
+    Retry(Duration),
+    /// Sent `max_deliveries` times without ack, or its payload cannot be
+    /// decompressed for the member.
+    GivenUp,
+}
+
+/// Delay before sending again a message nacked after `sent` deliveries, it
+/// doubles on each delivery.
+fn nack_backoff(sent: u32) -> Duration {
+    NACK_BACKOFF
+        .saturating_mul(1 << sent.saturating_sub(1).min(16))
+        .min(MAX_NACK_BACKOFF)
+}
+
+/// Member owning each partition of the queue. A partition keeps its owner
+/// while the owner lives, only the partitions of a member that leaves and the
+/// ones handed to a member that joins change of owner, so the messages of a
+/// key are not processed by two consumers at once on every join or leave.
+#[derive(Default)]
+struct Partitions {
+    owners: Vec<Option<u64>>,
+}
+
+impl Partitions {
+    fn new(count: usize) -> Self {
+        Self {
+            owners: vec![None; count],
+        }
+    }
+
+    fn owner(&self, partition: usize) -> Option<u64> {
+        self.owners.get(partition).copied().flatten()
+    }
+
+    fn count(&self, member: u64) -> usize {
+        self.owners
+            .iter()
+            .filter(|owner| **owner == Some(member))
+            .count()
+    }
+
+    /// Give the partitions without owner to the member that joins, then take
+    /// from the members with most partitions until it has its share.
+    fn join(&mut self, member: u64, members: usize) {
+        for owner in self.owners.iter_mut().filter(|owner| owner.is_none()) {
+            *owner = Some(member);
+        }
+        let share = self.owners.len() / members.max(1);
+        while self.count(member) < share {
+            let busiest = self
+                .owners
+                .iter()
+                .flatten()
+                .copied()
+                .max_by_key(|owner| self.count(*owner));
+            match busiest {
+                Some(busiest) if busiest != member && self.count(busiest) > share => {
+                    if let Some(owner) = self.owners.iter_mut().find(|o| **o == Some(busiest)) {
+                        *owner = Some(member);
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Give the partition to the member with fewest partitions that is not
+    /// skipped, it keeps its owner when every member is skipped.
+    fn reassign(&mut self, partition: usize, members: &[Arc<Member>], skip: &[u64]) {
+        let owner = members
+            .iter()
+            .map(|member| member.id())
+            .filter(|id| !skip.contains(id))
+            .min_by_key(|id| self.count(*id));
+        if let (Some(owner), Some(slot)) = (owner, self.owners.get_mut(partition)) {
+            *slot = Some(owner);
+        }
+    }
+
+    /// Give the partitions of the member that leaves to the members with
+    /// fewest partitions.
+    fn leave(&mut self, member: u64, members: &[Arc<Member>]) {
+        for partition in 0..self.owners.len() {
+            if self.owners[partition] != Some(member) {
+                continue;
+            }
+            self.owners[partition] = members
+                .iter()
+                .map(|member| member.id())
+                .min_by_key(|id| self.count(*id));
+        }
+    }
+}
+
 pub struct Group {
     name: String,
-    connections: OzesConnections,
-    actual_con: Mutex<usize>,
+    mode: GroupMode,
+    strategy: Strategy,
+    balancer: Box<dyn LoadBalancer>,
+    members: RwLock<Vec<Arc<Member>>>,
+    partitions: Mutex<Partitions>,
+    delivered: AtomicU64,
+    redelivered: AtomicU64,
+    rejected: AtomicU64,
+    ack_latency: Histogram,
 }
 
 impl Group {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, options: SubscribeOptions, partitions: Option<usize>) -> Self {
         Self {
             name,
-            connections: OzesConnections::default(),
-            actual_con: Mutex::new(0),
+            mode: options.mode,
+            strategy: options.strategy,
+            balancer: options.strategy.balancer(),
+            members: RwLock::default(),
+            partitions: Mutex::new(partitions.map(Partitions::new).unwrap_or_default()),
+            delivered: AtomicU64::default(),
+            redelivered: AtomicU64::default(),
+            rejected: AtomicU64::default(),
+            ack_latency: Histogram::default(),
         }
     }
 
@@ -29,125 +251,348 @@ impl Group {
         &self.name
     }
 
-    fn actual_con(&self) -> usize {
-        *self.actual_con.lock().unwrap()
+    pub fn mode(&self) -> GroupMode {
+        self.mode
     }
 
-    async fn pop_current_connection(&self) {
-        let actual_con = self.actual_con();
-        if let Some(connection) = self.connections.get(actual_con).await {
-            log::info!("pop connection {}", connection.socket_address());
-            self.connections.remove(actual_con).await;
-        }
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    pub async fn connections(&self) -> Vec<Arc<OzesConnection>> {
+        self.members
+            .read()
+            .await
+            .iter()
+            .map(|member| Arc::clone(member.connection()))
+            .collect()
+    }
+
+    pub async fn member_count(&self) -> usize {
+        self.members.read().await.len()
+    }
+
+    pub(crate) fn delivered(&self) -> u64 {
+        self.delivered.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn redelivered(&self) -> u64 {
+        self.redelivered.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn ack_latency(&self) -> &Histogram {
+        &self.ack_latency
     }
 
-    pub async fn push_connection(&self, connection: Arc<OzesConnection>) {
-        self.connections.push(connection).await;
+    pub(crate) async fn stats(&self) -> GroupStats {
+        let members = self.members.read().await;
+        GroupStats {
+            name: self.name.clone(),
+            mode: self.mode.to_string(),
+            strategy: self.strategy.to_string(),
+            delivered: self.delivered(),
+            redelivered: self.redelivered(),
+            rejected: self.rejected(),
+            members: members.len(),
+            consumers: members
+                .iter()
+                .map(|member| ConsumerStats {
+                    session_id: member.connection().session_id(),
+                    address: member.connection().peer_address().to_string(),
+                    name: member.connection().client().name,
+                    weight: member.weight(),
+                    outstanding: member.outstanding(),
+                })
+                .collect(),
+        }
     }
 
-    pub async fn send_message(&self, message: &Bytes) -> OzResult<()> {
-        loop {
-            if self.connections.is_empty().await {
-                break;
+    /// Member that receive the next message, the owner of the partition of
+    /// the message in partitioned queues. The members in `skip` are picked
+    /// only when no other member is left, a skipped owner gives its partition
+    /// to another member.
+    async fn pick_member(
+        &self,
+        partition: Option<usize>,
+        key: Option<&Bytes>,
+        skip: &[u64],
+    ) -> Option<Arc<Member>> {
+        let members = self.members.read().await;
+        let candidates: Vec<Arc<Member>> = members
+            .iter()
+            .filter(|member| !skip.contains(&member.id()))
+            .map(Arc::clone)
+            .collect();
+        let candidates = if candidates.is_empty() {
+            &members[..]
+        } else {
+            &candidates[..]
+        };
+        let id = match (self.mode, partition) {
+            (GroupMode::Exclusive, _) => candidates.first().map(|member| member.id()),
+            (GroupMode::Shared, Some(partition)) => {
+                let mut partitions = self.partitions.lock().unwrap();
+                if matches!(partitions.owner(partition), Some(owner) if skip.contains(&owner)) {
+                    partitions.reassign(partition, &members, skip);
+                }
+                partitions.owner(partition)
             }
-            if let Some(connection) = self.connections.get(self.actual_con()).await {
-                match connection.send_message(message.clone()).await {
-                    Ok(len) => match self.process_client_return(connection, len).await {
-                        Err(error) if error.is_error(OzesError::WithouConnection) => {
-                            self.pop_current_connection().await;
-                            continue;
-                        }
-                        Err(error) if error.is_error(OzesError::InvalidLen(len)) => {
-                            log::info!("invalid len: {} retry", len);
-                            self.next_connection();
-                            continue;
-                        }
-                        Err(error) if error.is_error(OzesError::UnknownError(String::new())) => {
-                            //TODO: catch unknown error and handle correctly
-                            log::info!("unknow error: {} retry", error);
-                            continue;
-                        }
-                        Err(error) if error.is_error(OzesError::TimeOut) => {
-                            self.next_connection();
-                            continue;
-                        }
-                        Err(error) => {
-                            //TODO: fix generic error catcher
-                            log::info!("error {:?} retry", error);
-                            continue;
-                        }
-                        Ok(_) => {
-                            self.next_connection();
-                            break;
-                        }
-                    },
-                    Err(e) => {
-                        log::error!(
-                            "error on send message {} to currently connection {e}",
-                            String::from_utf8_lossy(message)
-                        );
-                        self.pop_current_connection().await;
-                        continue;
-                    }
+            _ => self.balancer.pick(candidates, key.map(|key| &key[..])),
+        }?;
+        members
+            .iter()
+            .find(|member| member.id() == id)
+            .map(Arc::clone)
+    }
+
+    async fn pop_member(&self, member: &Member) {
+        let mut members = self.members.write().await;
+        if let Some(idx) = members.iter().position(|m| m.id() == member.id()) {
+            log::info!("pop connection {}", member.connection());
+            members.remove(idx);
+            self.balancer.on_leave(member);
+            self.partitions.lock().unwrap().leave(member.id(), &members);
+            if self.mode == GroupMode::Exclusive && idx == 0 {
+                if let Some(standby) = members.first() {
+                    log::info!(
+                        "standby {} takes over group {}",
+                        standby.connection(),
+                        self.name
+                    );
                 }
-            } else {
-                self.reset_connection();
             }
         }
-        Ok(())
     }
 
-    fn reset_connection(&self) {
-        *self.actual_con.lock().unwrap() = 0;
+    /// Move the active member of an exclusive group behind the standbys when
+    /// a delivery to it failed, so a stuck consumer that keeps its connection
+    /// does not hold the group. The next standby takes over and the member
+    /// becomes the last standby.
+    async fn fail_over(&self, member: &Member) {
+        if self.mode != GroupMode::Exclusive {
+            return;
+        }
+        let mut members = self.members.write().await;
+        if members.len() < 2 || members[0].id() != member.id() {
+            return;
+        }
+        let active = members.remove(0);
+        log::info!(
+            "active {} of group {} failed a delivery, standby {} takes over",
+            active.connection(),
+            self.name,
+            members[0].connection()
+        );
+        members.push(active);
+    }
+
+    /// Remove the connection from the group, a message waiting its reply is
+    /// redelivered to another member by `send_message`.
+    pub async fn remove_connection(&self, connection: &Arc<OzesConnection>) {
+        let member = self
+            .members
+            .read()
+            .await
+            .iter()
+            .find(|member| Arc::ptr_eq(member.connection(), connection))
+            .map(Arc::clone);
+        if let Some(member) = member {
+            self.pop_member(&member).await;
+        }
+    }
+
+    pub async fn push_connection(
+        &self,
+        connection: Arc<OzesConnection>,
+        options: SubscribeOptions,
+    ) {
+        let member = Member::new(connection, options);
+        self.balancer.on_join(&member);
+        let mut members = self.members.write().await;
+        self.partitions
+            .lock()
+            .unwrap()
+            .join(member.id(), members.len() + 1);
+        members.push(Arc::new(member));
+    }
+
+    /// Send the message to a member and wait its reply. A member that is gone
+    /// is popped and another one is tried at once, other failures end the
+    /// delivery so the queue sends the message again later without holding
+    /// the other queues.
+    pub(crate) async fn send_message(
+        &self,
+        message: &Message,
+        partition: Option<usize>,
+        attempts: &mut Attempts,
+        max_deliveries: u32,
+    ) -> Delivery {
+        while let Some(member) = self
+            .pick_member(partition, message.key(), &attempts.failed)
+            .await
+        {
+            if !member.connection().is_alive() {
+                self.pop_member(&member).await;
+                continue;
+            }
+            let frame = match message
+                .frame(member.headers(), member.accept_encoding())
+                .await
+            {
+                Ok(frame) => frame,
+                Err(error) => {
+                    log::error!("group {} gives up a message: {}", self.name, error);
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Delivery::GivenUp;
+                }
+            };
+            if attempts.sent > 0 {
+                self.redelivered.fetch_add(1, Ordering::Relaxed);
+            }
+            attempts.sent += 1;
+            member.start_delivery();
+            let result = self.deliver(&member, frame).await;
+            member.finish_delivery();
+            let retry = match result {
+                Err(error) if error.is_error(OzesError::WithouConnection) => {
+                    self.pop_member(&member).await;
+                    None
+                }
+                Err(error) => {
+                    log::info!("{} failed a delivery: {}", member.connection(), error);
+                    if !attempts.failed.contains(&member.id()) {
+                        attempts.failed.push(member.id());
+                    }
+                    self.fail_over(&member).await;
+                    Some(Duration::ZERO)
+                }
+                Ok(Reply::Ack) => {
+                    self.delivered.fetch_add(1, Ordering::Relaxed);
+                    member.connection().stats().message_out();
+                    return Delivery::Done;
+                }
+                Ok(Reply::Nack) => Some(nack_backoff(attempts.sent)),
+                Ok(Reply::Reject) => {
+                    log::info!("message rejected by {}", member.connection());
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Delivery::Done;
+                }
+            };
+            if attempts.sent >= max_deliveries {
+                log::warn!(
+                    "group {} gives up a message after {} deliveries",
+                    self.name,
+                    attempts.sent
+                );
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Delivery::GivenUp;
+            }
+            if let Some(delay) = retry {
+                return Delivery::Retry(delay);
+            }
+        }
+        Delivery::Waiting
     }
 
-    fn next_connection(&self) {
-        *self.actual_con.lock().unwrap() += 1;
+    /// Send the frame to the member once it answered the deliveries it was
+    /// given before, and wait its reply.
+    async fn deliver(&self, member: &Member, frame: Bytes) -> OzResult<Reply> {
+        let _turn = member.turn().await;
+        let connection = Arc::clone(member.connection());
+        connection.discard_replies().await;
+        connection.next_delivery();
+        let sent_at = Instant::now();
+        let written = match connection.send_message(frame.clone()).await {
+            Ok(written) => written,
+            Err(e) => {
+                log::error!(
+                    "error on send message {} to currently connection {e}",
+                    String::from_utf8_lossy(&frame)
+                );
+                return Err(OzesError::WithouConnection);
+            }
+        };
+        // consumers ack with the len of the delivery header or with the
+        // bytes they read
+        let len = delivery_header(&frame).map_or(written, |(len, _)| len);
+        let reply = self
+            .process_client_return(connection, [len, written], member.ack_timeout())
+            .await?;
+        if reply == Reply::Ack {
+            self.ack_latency.observe(sent_at.elapsed());
+        }
+        Ok(reply)
     }
 
     async fn process_client_return(
         &self,
         connection: Arc<OzesConnection>,
-        msg_len: usize,
-    ) -> OzResult<()> {
-        let msg = connection.read_message().await;
-        if let Ok(msg) = msg {
-            let commands = ozes_parser::parser::parse(msg);
-            match commands {
-                Ok(cmds) => {
-                    match &cmds[..] {
-                        [Command::Ok { len }] => {
-                            if *len != msg_len {
-                                return Err(OzesError::InvalidLen(*len));
-                            }
-                        }
-                        [_] => {
-                            connection
-                                .send_error_message(Bytes::from_static(
-                                    b"expected 'Ok' one command\n",
-                                ))
-                                .await?;
-                        }
-                        _ => {
-                            connection
-                                .send_error_message(Bytes::from_static(
-                                    b"expected exactly one command\n",
-                                ))
-                                .await?;
+        lens: [usize; 2],
+        ack_timeout: Duration,
+    ) -> OzResult<Reply> {
+        let msg = connection.read_reply(ack_timeout).await?;
+        if let Some(Ok(commands)) = command::parse(&msg) {
+            match &commands[..] {
+                [ExtCommand::Ack { len } | ExtCommand::Nack { len } | ExtCommand::Reject { len }]
+                    if !lens.contains(len) =>
+                {
+                    return Err(OzesError::InvalidLen(*len));
+                }
+                [ExtCommand::Ack { .. }] => return Ok(Reply::Ack),
+                [ExtCommand::Nack { .. }] => return Ok(Reply::Nack),
+                [ExtCommand::Reject { .. }] => return Ok(Reply::Reject),
+                _ => {}
+            }
+        }
+        let commands = command::parse_base(msg);
+        match commands {
+            Ok(cmds) => {
+                match &cmds[..] {
+                    [Command::Ok { len }] => {
+                        if !lens.contains(len) {
+                            return Err(OzesError::InvalidLen(*len));
                         }
                     }
-                    Ok(())
-                }
-                Err(error) => {
-                    println!("parse error: {}", error);
-                    connection
-                        .send_error_message(Bytes::copy_from_slice(error.to_string().as_bytes()))
-                        .await?;
-                    Ok(())
+                    [_] => {
+                        connection
+                            .send_error_message(
+                                ErrorCode::ParseError,
+                                Bytes::from_static(b"expected 'Ok' one command\n"),
+                            )
+                            .await?;
+                        return Err(OzesError::InvalidCommand(String::from(
+                            "expected 'Ok' one command",
+                        )));
+                    }
+                    _ => {
+                        connection
+                            .send_error_message(
+                                ErrorCode::ParseError,
+                                Bytes::from_static(b"expected exactly one command\n"),
+                            )
+                            .await?;
+                        return Err(OzesError::InvalidCommand(String::from(
+                            "expected exactly one command",
+                        )));
+                    }
                 }
+                Ok(Reply::Ack)
+            }
+            Err(error) => {
+                METRICS.parse_error();
+                log::warn!("parse error on reply of {}: {}", connection, error);
+                connection
+                    .send_error_message(
+                        ErrorCode::ParseError,
+                        Bytes::copy_from_slice(error.to_string().as_bytes()),
+                    )
+                    .await?;
+                Err(OzesError::InvalidCommand(error.to_string()))
             }
-        } else {
-            Err(OzesError::WithouConnection)
         }
     }
 }
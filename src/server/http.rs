@@ -0,0 +1,779 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    fmt::Display,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{Buf, Bytes, BytesMut};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::Incoming, header, server::conn::http1, service::service_fn, Method, Request, Response,
+    StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+    net::TcpListener,
+    sync::{mpsc, Mutex, Notify, RwLock},
+    time::{self, Instant},
+};
+
+use crate::{
+    connection::{BoxedStream, ClientInfo, OzesConnection, PeerAddress},
+    delivery_header, delivery_len, BUFFER_SIZE,
+};
+
+use super::{
+    acl::Permission,
+    auth::Credentials,
+    error::{ErrorCode, OzesError},
+    group::SubscribeOptions,
+    handle_consumer,
+    message::Message,
+    Broker,
+};
+
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const MAX_FETCH: usize = 100;
+const MAX_WAIT: Duration = Duration::from_secs(30);
+/// Connections the gateway opens in a group, each one holds a message until
+/// a HTTP client acks it.
+const MAX_MEMBERS: usize = 10;
+/// Time without fetch before the gateway leaves the group.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+const JOIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Extra time the group waits the reply of the gateway, so the gateway nacks
+/// the messages whose time ran out itself.
+const REPLY_MARGIN: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    pub port: u16,
+    /// Time a message delivered to the gateway waits its ack before being
+    /// sent again.
+    pub ack_timeout: Duration,
+}
+
+enum HttpError {
+    BadRequest(String),
+    Unauthorized,
+    Forbidden(String),
+    NotFound,
+    QueueFull(String),
+    Internal(String),
+}
+
+impl From<OzesError> for HttpError {
+    fn from(error: OzesError) -> Self {
+        match error {
+            OzesError::Unauthorized => Self::Unauthorized,
+            OzesError::AccessDenied(message) => Self::Forbidden(message),
+            OzesError::QueueFull(queue) => Self::QueueFull(queue),
+            error => Self::Internal(error.to_string()),
+        }
+    }
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadRequest(message) => write!(f, "bad request: {}", message),
+            Self::Unauthorized => write!(f, "authentication required"),
+            Self::Forbidden(message) => write!(f, "{}", message),
+            Self::NotFound => write!(f, "not found"),
+            Self::QueueFull(queue) => write!(f, "queue {} is full", queue),
+            Self::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl HttpError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::QueueFull(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+type HttpResult<T> = Result<T, HttpError>;
+
+#[derive(Deserialize)]
+struct PublishRequest {
+    /// Base64 of the message.
+    payload: String,
+    key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FetchedMessage {
+    id: u64,
+    payload: String,
+    redelivered: bool,
+}
+
+#[derive(Serialize)]
+struct FetchResponse {
+    messages: Vec<FetchedMessage>,
+}
+
+/// Message delivered to a member of the gateway, held until a HTTP client
+/// acks it or its time runs out.
+struct Held {
+    member: Arc<GatewayMember>,
+    /// Len of the delivery header, the reply to the group carries it.
+    len: usize,
+    payload: Bytes,
+    redelivered: bool,
+    /// Id given to the HTTP client that fetched the message.
+    id: Option<u64>,
+    deadline: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    held: Vec<Held>,
+    /// Payloads nacked once their time ran out, they are marked redelivered
+    /// when they come back.
+    expired: VecDeque<Bytes>,
+}
+
+/// Connection of the gateway in the group. The group waits the reply to a
+/// delivery before the next one, so a member holds one message at a time.
+struct GatewayMember {
+    connection: Arc<OzesConnection>,
+    writer: Mutex<WriteHalf<DuplexStream>>,
+    /// Replies to `SUBSCRIBE`, read from the gateway stream with the
+    /// deliveries.
+    replies: Mutex<mpsc::UnboundedReceiver<Bytes>>,
+}
+
+impl GatewayMember {
+    async fn reply(&self, statement: String) {
+        if let Err(error) = self
+            .writer
+            .lock()
+            .await
+            .write_all(statement.as_bytes())
+            .await
+        {
+            log::info!("http gateway cannot reply {statement}: {error}");
+        }
+    }
+}
+
+/// Messages delivered to the gateway for a group, held until a HTTP client
+/// fetch and ack them.
+///
+/// The members of the gateway answer the group only on the ack of the HTTP
+/// client, and nack the messages whose time runs out or that are held when
+/// the gateway leaves the group, so they are sent again.
+struct Subscription {
+    queue: String,
+    group: String,
+    ack_timeout: Duration,
+    /// Held while joining or leaving the group.
+    members: Mutex<Vec<Arc<GatewayMember>>>,
+    state: StdMutex<State>,
+    notify: Notify,
+    fetching: AtomicUsize,
+    last_fetch: StdMutex<Instant>,
+}
+
+impl Subscription {
+    fn new(queue: &str, group: &str, ack_timeout: Duration) -> Self {
+        Self {
+            queue: queue.to_string(),
+            group: group.to_string(),
+            ack_timeout,
+            members: Mutex::default(),
+            state: StdMutex::default(),
+            notify: Notify::new(),
+            fetching: AtomicUsize::new(0),
+            last_fetch: StdMutex::new(Instant::now()),
+        }
+    }
+
+    /// Join the group as `user` until the gateway has `count` members.
+    async fn join(
+        self: &Arc<Self>,
+        broker: &Arc<Broker>,
+        user: Option<String>,
+        count: usize,
+    ) -> HttpResult<()> {
+        let mut members = self.members.lock().await;
+        while members.len() < count {
+            let member = self.open_member(broker, user.clone());
+            if let Err(error) = self.subscribe(&member, broker).await {
+                let _ = member.writer.lock().await.shutdown().await;
+                return Err(error);
+            }
+            members.push(member);
+            log::info!(
+                "http gateway joined group {} of queue {} with {} members",
+                self.group,
+                self.queue,
+                members.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Open a connection of the gateway, served like the TCP connections.
+    fn open_member(
+        self: &Arc<Self>,
+        broker: &Arc<Broker>,
+        user: Option<String>,
+    ) -> Arc<GatewayMember> {
+        let (stream, gateway_stream) = tokio::io::duplex(BUFFER_SIZE);
+        let stream: BoxedStream = Box::new(stream);
+        let connection = Arc::new(OzesConnection::new(
+            stream,
+            PeerAddress::Internal(String::from("http gateway")),
+        ));
+        connection.set_client(ClientInfo {
+            name: Some(String::from("http-gateway")),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            heartbeat: false,
+        });
+        if let Some(user) = user {
+            connection.set_user(user);
+        }
+        let (reader, writer) = tokio::io::split(gateway_stream);
+        let (replies_sender, replies) = mpsc::unbounded_channel();
+        let member = Arc::new(GatewayMember {
+            connection: Arc::clone(&connection),
+            writer: Mutex::new(writer),
+            replies: Mutex::new(replies),
+        });
+        let consumer_broker = Arc::clone(broker);
+        tokio::spawn(async move {
+            consumer_broker.registry.register(&connection);
+            handle_consumer(Arc::clone(&connection), Arc::clone(&consumer_broker.queues)).await;
+            consumer_broker.registry.unregister(connection.session_id());
+        });
+        tokio::spawn(receive_deliveries(
+            reader,
+            Arc::clone(&member),
+            Arc::clone(self),
+            replies_sender,
+        ));
+        member
+    }
+
+    async fn subscribe(&self, member: &GatewayMember, broker: &Broker) -> HttpResult<()> {
+        let mut replies = member.replies.lock().await;
+        let options = SubscribeOptions {
+            ack_timeout: self.ack_timeout + REPLY_MARGIN,
+            ..Default::default()
+        };
+        let subscribed = broker
+            .queues
+            .add_listener(
+                Arc::clone(&member.connection),
+                &self.queue,
+                &self.group,
+                options,
+            )
+            .await?;
+        let reply = time::timeout(JOIN_TIMEOUT, replies.recv())
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        if !subscribed {
+            let reply = String::from_utf8_lossy(&reply);
+            let (_, message) = ErrorCode::from_reply(reply.trim_start_matches("error #"));
+            return Err(HttpError::Forbidden(message.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Leave the group, the messages held and not fetched are nacked.
+    async fn leave(&self) {
+        let held = std::mem::take(&mut self.state.lock().unwrap().held);
+        for held in held {
+            held.member.reply(format!("NACK +l{};", held.len)).await;
+        }
+        for member in self.members.lock().await.iter() {
+            let _ = member.writer.lock().await.shutdown().await;
+        }
+        log::info!(
+            "http gateway left group {} of queue {}",
+            self.group,
+            self.queue
+        );
+    }
+
+    /// No HTTP client fetched for a while and no fetched message waits its
+    /// ack.
+    fn is_idle(&self) -> bool {
+        self.fetching.load(Ordering::Relaxed) == 0
+            && self.last_fetch.lock().unwrap().elapsed() >= IDLE_TIMEOUT
+            && self
+                .state
+                .lock()
+                .unwrap()
+                .held
+                .iter()
+                .all(|held| held.id.is_none())
+    }
+
+    fn hold(&self, member: &Arc<GatewayMember>, len: usize, payload: Bytes) {
+        let mut state = self.state.lock().unwrap();
+        // the group sends the member a new message once the previous one was
+        // answered or its time ran out
+        state.held.retain(|held| !Arc::ptr_eq(&held.member, member));
+        let redelivered = match state.expired.iter().position(|expired| *expired == payload) {
+            Some(position) => state.expired.remove(position).is_some(),
+            None => false,
+        };
+        state.held.push(Held {
+            member: Arc::clone(member),
+            len,
+            payload,
+            redelivered,
+            id: None,
+            deadline: Instant::now() + self.ack_timeout,
+        });
+        drop(state);
+        self.notify.notify_waiters();
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+        state.held.iter().map(|held| held.deadline).min()
+    }
+
+    /// Nack the messages whose time ran out, the group sends them again.
+    async fn nack_expired(&self) {
+        let expired: Vec<Held> = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let (expired, held) = std::mem::take(&mut state.held)
+                .into_iter()
+                .partition(|held: &Held| held.deadline <= now);
+            state.held = held;
+            for held in &expired {
+                state.expired.push_back(held.payload.clone());
+            }
+            while state.expired.len() > MAX_MEMBERS {
+                state.expired.pop_front();
+            }
+            expired
+        };
+        for held in expired {
+            held.member.reply(format!("NACK +l{};", held.len)).await;
+        }
+    }
+
+    fn lease(&self, max: usize, ids: &AtomicU64) -> Vec<FetchedMessage> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .held
+            .iter_mut()
+            .filter(|held| held.id.is_none())
+            .take(max)
+            .map(|held| {
+                let id = ids.fetch_add(1, Ordering::Relaxed);
+                held.id = Some(id);
+                FetchedMessage {
+                    id,
+                    payload: STANDARD.encode(&held.payload),
+                    redelivered: held.redelivered,
+                }
+            })
+            .collect()
+    }
+
+    /// Join the group with a member for each message asked, up to
+    /// `MAX_MEMBERS`, and wait the messages.
+    async fn fetch(
+        self: &Arc<Self>,
+        broker: &Arc<Broker>,
+        user: Option<String>,
+        max: usize,
+        wait: Duration,
+        ids: &AtomicU64,
+    ) -> HttpResult<Vec<FetchedMessage>> {
+        let result = match self.join(broker, user, max.min(MAX_MEMBERS)).await {
+            Ok(()) => Ok(self.wait_messages(max, wait, ids).await),
+            Err(error) => Err(error),
+        };
+        *self.last_fetch.lock().unwrap() = Instant::now();
+        self.fetching.fetch_sub(1, Ordering::Relaxed);
+        result
+    }
+
+    async fn wait_messages(
+        &self,
+        max: usize,
+        wait: Duration,
+        ids: &AtomicU64,
+    ) -> Vec<FetchedMessage> {
+        let deadline = Instant::now() + wait;
+        loop {
+            let notified = self.notify.notified();
+            let messages = self.lease(max, ids);
+            if !messages.is_empty() || Instant::now() >= deadline {
+                return messages;
+            }
+            let _ = time::timeout_at(deadline, notified).await;
+        }
+    }
+
+    /// Ack the message to the group, `false` when no message has the id.
+    async fn ack(&self, id: u64) -> bool {
+        let held = {
+            let mut state = self.state.lock().unwrap();
+            let position = state.held.iter().position(|held| held.id == Some(id));
+            position.map(|position| state.held.remove(position))
+        };
+        match held {
+            Some(held) => {
+                held.member.reply(format!("ok +l{};", held.len)).await;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+type Subscriptions = HashMap<(String, String), Arc<Subscription>>;
+
+/// Embedded HTTP gateway for clients that cannot keep a connection open.
+///
+/// To fetch messages the gateway joins the group with in-process
+/// connections, so messages follow the same delivery path as the TCP
+/// consumers. A message delivered to it is acked to the group only when a
+/// HTTP client acks it, and the gateway leaves the group when the HTTP
+/// clients stop fetching.
+pub(crate) struct Gateway {
+    broker: Arc<Broker>,
+    config: HttpConfig,
+    subscriptions: Arc<RwLock<Subscriptions>>,
+    ids: AtomicU64,
+}
+
+impl Gateway {
+    pub(crate) fn new(broker: Arc<Broker>, config: HttpConfig) -> Self {
+        Self {
+            broker,
+            config,
+            subscriptions: Arc::default(),
+            ids: AtomicU64::new(1),
+        }
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        self.config.port
+    }
+
+    async fn handle(&self, request: Request<Incoming>, address: IpAddr) -> Response<Full<Bytes>> {
+        let method = request.method().clone();
+        log::debug!("http {} {}", method, request.uri());
+        match self.route(request, address).await {
+            Ok(response) => response,
+            Err(error) => {
+                log::info!("http {} request failed: {}", method, error);
+                json_response(
+                    error.status(),
+                    &serde_json::json!({ "error": error.to_string() }),
+                )
+            }
+        }
+    }
+
+    async fn route(
+        &self,
+        request: Request<Incoming>,
+        address: IpAddr,
+    ) -> HttpResult<Response<Full<Bytes>>> {
+        let user = self.authenticate(&request, address).await?;
+        let segments: Vec<String> = request
+            .uri()
+            .path()
+            .trim_matches('/')
+            .split('/')
+            .map(String::from)
+            .collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        match (request.method(), &segments[..]) {
+            (&Method::POST, ["queues", queue, "messages"]) => {
+                let queue = queue.to_string();
+                self.publish(&queue, user, request).await
+            }
+            (&Method::GET, ["queues", queue, "groups", group, "messages"]) => {
+                let query = parse_query(request.uri().query());
+                self.fetch(queue, group, user, &query).await
+            }
+            (&Method::POST, ["queues", queue, "groups", group, "messages", id, "ack"]) => {
+                let id = id
+                    .parse()
+                    .map_err(|_| HttpError::BadRequest(format!("invalid message id {}", id)))?;
+                self.ack(queue, group, id, user).await
+            }
+            _ => Err(HttpError::NotFound),
+        }
+    }
+
+    /// User of the request, with `Authorization: Bearer <token>` or basic
+    /// authentication, required only when the server require `AUTH`.
+    async fn authenticate(
+        &self,
+        request: &Request<Incoming>,
+        address: IpAddr,
+    ) -> HttpResult<Option<String>> {
+        let auth = match &self.broker.auth {
+            Some(auth) => auth,
+            None => return Ok(None),
+        };
+        let header = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(HttpError::Unauthorized)?;
+        let credentials = match header.split_once(' ') {
+            Some(("Bearer", token)) => Credentials::Token(token.to_string()),
+            Some(("Basic", encoded)) => {
+                let decoded = STANDARD
+                    .decode(encoded)
+                    .ok()
+                    .and_then(|decoded| String::from_utf8(decoded).ok())
+                    .ok_or(HttpError::Unauthorized)?;
+                let (username, password) =
+                    decoded.split_once(':').ok_or(HttpError::Unauthorized)?;
+                Credentials::Password {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                }
+            }
+            _ => return Err(HttpError::Unauthorized),
+        };
+        auth.authenticate(&credentials, Some(address))
+            .await
+            .map(Some)
+            .ok_or(HttpError::Unauthorized)
+    }
+
+    async fn publish(
+        &self,
+        queue: &str,
+        user: Option<String>,
+        request: Request<Incoming>,
+    ) -> HttpResult<Response<Full<Bytes>>> {
+        let body = Limited::new(request.into_body(), MAX_BODY_SIZE)
+            .collect()
+            .await
+            .map_err(|error| HttpError::BadRequest(error.to_string()))?
+            .to_bytes();
+        let request: PublishRequest = serde_json::from_slice(&body)
+            .map_err(|error| HttpError::BadRequest(error.to_string()))?;
+        let payload = STANDARD
+            .decode(&request.payload)
+            .map_err(|error| HttpError::BadRequest(format!("invalid base64 payload: {error}")))?;
+        let message = Message::with_key(Bytes::from(payload), request.key.map(Bytes::from));
+        self.broker
+            .queues
+            .push_message(
+                message,
+                Bytes::copy_from_slice(queue.as_bytes()),
+                user.as_deref(),
+            )
+            .await?;
+        Ok(json_response(
+            StatusCode::OK,
+            &serde_json::json!({ "status": "published" }),
+        ))
+    }
+
+    async fn fetch(
+        &self,
+        queue: &str,
+        group: &str,
+        user: Option<String>,
+        query: &HashMap<String, String>,
+    ) -> HttpResult<Response<Full<Bytes>>> {
+        let max = query
+            .get("max")
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(1usize)
+            .clamp(1, MAX_FETCH);
+        let wait = query
+            .get("wait")
+            .and_then(|wait| wait.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default()
+            .min(MAX_WAIT);
+        self.broker
+            .queues
+            .check(user.as_deref(), Permission::Consume, queue, Some(group))?;
+        if !self.broker.queues.exists(queue).await {
+            return Err(HttpError::NotFound);
+        }
+        let subscription = self.subscription(queue, group).await;
+        let messages = subscription
+            .fetch(&self.broker, user, max, wait, &self.ids)
+            .await?;
+        Ok(json_response(StatusCode::OK, &FetchResponse { messages }))
+    }
+
+    async fn ack(
+        &self,
+        queue: &str,
+        group: &str,
+        id: u64,
+        user: Option<String>,
+    ) -> HttpResult<Response<Full<Bytes>>> {
+        self.broker
+            .queues
+            .check(user.as_deref(), Permission::Consume, queue, Some(group))?;
+        let key = (queue.to_string(), group.to_string());
+        let subscription = self.subscriptions.read().await.get(&key).map(Arc::clone);
+        match subscription {
+            Some(subscription) if subscription.ack(id).await => Ok(json_response(
+                StatusCode::OK,
+                &serde_json::json!({ "status": "acked" }),
+            )),
+            _ => Err(HttpError::NotFound),
+        }
+    }
+
+    /// Subscription of the gateway to the group, counted as fetching so it
+    /// is not left meanwhile. It is created on a fetch and removed when it
+    /// leaves the group.
+    async fn subscription(&self, queue: &str, group: &str) -> Arc<Subscription> {
+        let key = (queue.to_string(), group.to_string());
+        if let Some(subscription) = self.subscriptions.read().await.get(&key) {
+            subscription.fetching.fetch_add(1, Ordering::Relaxed);
+            return Arc::clone(subscription);
+        }
+        let mut subscriptions = self.subscriptions.write().await;
+        let subscription = subscriptions.entry(key).or_insert_with(|| {
+            let subscription = Arc::new(Subscription::new(queue, group, self.config.ack_timeout));
+            tokio::spawn(watch(
+                Arc::clone(&subscription),
+                Arc::clone(&self.subscriptions),
+            ));
+            subscription
+        });
+        subscription.fetching.fetch_add(1, Ordering::Relaxed);
+        Arc::clone(subscription)
+    }
+}
+
+/// Read the messages delivered to a member of the gateway,
+/// `+l<len> #<message>`, and hold them for the HTTP clients. Other replies go
+/// to `subscribe`.
+async fn receive_deliveries(
+    mut reader: ReadHalf<DuplexStream>,
+    member: Arc<GatewayMember>,
+    subscription: Arc<Subscription>,
+    replies: mpsc::UnboundedSender<Bytes>,
+) {
+    let mut buffer = BytesMut::with_capacity(BUFFER_SIZE);
+    loop {
+        let read = tokio::select! {
+            read = reader.read_buf(&mut buffer) => read,
+            _ = member.connection.closed() => break,
+        };
+        match read {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        while let Some((len, header_len)) = delivery_header(&buffer) {
+            if buffer.len() < delivery_len(len) {
+                break;
+            }
+            let mut delivery = buffer.split_to(delivery_len(len));
+            delivery.advance(header_len);
+            subscription.hold(&member, len, delivery.freeze());
+        }
+        if buffer.len() > 2 && !buffer.starts_with(b"+l") {
+            let _ = replies.send(buffer.split().freeze());
+        }
+    }
+}
+
+/// Nack the messages whose time ran out, and leave the group when no HTTP
+/// client fetched for a while.
+async fn watch(subscription: Arc<Subscription>, subscriptions: Arc<RwLock<Subscriptions>>) {
+    let mut idle_check = time::interval(IDLE_TIMEOUT);
+    loop {
+        let notified = subscription.notify.notified();
+        let deadline = subscription.next_deadline();
+        tokio::select! {
+            _ = idle_check.tick() => {
+                let mut subscriptions = subscriptions.write().await;
+                if subscription.is_idle() {
+                    subscriptions.remove(&(subscription.queue.clone(), subscription.group.clone()));
+                    drop(subscriptions);
+                    subscription.leave().await;
+                    return;
+                }
+            }
+            _ = async {
+                match deadline {
+                    Some(deadline) => time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => subscription.nack_expired().await,
+            _ = notified => {}
+        }
+    }
+}
+
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Full<Bytes>> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+pub(crate) async fn accept_http(listener: TcpListener, gateway: Arc<Gateway>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, socket_address)) => {
+                let gateway = Arc::clone(&gateway);
+                tokio::task::spawn(async move {
+                    let service = service_fn(move |request| {
+                        let gateway = Arc::clone(&gateway);
+                        async move {
+                            Ok::<_, Infallible>(gateway.handle(request, socket_address.ip()).await)
+                        }
+                    });
+                    if let Err(error) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        log::error!("error on http connection {socket_address}: {error}");
+                    }
+                });
+            }
+            Err(e) => log::error!("error on accept http connection {}", e),
+        }
+    }
+}
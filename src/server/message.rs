@@ -0,0 +1,148 @@
+use std::{sync::Arc, time::SystemTime};
+
+use bytes::Bytes;
+use tokio::sync::OnceCell;
+
+use crate::{
+    compression::{Compression, Encodings, CONTENT_ENCODING},
+    delivery_statement,
+};
+
+#[derive(Clone, Debug)]
+pub struct Message {
+    payload: Bytes,
+    key: Option<Bytes>,
+    headers: Vec<(String, String)>,
+    published_at: SystemTime,
+    /// Payload without its compression, decompressed once and shared by the
+    /// copies of the message.
+    decompressed: Arc<OnceCell<Result<Bytes, String>>>,
+}
+
+impl Message {
+    pub fn new(payload: Bytes) -> Self {
+        Self::with_key(payload, None)
+    }
+
+    pub fn with_key(payload: Bytes, key: Option<Bytes>) -> Self {
+        Self {
+            payload,
+            key,
+            headers: vec![],
+            published_at: SystemTime::now(),
+            decompressed: Arc::default(),
+        }
+    }
+
+    pub fn with_headers(mut self, headers: Vec<(String, String)>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    pub fn key(&self) -> Option<&Bytes> {
+        self.key.as_ref()
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    pub fn published_at(&self) -> SystemTime {
+        self.published_at
+    }
+
+    /// Message as sent to consumers, `+l<len> #<payload>`, or with the
+    /// headers for consumers that asked them, `+l<len> <name>=<value> #<payload>`.
+    ///
+    /// A compressed payload is sent as it was published to consumers that
+    /// accept its encoding, and decompressed for the others. Fails when the
+    /// payload cannot be decompressed.
+    pub async fn frame(
+        &self,
+        with_headers: bool,
+        accept_encoding: Encodings,
+    ) -> Result<Bytes, String> {
+        let decompressed = self.decompress_for(accept_encoding).await?;
+        let payload = decompressed.as_ref().unwrap_or(&self.payload);
+        let mut headers = vec![];
+        if with_headers {
+            for (name, value) in &self.headers {
+                if decompressed.is_some() && name == CONTENT_ENCODING {
+                    continue;
+                }
+                headers.push(format!("{name}={value}"));
+            }
+        }
+        Ok(Bytes::from(delivery_statement(&headers.join(" "), payload)))
+    }
+
+    pub fn compression(&self) -> Option<Compression> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name == CONTENT_ENCODING)
+            .and_then(|(_, value)| value.parse().ok())
+    }
+
+    /// Payload decompressed when the consumer does not accept its encoding,
+    /// the decompression runs in a blocking task the first time only.
+    async fn decompress_for(&self, accept_encoding: Encodings) -> Result<Option<Bytes>, String> {
+        let Some(compression) = self
+            .compression()
+            .filter(|compression| !accept_encoding.contains(*compression))
+        else {
+            return Ok(None);
+        };
+        let payload = self.payload.clone();
+        self.decompressed
+            .get_or_init(|| async move {
+                tokio::task::spawn_blocking(move || compression.decompress(&payload))
+                    .await
+                    .map_err(|error| error.to_string())
+                    .and_then(|decompressed| decompressed.map_err(|error| error.to_string()))
+                    .map(Bytes::from)
+                    .map_err(|error| format!("cannot decompress {compression} message: {error}"))
+            })
+            .await
+            .clone()
+            .map(Some)
+    }
+
+    /// Partition of the message in a queue with `partitions` partitions,
+    /// messages with the same key always land in the same partition, also
+    /// across broker versions.
+    pub fn partition(&self, partitions: usize) -> Option<usize> {
+        let key = self.key.as_ref()?;
+        Some((fnv1a(key) % partitions as u64) as usize)
+    }
+}
+
+/// 64 bits FNV-1a hash.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partition_is_stable() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        let message = Message::with_key(
+            Bytes::from_static(b"payload"),
+            Some(Bytes::from_static(b"a")),
+        );
+        assert_eq!(
+            message.partition(10),
+            Some((0xaf63dc4c8601ec8c_u64 % 10) as usize)
+        );
+        assert_eq!(Message::new(Bytes::new()).partition(10), None);
+    }
+}
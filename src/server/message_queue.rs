@@ -1,143 +1,680 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::Arc,
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Duration, UNIX_EPOCH},
 };
 
+use base64::{engine::general_purpose::STANDARD, Engine};
 use bytes::Bytes;
-use tokio::sync::RwLock;
+use tokio::{
+    sync::{Notify, RwLock, RwLockReadGuard},
+    time::Instant,
+};
 
 use crate::connection::Connection;
 
-use super::{group::Group, OzResult, OzesConnection};
-
-#[derive(Default)]
-pub struct OzesConnections(RwLock<Vec<Arc<OzesConnection>>>);
+use super::{
+    acl::{Acl, Permission},
+    config::DEFAULT_MAX_DELIVERIES,
+    error::{ErrorCode, OzesError},
+    group::{Attempts, Delivery, Group, GroupMode, SubscribeOptions},
+    message::Message,
+    stats::{PeekedMessage, QueueStats},
+    OzResult, OzesConnection,
+};
 
 #[derive(Default)]
 struct QueueWrapper(RwLock<HashMap<String, Arc<InnerQueue>>>);
 
-#[derive(Default)]
 pub struct MQueue {
     queues: QueueWrapper,
+    acl: Option<Acl>,
+    max_deliveries: u32,
+    dead_letter_suffix: Option<String>,
+    max_messages: Option<usize>,
+}
+
+impl Default for MQueue {
+    fn default() -> Self {
+        Self::new(None, DEFAULT_MAX_DELIVERIES, None, None)
+    }
+}
+
+/// Message taken from the queue that some groups did not take yet.
+pub(super) struct InFlight {
+    message: Message,
+    partition: Option<usize>,
+    /// Groups to send the message again, by name.
+    groups: Vec<(String, Attempts)>,
+    /// Time to send it again, `None` while every group left waits a member.
+    retry_at: Option<Instant>,
+}
+
+impl InFlight {
+    /// Every group took the message.
+    pub(super) fn is_done(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    pub(super) fn retry_at(&self) -> Option<Instant> {
+        self.retry_at
+    }
+}
+
+/// Messages taken from the queue and not taken by every group yet.
+#[derive(Default)]
+struct Slots {
+    /// Messages in flight without partition, up to the window of the queue.
+    count: usize,
+    /// Partitions with a message in flight, each partition has one so the
+    /// order of a key is kept and a slow partition does not hold the others.
+    partitions: HashSet<usize>,
 }
 
 #[derive(Default)]
 pub(super) struct InnerQueue {
-    groups: RwLock<Vec<Group>>,
-    messages: RwLock<VecDeque<Bytes>>,
+    partitions: Option<usize>,
+    groups: RwLock<Vec<Arc<Group>>>,
+    messages: RwLock<VecDeque<Message>>,
+    in_flight: StdMutex<Slots>,
+    /// Notified when a message is published, a consumer subscribes, a
+    /// message in flight is taken or the queue is deleted.
+    changed: Arc<Notify>,
+    published: AtomicU64,
 }
 
 impl InnerQueue {
-    fn with_groups(groups: Vec<Group>) -> Self {
+    fn with_partitions(partitions: Option<usize>) -> Self {
         Self {
-            groups: RwLock::new(groups),
-            messages: RwLock::default(),
+            partitions,
+            ..Default::default()
+        }
+    }
+
+    pub(super) async fn depth(&self) -> usize {
+        self.messages.read().await.len()
+    }
+
+    pub(super) fn published(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    pub(super) async fn groups(&self) -> RwLockReadGuard<'_, Vec<Arc<Group>>> {
+        self.groups.read().await
+    }
+
+    pub(super) fn changed(&self) -> Arc<Notify> {
+        Arc::clone(&self.changed)
+    }
+
+    pub(super) async fn stats(&self, name: &str) -> QueueStats {
+        let mut groups = vec![];
+        for group in self.groups.read().await.iter() {
+            groups.push(group.stats().await);
+        }
+        QueueStats {
+            name: name.to_string(),
+            partitions: self.partitions,
+            messages: self.depth().await,
+            published: self.published(),
+            groups,
         }
     }
 
-    async fn get_message(&self) -> Option<Bytes> {
-        self.messages.write().await.pop_front()
+    /// Messages without partition delivered at once. Each member answers its
+    /// deliveries one by one, so more messages than members would only wait
+    /// their turn, and queues with an exclusive group deliver one by one to
+    /// keep the order.
+    async fn window(&self) -> usize {
+        let groups = self.groups.read().await;
+        if groups
+            .iter()
+            .any(|group| group.mode() == GroupMode::Exclusive)
+        {
+            return 1;
+        }
+        let mut members = 0;
+        for group in groups.iter() {
+            members += group.member_count().await;
+        }
+        members.max(1)
     }
 
-    pub(crate) async fn process_message(&self) {
-        if let Some(message) = self.get_message().await {
-            let final_message = self.make_final_message(message);
-            let groups = self.groups.read().await;
-            for group in groups.iter() {
-                let _ = group.send_message(&final_message).await;
+    async fn has_members(&self) -> bool {
+        for group in self.groups.read().await.iter() {
+            if group.member_count().await > 0 {
+                return true;
             }
         }
+        false
+    }
+
+    /// Take the next message to deliver, skipping the messages of partitions
+    /// with a message in flight and, when the window is full, the messages
+    /// without partition. `None` when there is none or no group has members,
+    /// messages stay in the queue while no group has members.
+    pub(super) async fn next_in_flight(&self) -> Option<InFlight> {
+        if !self.has_members().await {
+            return None;
+        }
+        let window = self.window().await;
+        let (message, partition) = {
+            let mut messages = self.messages.write().await;
+            let mut slots = self.in_flight.lock().unwrap();
+            let partition_of = |message: &Message| {
+                self.partitions
+                    .and_then(|partitions| message.partition(partitions))
+            };
+            let position = messages
+                .iter()
+                .position(|message| match partition_of(message) {
+                    Some(partition) => !slots.partitions.contains(&partition),
+                    None => slots.count < window,
+                })?;
+            let message = messages.remove(position)?;
+            let partition = partition_of(&message);
+            match partition {
+                Some(partition) => slots.partitions.insert(partition),
+                None => {
+                    slots.count += 1;
+                    true
+                }
+            };
+            (message, partition)
+        };
+        let groups = self
+            .groups
+            .read()
+            .await
+            .iter()
+            .map(|group| (group.name().to_string(), Attempts::default()))
+            .collect();
+        Some(InFlight {
+            message,
+            partition,
+            groups,
+            retry_at: Some(Instant::now()),
+        })
     }
 
-    #[inline(always)]
-    fn make_final_message(&self, message: Bytes) -> Bytes {
-        let message_len = message.len();
-        let len_string = Self::len_string_len(message_len);
-        const SIZE_INFO: usize = 4;
-        let final_size: usize = message_len + len_string + SIZE_INFO;
-        let mut final_message: Vec<u8> = Vec::with_capacity(final_size);
-        final_message.extend_from_slice(b"+l");
-        final_message.extend_from_slice(final_size.to_string().as_bytes());
-        final_message.extend_from_slice(b" #");
-        final_message.extend_from_slice(&message);
-        Bytes::copy_from_slice(&final_message)
+    /// Free the slot of a message every group took.
+    pub(super) fn release(&self, in_flight: &InFlight) {
+        let mut slots = self.in_flight.lock().unwrap();
+        match in_flight.partition {
+            Some(partition) => {
+                slots.partitions.remove(&partition);
+            }
+            None => slots.count = slots.count.saturating_sub(1),
+        }
+        drop(slots);
+        self.changed.notify_waiters();
     }
 
-    #[inline(always)]
-    fn len_string_len(len: usize) -> usize {
-        len.to_string().len()
+    /// Deliver the message to the groups that did not take it yet. The ones
+    /// that have to receive it again stay in flight with the time to send it
+    /// again. Returns a copy of the message for each group that gave it up.
+    pub(super) async fn deliver(
+        &self,
+        in_flight: &mut InFlight,
+        max_deliveries: u32,
+    ) -> Vec<Message> {
+        // the groups are not held while waiting the replies, a consumer can
+        // subscribe meanwhile
+        let groups: Vec<Arc<Group>> = self.groups.read().await.iter().map(Arc::clone).collect();
+        let mut pending = vec![];
+        let mut given_up = vec![];
+        let mut retry_in: Option<Duration> = None;
+        for (name, mut attempts) in std::mem::take(&mut in_flight.groups) {
+            let Some(group) = groups.iter().find(|group| group.name() == name) else {
+                continue;
+            };
+            let delivery = group
+                .send_message(
+                    &in_flight.message,
+                    in_flight.partition,
+                    &mut attempts,
+                    max_deliveries,
+                )
+                .await;
+            match delivery {
+                Delivery::Retry(delay) => {
+                    retry_in = Some(retry_in.map_or(delay, |retry_in| retry_in.min(delay)));
+                }
+                Delivery::Waiting => {}
+                Delivery::Done => continue,
+                Delivery::GivenUp => {
+                    given_up.push(in_flight.message.clone());
+                    continue;
+                }
+            }
+            pending.push((name, attempts));
+        }
+        in_flight.groups = pending;
+        in_flight.retry_at = retry_in.map(|delay| Instant::now() + delay);
+        given_up
     }
 
-    async fn push_message(&self, message: Bytes) {
+    async fn push_message(&self, message: Message) {
         self.messages.write().await.push_back(message);
+        self.published.fetch_add(1, Ordering::Relaxed);
+        self.changed.notify_waiters();
     }
 }
 
 impl MQueue {
+    pub(crate) fn new(
+        acl: Option<Acl>,
+        max_deliveries: u32,
+        dead_letter_suffix: Option<String>,
+        max_messages: Option<usize>,
+    ) -> Self {
+        Self {
+            queues: QueueWrapper::default(),
+            acl,
+            max_deliveries,
+            dead_letter_suffix,
+            max_messages,
+        }
+    }
+
+    pub(super) fn max_deliveries(&self) -> u32 {
+        self.max_deliveries
+    }
+
+    pub(super) fn has_acl(&self) -> bool {
+        self.acl.is_some()
+    }
+
+    /// Publish a message given up by a group of `queue_name` to its dead
+    /// letter queue, creating it when needed. Messages given up in a dead
+    /// letter queue are dropped.
+    pub(super) async fn dead_letter(&self, queue_name: &str, message: Message) {
+        let Some(suffix) = &self.dead_letter_suffix else {
+            return;
+        };
+        if queue_name.ends_with(suffix.as_str()) {
+            log::warn!("message given up in dead letter queue {queue_name} dropped");
+            return;
+        }
+        let dead_letter_queue = format!("{queue_name}{suffix}");
+        log::info!("message of {queue_name} sent to {dead_letter_queue}");
+        self.queues
+            .get_or_insert_with(&dead_letter_queue, InnerQueue::default)
+            .await
+            .push_message(message)
+            .await;
+    }
+
+    pub(super) fn check(
+        &self,
+        user: Option<&str>,
+        permission: Permission,
+        queue_name: &str,
+        group_name: Option<&str>,
+    ) -> OzResult<()> {
+        match &self.acl {
+            Some(acl) => acl.check(user, permission, queue_name, group_name),
+            None => Ok(()),
+        }
+    }
+
+    fn allows(&self, user: Option<&str>, permission: Permission, queue_name: &str) -> bool {
+        match &self.acl {
+            Some(acl) => acl.allows(user, permission, queue_name, None),
+            None => true,
+        }
+    }
+
     pub async fn add_listener(
         &self,
         connection: Arc<OzesConnection>,
         queue_name: &str,
         group_name: &str,
-    ) {
+        options: SubscribeOptions,
+    ) -> OzResult<bool> {
         log::info!(
             "add listener {} to queue {queue_name} with group {group_name}",
-            connection.socket_address()
+            connection
         );
 
-        if let Some(inner) = self.queues.get(queue_name).await {
-            let mut groups = inner.groups.write().await;
-            if connection.ok_subscribed().await.is_ok() {
-                if let Some(group) = groups.iter().find(|g| g.name() == group_name) {
-                    group.push_connection(Arc::clone(&connection)).await;
-                } else {
-                    let group = Group::new(group_name.to_string());
-                    group.push_connection(Arc::clone(&connection)).await;
-                    groups.push(group);
-                    log::debug!("finish to add consumer to existent group in queue {queue_name}");
+        let user = connection.user();
+        let queue = self.queues.get(queue_name).await;
+        let group_exists = match &queue {
+            Some(inner) => inner
+                .groups
+                .read()
+                .await
+                .iter()
+                .any(|g| g.name() == group_name),
+            None => false,
+        };
+        let allowed = self
+            .check(
+                user.as_deref(),
+                Permission::Consume,
+                queue_name,
+                Some(group_name),
+            )
+            .and_then(|_| {
+                if group_exists {
+                    return Ok(());
                 }
+                self.check(
+                    user.as_deref(),
+                    Permission::Create,
+                    queue_name,
+                    Some(group_name),
+                )
+            });
+        if let Err(error) = allowed {
+            connection.send_error(&error).await?;
+            return Ok(false);
+        }
+
+        let inner = match queue {
+            Some(inner) => inner,
+            None => {
+                self.queues
+                    .get_or_insert_with(queue_name, || {
+                        InnerQueue::with_partitions(options.partitions)
+                    })
+                    .await
             }
-        } else {
-            self.add_queue_with_listener(group_name, connection, queue_name)
+        };
+        // a queue created by a publisher or an older subscriber has no
+        // partitions, the ones asked now cannot be honored
+        if options.partitions.is_some() && options.partitions != inner.partitions {
+            let partitions = match inner.partitions {
+                Some(partitions) => format!("{partitions} partitions"),
+                None => String::from("no partitions"),
+            };
+            connection
+                .send_error_message(
+                    ErrorCode::InvalidState,
+                    Bytes::from(format!(
+                        "queue {queue_name} already exists with {partitions}"
+                    )),
+                )
+                .await?;
+            return Ok(false);
+        }
+        let mut groups = inner.groups.write().await;
+        if let Some(group) = groups.iter().find(|g| g.name() == group_name) {
+            if group.mode() != options.mode || group.strategy() != options.strategy {
+                connection
+                    .send_error_message(
+                        ErrorCode::InvalidState,
+                        Bytes::from(format!(
+                            "group {group_name} already exists with mode {:?} and strategy {:?}",
+                            group.mode(),
+                            group.strategy()
+                        )),
+                    )
+                    .await?;
+                return Ok(false);
+            }
+            if connection.ok_subscribed().await.is_ok() {
+                group
+                    .push_connection(Arc::clone(&connection), options)
+                    .await;
+                inner.changed.notify_waiters();
+                return Ok(true);
+            }
+        } else if connection.ok_subscribed().await.is_ok() {
+            let group = Group::new(group_name.to_string(), options, inner.partitions);
+            group
+                .push_connection(Arc::clone(&connection), options)
                 .await;
+            groups.push(Arc::new(group));
+            inner.changed.notify_waiters();
+            log::debug!("finish to add consumer to existent group in queue {queue_name}");
+            return Ok(true);
         }
+        Ok(false)
     }
 
-    async fn add_queue_with_listener(
+    pub async fn create_queue(
         &self,
-        group_name: &str,
-        connection: Arc<OzesConnection>,
         queue_name: &str,
-    ) {
-        log::info!("adding new group {group_name} to queue {queue_name}");
-        let group = Group::new(group_name.to_string());
-        log::info!("adding connection to new group {group_name}");
-        group.push_connection(Arc::clone(&connection)).await;
-        log::info!("adding group {group_name} to queue {queue_name}");
-        let inner_queue = InnerQueue::with_groups(vec![group]);
-        if connection.ok_subscribed().await.is_ok() {
-            self.queues.insert(queue_name, Arc::new(inner_queue)).await;
-            log::info!("listener add to queue {queue_name} with group {group_name}");
+        partitions: Option<usize>,
+        user: Option<&str>,
+    ) -> OzResult<()> {
+        self.check(user, Permission::Create, queue_name, None)?;
+        let queue = Arc::new(InnerQueue::with_partitions(partitions));
+        if !self.queues.try_insert(queue_name, queue).await {
+            return Err(OzesError::QueueAlreadyExists(queue_name.to_string()));
         }
+        log::info!("created queue {queue_name} with {partitions:?} partitions");
+        Ok(())
     }
 
-    pub async fn push_message(&self, message: Bytes, queue_name: Bytes) -> OzResult<()> {
-        let queue_name = String::from_utf8_lossy(&queue_name[..]);
-        log::info!("checking if {queue_name} exists",);
-        if let Some(queue) = self.queues.get(&*queue_name).await {
-            queue.push_message(message).await;
-            log::info!("queue {} founded, push message to queue", queue_name);
-        } else {
-            let inner_queue = InnerQueue::default();
-            log::info!("adding new queue {queue_name}");
-            inner_queue.push_message(message).await;
-            self.queues
-                .insert(&*queue_name, Arc::new(inner_queue))
-                .await;
+    /// Check that the user can publish to the queue, and create it when it
+    /// does not exist yet.
+    pub async fn check_publish(&self, user: Option<&str>, queue_name: &str) -> OzResult<()> {
+        self.check(user, Permission::Publish, queue_name, None)?;
+        if self.queues.get(queue_name).await.is_none() {
+            self.check(user, Permission::Create, queue_name, None)?;
         }
         Ok(())
     }
 
+    /// Fail with `QueueFull` when the queue cannot take `count` messages
+    /// more. Dead letters and moved messages are not bounded.
+    pub(super) async fn check_room(&self, queue_name: &str, count: usize) -> OzResult<()> {
+        let Some(max_messages) = self.max_messages else {
+            return Ok(());
+        };
+        let waiting = match self.queues.get(queue_name).await {
+            Some(queue) => queue.depth().await,
+            None => 0,
+        };
+        if waiting + count > max_messages {
+            return Err(OzesError::QueueFull(queue_name.to_string()));
+        }
+        Ok(())
+    }
+
+    pub async fn push_message(
+        &self,
+        message: Message,
+        queue_name: Bytes,
+        user: Option<&str>,
+    ) -> OzResult<()> {
+        self.check_room(&String::from_utf8_lossy(&queue_name), 1)
+            .await?;
+        self.push_unbounded(message, queue_name, user).await
+    }
+
+    /// Push the message even when the queue is full, for the commits checked
+    /// before.
+    pub(super) async fn push_unbounded(
+        &self,
+        message: Message,
+        queue_name: Bytes,
+        user: Option<&str>,
+    ) -> OzResult<()> {
+        let queue_name = String::from_utf8_lossy(&queue_name[..]);
+        self.check(user, Permission::Publish, &queue_name, None)?;
+        let queue = match self.queues.get(&queue_name).await {
+            Some(queue) => queue,
+            None => {
+                self.check(user, Permission::Create, &queue_name, None)?;
+                self.queues
+                    .get_or_insert_with(&queue_name, InnerQueue::default)
+                    .await
+            }
+        };
+        queue.push_message(message).await;
+        Ok(())
+    }
+
+    pub async fn remove_connection(&self, connection: &Arc<OzesConnection>) {
+        for key in self.queues.get_keys().await {
+            if let Some(queue) = self.queues.get(&key).await {
+                for group in queue.groups.read().await.iter() {
+                    group.remove_connection(connection).await;
+                }
+            }
+        }
+    }
+
+    /// Stats of every queue the user can consume sorted by name, or only of
+    /// `queue_name`. Admins see every queue.
+    pub async fn stats(
+        &self,
+        queue_name: Option<&str>,
+        user: Option<&str>,
+    ) -> OzResult<Vec<QueueStats>> {
+        let mut names = match queue_name {
+            Some(queue_name) => {
+                self.check(user, Permission::Consume, queue_name, None)?;
+                vec![queue_name.to_string()]
+            }
+            None => {
+                let admin = self.allows(user, Permission::Admin, "*");
+                let mut names = self.queues.get_keys().await;
+                names.retain(|name| admin || self.allows(user, Permission::Consume, name));
+                names
+            }
+        };
+        names.sort();
+        let mut stats = vec![];
+        for name in names {
+            match self.queues.get(&name).await {
+                Some(queue) => stats.push(queue.stats(&name).await),
+                None if queue_name.is_some() => return Err(OzesError::QueueNotFound(name)),
+                None => {}
+            }
+        }
+        Ok(stats)
+    }
+
+    pub async fn exists(&self, queue_name: &str) -> bool {
+        self.queues.get(queue_name).await.is_some()
+    }
+
+    /// Messages waiting in the queue from `offset`, the queue is left as is.
+    pub async fn peek(
+        &self,
+        queue_name: &str,
+        offset: usize,
+        count: usize,
+        user: Option<&str>,
+    ) -> OzResult<Vec<PeekedMessage>> {
+        self.check(user, Permission::Consume, queue_name, None)?;
+        let queue = self
+            .queues
+            .get(queue_name)
+            .await
+            .ok_or_else(|| OzesError::QueueNotFound(queue_name.to_string()))?;
+        let messages = queue.messages.read().await;
+        Ok(messages
+            .iter()
+            .enumerate()
+            .skip(offset)
+            .take(count)
+            .map(|(offset, message)| PeekedMessage {
+                offset,
+                size: message.payload().len(),
+                key: message
+                    .key()
+                    .map(|key| String::from_utf8_lossy(key).into_owned()),
+                partition: queue
+                    .partitions
+                    .and_then(|partitions| message.partition(partitions)),
+                published_at: message
+                    .published_at()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_millis() as u64)
+                    .unwrap_or_default(),
+                headers: message.headers().iter().cloned().collect(),
+                payload: STANDARD.encode(message.payload()),
+            })
+            .collect())
+    }
+
+    /// Remove the queue and return the consumers that were subscribed to it.
+    pub async fn delete_queue(
+        &self,
+        queue_name: &str,
+        user: Option<&str>,
+    ) -> OzResult<Vec<Arc<OzesConnection>>> {
+        self.check(user, Permission::Delete, queue_name, None)?;
+        let queue = self
+            .queues
+            .remove(queue_name)
+            .await
+            .ok_or_else(|| OzesError::QueueNotFound(queue_name.to_string()))?;
+        queue.changed.notify_waiters();
+        log::info!("queue {queue_name} deleted");
+        let mut connections = vec![];
+        for group in queue.groups.read().await.iter() {
+            connections.extend(group.connections().await);
+        }
+        Ok(connections)
+    }
+
+    /// Drop the messages waiting in the queue and return how many they were.
+    pub async fn purge_queue(&self, queue_name: &str, user: Option<&str>) -> OzResult<usize> {
+        self.check(user, Permission::Delete, queue_name, None)?;
+        let queue = self
+            .queues
+            .get(queue_name)
+            .await
+            .ok_or_else(|| OzesError::QueueNotFound(queue_name.to_string()))?;
+        let mut messages = queue.messages.write().await;
+        let purged = messages.len();
+        messages.clear();
+        log::info!("{purged} messages purged from queue {queue_name}");
+        Ok(purged)
+    }
+
+    /// Move the waiting messages, up to `limit`, to the end of another queue
+    /// keeping their order, like to replay a dead letter queue.
+    pub async fn move_messages(
+        &self,
+        from: &str,
+        to: &str,
+        limit: Option<usize>,
+        user: Option<&str>,
+    ) -> OzResult<usize> {
+        if from == to {
+            return Err(OzesError::InvalidCommand(format!(
+                "cannot move messages from {from} to itself"
+            )));
+        }
+        self.check(user, Permission::Consume, from, None)?;
+        self.check(user, Permission::Publish, to, None)?;
+        let source = self
+            .queues
+            .get(from)
+            .await
+            .ok_or_else(|| OzesError::QueueNotFound(from.to_string()))?;
+        let target = match self.queues.get(to).await {
+            Some(target) => target,
+            None => {
+                self.check(user, Permission::Create, to, None)?;
+                self.queues
+                    .get_or_insert_with(to, InnerQueue::default)
+                    .await
+            }
+        };
+        let moved: Vec<Message> = {
+            let mut messages = source.messages.write().await;
+            let count = limit.unwrap_or(messages.len()).min(messages.len());
+            messages.drain(..count).collect()
+        };
+        let count = moved.len();
+        for message in moved {
+            target.push_message(message).await;
+        }
+        log::info!("{count} messages moved from queue {from} to {to}");
+        Ok(count)
+    }
+
     pub(super) async fn get_keys(&self) -> Vec<String> {
         self.queues.get_keys().await
     }
@@ -152,30 +689,72 @@ impl QueueWrapper {
         self.0.read().await.get(key).map(Arc::clone)
     }
 
-    async fn insert(&self, key: &str, value: Arc<InnerQueue>) {
-        self.0.write().await.insert(key.to_string(), value);
+    async fn remove(&self, key: &str) -> Option<Arc<InnerQueue>> {
+        self.0.write().await.remove(key)
     }
 
-    pub async fn get_keys(&self) -> Vec<String> {
-        let queues = self.0.read().await;
-        queues.keys().cloned().collect()
+    /// Insert the queue unless `key` has one already, return if it was
+    /// inserted.
+    async fn try_insert(&self, key: &str, value: Arc<InnerQueue>) -> bool {
+        match self.0.write().await.entry(key.to_string()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                true
+            }
+        }
     }
-}
 
-impl OzesConnections {
-    pub(crate) async fn get(&self, idx: usize) -> Option<Arc<OzesConnection>> {
-        self.0.read().await.get(idx).map(Arc::clone)
+    /// The queue of `key`, inserting a new one under the same lock when there
+    /// is none, so a queue created meanwhile is not replaced.
+    async fn get_or_insert_with(
+        &self,
+        key: &str,
+        new: impl FnOnce() -> InnerQueue,
+    ) -> Arc<InnerQueue> {
+        let mut queues = self.0.write().await;
+        Arc::clone(
+            queues
+                .entry(key.to_string())
+                .or_insert_with(|| Arc::new(new())),
+        )
     }
 
-    pub(crate) async fn remove(&self, idx: usize) {
-        self.0.write().await.remove(idx);
+    pub async fn get_keys(&self) -> Vec<String> {
+        let queues = self.0.read().await;
+        queues.keys().cloned().collect()
     }
+}
 
-    pub(crate) async fn push(&self, connection: Arc<OzesConnection>) {
-        self.0.write().await.push(connection)
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub(crate) async fn is_empty(&self) -> bool {
-        self.0.read().await.is_empty()
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_publishers_of_a_new_queue_keep_every_message() {
+        let message_queue = Arc::new(MQueue::default());
+        let publishers: Vec<_> = (0..20)
+            .map(|_| {
+                let message_queue = Arc::clone(&message_queue);
+                tokio::spawn(async move {
+                    message_queue
+                        .push_message(
+                            Message::new(Bytes::from_static(b"hello")),
+                            Bytes::from_static(b"orders"),
+                            None,
+                        )
+                        .await
+                })
+            })
+            .collect();
+        for publisher in publishers {
+            publisher.await.unwrap().unwrap();
+        }
+        let queue = message_queue.get("orders").await.unwrap();
+        assert_eq!(queue.depth().await, 20);
+        assert!(matches!(
+            message_queue.create_queue("orders", None, None).await,
+            Err(OzesError::QueueAlreadyExists(_))
+        ));
     }
 }
@@ -0,0 +1,284 @@
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    body::Incoming, header, server::conn::http1, service::service_fn, Method, Request, Response,
+    StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+
+use crate::connection::ConnectionType;
+
+use super::Broker;
+
+/// Upper bounds in seconds of the latency histogram buckets.
+const BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Counters not tied to a queue, they are updated from everywhere in the
+/// server so they live in a static like the session ids.
+pub(crate) struct Metrics {
+    timeouts: AtomicU64,
+    parse_errors: AtomicU64,
+}
+
+pub(crate) static METRICS: Metrics = Metrics {
+    timeouts: AtomicU64::new(0),
+    parse_errors: AtomicU64::new(0),
+};
+
+impl Metrics {
+    pub(crate) fn timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+        let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    }
+}
+
+fn header(out: &mut String, name: &str, ty: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {ty}");
+}
+
+/// Metrics in the Prometheus text format.
+async fn render(broker: &Broker) -> String {
+    let mut out = String::new();
+    let mut depth = String::new();
+    let mut published = String::new();
+    let mut delivered = String::new();
+    let mut redelivered = String::new();
+    let mut rejected = String::new();
+    let mut consumers = String::new();
+    let mut ack_latency = String::new();
+    for queue_name in broker.queues.get_keys().await {
+        let queue = match broker.queues.get(&queue_name).await {
+            Some(queue) => queue,
+            None => continue,
+        };
+        let labels = format!("queue=\"{}\"", escape(&queue_name));
+        let _ = writeln!(
+            depth,
+            "ozes_queue_depth{{{labels}}} {}",
+            queue.depth().await
+        );
+        let _ = writeln!(
+            published,
+            "ozes_messages_published_total{{{labels}}} {}",
+            queue.published()
+        );
+        for group in queue.groups().await.iter() {
+            let labels = format!("{labels},group=\"{}\"", escape(group.name()));
+            let _ = writeln!(
+                delivered,
+                "ozes_messages_delivered_total{{{labels}}} {}",
+                group.delivered()
+            );
+            let _ = writeln!(
+                redelivered,
+                "ozes_messages_redelivered_total{{{labels}}} {}",
+                group.redelivered()
+            );
+            let _ = writeln!(
+                rejected,
+                "ozes_messages_rejected_total{{{labels}}} {}",
+                group.rejected()
+            );
+            let _ = writeln!(
+                consumers,
+                "ozes_group_consumers{{{labels}}} {}",
+                group.member_count().await
+            );
+            group
+                .ack_latency()
+                .render("ozes_ack_latency_seconds", &labels, &mut ack_latency);
+        }
+    }
+    header(
+        &mut out,
+        "ozes_queue_depth",
+        "gauge",
+        "Messages waiting in the queue.",
+    );
+    out.push_str(&depth);
+    header(
+        &mut out,
+        "ozes_messages_published_total",
+        "counter",
+        "Messages published in the queue.",
+    );
+    out.push_str(&published);
+    header(
+        &mut out,
+        "ozes_messages_delivered_total",
+        "counter",
+        "Messages acked by a consumer of the group.",
+    );
+    out.push_str(&delivered);
+    header(
+        &mut out,
+        "ozes_messages_redelivered_total",
+        "counter",
+        "Messages sent again after a failed delivery.",
+    );
+    out.push_str(&redelivered);
+    header(
+        &mut out,
+        "ozes_messages_rejected_total",
+        "counter",
+        "Messages dropped by a consumer of the group.",
+    );
+    out.push_str(&rejected);
+    header(
+        &mut out,
+        "ozes_group_consumers",
+        "gauge",
+        "Consumers in the group.",
+    );
+    out.push_str(&consumers);
+    header(
+        &mut out,
+        "ozes_ack_latency_seconds",
+        "histogram",
+        "Time between sending a message and receiving its ack.",
+    );
+    out.push_str(&ack_latency);
+
+    let mut roles: BTreeMap<String, usize> = [
+        ConnectionType::Unknown,
+        ConnectionType::Publisher,
+        ConnectionType::Consumer,
+        ConnectionType::Multiplexed,
+    ]
+    .iter()
+    .map(|ty| (ty.to_string(), 0))
+    .collect();
+    for connection in broker.registry.connections() {
+        *roles.entry(connection.ty().await.to_string()).or_default() += 1;
+    }
+    header(
+        &mut out,
+        "ozes_connections",
+        "gauge",
+        "Live connections by role.",
+    );
+    for (role, count) in roles {
+        let _ = writeln!(out, "ozes_connections{{role=\"{role}\"}} {count}");
+    }
+    header(
+        &mut out,
+        "ozes_timeouts_total",
+        "counter",
+        "Reads and writes that timed out.",
+    );
+    let _ = writeln!(
+        out,
+        "ozes_timeouts_total {}",
+        METRICS.timeouts.load(Ordering::Relaxed)
+    );
+    header(
+        &mut out,
+        "ozes_parse_errors_total",
+        "counter",
+        "Commands that could not be parsed.",
+    );
+    let _ = writeln!(
+        out,
+        "ozes_parse_errors_total {}",
+        METRICS.parse_errors.load(Ordering::Relaxed)
+    );
+    out
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+async fn handle(broker: &Broker, request: Request<Incoming>) -> Response<Full<Bytes>> {
+    let (status, content_type, body) = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => (
+            StatusCode::OK,
+            "text/plain; version=0.0.4",
+            render(broker).await,
+        ),
+        _ => (
+            StatusCode::NOT_FOUND,
+            "text/plain",
+            String::from("not found"),
+        ),
+    };
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+pub(crate) async fn accept_admin(listener: TcpListener, broker: Arc<Broker>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, socket_address)) => {
+                let broker = Arc::clone(&broker);
+                tokio::task::spawn(async move {
+                    let service = service_fn(move |request| {
+                        let broker = Arc::clone(&broker);
+                        async move { Ok::<_, Infallible>(handle(&broker, request).await) }
+                    });
+                    if let Err(error) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        log::error!("error on admin connection {socket_address}: {error}");
+                    }
+                });
+            }
+            Err(e) => log::error!("error on accept admin connection {}", e),
+        }
+    }
+}
@@ -1,35 +1,207 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+    time::{Duration, UNIX_EPOCH},
+};
 
 use bytes::Bytes;
-use ozes_parser::parser::{self, Command};
-use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{net::TcpListener, sync::mpsc, time};
 
 use crate::{
-    connection::{Connection, OzesConnection},
-    server::message_queue::MQueue,
+    connection::ClientInfo,
+    connection::{
+        self, BoxedStream, Connection, ConnectionType, OzesConnection, PeerAddress,
+        ProtocolVersion, WebSocketStream,
+    },
+    server::message_queue::{InFlight, InnerQueue, MQueue},
     BASE_MESSAGE_LEN,
 };
 
-use self::error::{OzResult, OzesError};
+pub use self::{
+    auth::{hash_password, AuthConfig, Credentials},
+    balancer::Strategy,
+    config::{Config, HeartbeatConfig, DEFAULT_PORT},
+    error::ErrorCode,
+    group::{GroupMode, SubscribeOptions},
+    http::HttpConfig,
+    tls::TlsConfig,
+    unix::UnixConfig,
+};
+
+use self::{
+    acl::{Acl, Permission},
+    auth::Authenticator,
+    command::{Command, ExtCommand},
+    error::{OzResult, OzesError},
+    group::Reply,
+    http::Gateway,
+    message::Message,
+    metrics::METRICS,
+    registry::Registry,
+    stats::{BrokerStats, ConnectionInfo, ConnectionsStats, PeekStats},
+    tls::TlsReloader,
+    transaction::Transaction,
+};
 
+mod acl;
+mod auth;
+mod balancer;
+mod command;
+mod config;
 pub(crate) mod error;
 mod group;
+mod http;
+mod message;
 mod message_queue;
+pub(crate) mod metrics;
+mod registry;
+mod stats;
+mod tls;
+mod transaction;
+mod unix;
 
 type Queues = Arc<MQueue>;
 
+/// Time a client has to end the TLS handshake, a client that opens the
+/// connection and sends nothing would hold its task forever.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often new queues are looked for to start delivering their messages.
+const QUEUE_SCAN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// State shared by every connection of the server.
+pub(crate) struct Broker {
+    queues: Queues,
+    registry: Registry,
+    auth: Option<Authenticator>,
+    config: Config,
+    /// Channels opened by multiplexed connections, served by
+    /// `accept_channels`.
+    channels: mpsc::UnboundedSender<OzesConnection>,
+}
+
 pub async fn start_server(port: u16) -> OzResult<()> {
-    let listener = TcpListener::bind(&format!("0.0.0.0:{port}")).await?;
-    log::info!("start listen on port {}", 7656);
-    let queues = Arc::new(MQueue::default());
-    tokio::spawn(process_queues(Arc::clone(&queues)));
+    start_server_with_config(Config {
+        port,
+        ..Default::default()
+    })
+    .await
+}
+
+pub async fn start_server_with_config(config: Config) -> OzResult<()> {
+    // without authentication every connection is anonymous, an ACL would
+    // only give the permissions of `*`
+    if config.acl_path.is_some() && config.auth.is_none() {
+        return Err(OzesError::UnknownError(
+            "an acl file needs authentication, set an auth file or tokens".to_string(),
+        ));
+    }
+    let listener = if config.tcp {
+        let port = config.port;
+        let listener = TcpListener::bind(&format!("0.0.0.0:{port}")).await?;
+        log::info!("start listen on port {}", port);
+        Some(listener)
+    } else {
+        None
+    };
+    #[cfg(unix)]
+    let unix_listener = match &config.unix {
+        Some(unix) => {
+            let listener = unix::bind(unix)?;
+            log::info!("start listen on unix socket {}", unix.path.display());
+            Some(listener)
+        }
+        None => None,
+    };
+    #[cfg(not(unix))]
+    let unix_listener: Option<()> = match &config.unix {
+        Some(_) => {
+            return Err(OzesError::UnknownError(
+                "unix sockets are not supported on this platform".to_string(),
+            ))
+        }
+        None => None,
+    };
+    if listener.is_none()
+        && unix_listener.is_none()
+        && config.tls.is_none()
+        && config.websocket_port.is_none()
+        && config.http.is_none()
+    {
+        return Err(OzesError::UnknownError(
+            "no listener enabled, set a tcp port, a unix socket, tls, websocket or http"
+                .to_string(),
+        ));
+    }
+    let tls = match &config.tls {
+        Some(tls) => Some(Arc::new(TlsReloader::new(tls.clone())?)),
+        None => None,
+    };
+    let auth = match &config.auth {
+        Some(auth) => Some(Authenticator::load(auth)?),
+        None => None,
+    };
+    let acl = match &config.acl_path {
+        Some(acl_path) => Some(Acl::load(acl_path)?),
+        None => None,
+    };
+    let (channels, opened_channels) = mpsc::unbounded_channel();
+    let broker = Arc::new(Broker {
+        queues: Arc::new(MQueue::new(
+            acl,
+            config.max_deliveries,
+            config.dead_letter_suffix.clone(),
+            config.max_queue_messages,
+        )),
+        registry: Registry::default(),
+        auth,
+        config,
+        channels,
+    });
+    tokio::spawn(process_queues(Arc::clone(&broker.queues)));
+    tokio::spawn(accept_channels(opened_channels, Arc::clone(&broker)));
+    if let Some(tls) = tls {
+        let tls_listener = TcpListener::bind(&format!("0.0.0.0:{}", tls.port())).await?;
+        log::info!("start listen with tls on port {}", tls.port());
+        tokio::spawn(Arc::clone(&tls).watch());
+        tokio::spawn(accept_tls(tls_listener, tls, Arc::clone(&broker)));
+    }
+    if let Some(port) = broker.config.websocket_port {
+        let websocket_listener = TcpListener::bind(&format!("0.0.0.0:{port}")).await?;
+        log::info!("start listen with websocket on port {}", port);
+        tokio::spawn(accept_websocket(websocket_listener, Arc::clone(&broker)));
+    }
+    if let Some(http) = &broker.config.http {
+        let gateway = Arc::new(Gateway::new(Arc::clone(&broker), http.clone()));
+        let http_listener = TcpListener::bind(&format!("0.0.0.0:{}", gateway.port())).await?;
+        log::info!("start http gateway on port {}", gateway.port());
+        tokio::spawn(http::accept_http(http_listener, gateway));
+    }
+    if let Some(port) = broker.config.admin_port {
+        let admin_listener = TcpListener::bind(&format!("0.0.0.0:{port}")).await?;
+        log::info!("start admin server on port {}", port);
+        tokio::spawn(metrics::accept_admin(admin_listener, Arc::clone(&broker)));
+    }
+    #[cfg(unix)]
+    if let Some(unix_listener) = unix_listener {
+        tokio::spawn(accept_unix(unix_listener, Arc::clone(&broker)));
+    }
+    match listener {
+        Some(listener) => accept_tcp(listener, broker).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn accept_tcp(listener: TcpListener, broker: Arc<Broker>) -> OzResult<()> {
     loop {
         match listener.accept().await {
             Ok((stream, socket_address)) => {
-                let queue = Arc::clone(&queues);
+                let stream: BoxedStream = Box::new(stream);
                 tokio::task::spawn(handle_connection(
                     OzesConnection::new(stream, socket_address),
-                    queue,
+                    Arc::clone(&broker),
                 ));
             }
             Err(e) => log::error!("error on accept connection {}", e),
@@ -37,26 +209,286 @@ pub async fn start_server(port: u16) -> OzResult<()> {
     }
 }
 
+#[cfg(unix)]
+async fn accept_unix(listener: UnixListener, broker: Arc<Broker>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer_address)) => {
+                let stream: BoxedStream = Box::new(stream);
+                tokio::task::spawn(handle_connection(
+                    OzesConnection::new(stream, peer_address),
+                    Arc::clone(&broker),
+                ));
+            }
+            Err(e) => log::error!("error on accept unix connection {}", e),
+        }
+    }
+}
+
+async fn accept_websocket(listener: TcpListener, broker: Arc<Broker>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, socket_address)) => {
+                let broker = Arc::clone(&broker);
+                tokio::task::spawn(async move {
+                    match tokio_tungstenite::accept_async(stream).await {
+                        Ok(stream) => {
+                            let stream: BoxedStream = Box::new(WebSocketStream::new(stream));
+                            handle_connection(OzesConnection::new(stream, socket_address), broker)
+                                .await
+                        }
+                        Err(error) => {
+                            log::error!(
+                                "websocket handshake with {socket_address} failed: {error}"
+                            );
+                            Ok(())
+                        }
+                    }
+                });
+            }
+            Err(e) => log::error!("error on accept websocket connection {}", e),
+        }
+    }
+}
+
+async fn accept_tls(listener: TcpListener, tls: Arc<TlsReloader>, broker: Arc<Broker>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, socket_address)) => {
+                let tls = Arc::clone(&tls);
+                let broker = Arc::clone(&broker);
+                // handshake out of the accept loop, a slow client cannot
+                // block the others
+                tokio::task::spawn(async move {
+                    match time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let stream: BoxedStream = Box::new(stream);
+                            handle_connection(OzesConnection::new(stream, socket_address), broker)
+                                .await
+                        }
+                        Ok(Err(error)) => {
+                            log::error!("tls handshake with {socket_address} failed: {error}");
+                            Ok(())
+                        }
+                        Err(_) => {
+                            log::error!("tls handshake with {socket_address} time out");
+                            Ok(())
+                        }
+                    }
+                });
+            }
+            Err(e) => log::error!("error on accept tls connection {}", e),
+        }
+    }
+}
+
+/// Serve the channels of the multiplexed connections like any other
+/// connection.
+async fn accept_channels(
+    mut channels: mpsc::UnboundedReceiver<OzesConnection>,
+    broker: Arc<Broker>,
+) {
+    while let Some(channel) = channels.recv().await {
+        tokio::task::spawn(handle_connection(channel, Arc::clone(&broker)));
+    }
+}
+
+/// Start a task delivering the messages of each queue, a queue waiting the
+/// reply of a slow consumer does not hold the others.
 async fn process_queues(queues: Arc<MQueue>) {
+    let mut running: HashMap<String, Weak<InnerQueue>> = HashMap::new();
     loop {
-        let keys = queues.get_keys().await;
-        for key in keys {
-            if let Some(queue) = queues.get(&key).await {
-                queue.process_message().await;
+        running.retain(|_, queue| queue.strong_count() > 0);
+        for key in queues.get_keys().await {
+            let Some(queue) = queues.get(&key).await else {
+                continue;
+            };
+            let started = running
+                .get(&key)
+                .and_then(Weak::upgrade)
+                .is_some_and(|running| Arc::ptr_eq(&running, &queue));
+            if !started {
+                running.insert(key.clone(), Arc::downgrade(&queue));
+                tokio::spawn(process_queue(Arc::clone(&queues), key, queue));
             }
         }
+        time::sleep(QUEUE_SCAN_INTERVAL).await;
     }
 }
 
-async fn handle_connection(ozes_connection: OzesConnection, message_queue: Queues) -> OzResult<()> {
-    log::info!(
-        "handle connection from address {}",
-        ozes_connection.socket_address()
-    );
+/// Take the messages of the queue until it is deleted, each one delivered
+/// by a task of its own, and wait a change of the queue when there is
+/// nothing to take.
+async fn process_queue(queues: Arc<MQueue>, name: String, queue: Arc<InnerQueue>) {
+    let changed = queue.changed();
+    let weak = Arc::downgrade(&queue);
+    drop(queue);
+    loop {
+        let notified = changed.notified();
+        let Some(queue) = weak.upgrade() else {
+            break;
+        };
+        match queue.next_in_flight().await {
+            Some(in_flight) => {
+                tokio::spawn(deliver_in_flight(
+                    Arc::clone(&queues),
+                    name.clone(),
+                    Weak::clone(&weak),
+                    in_flight,
+                ));
+            }
+            None => {
+                drop(queue);
+                notified.await;
+            }
+        }
+    }
+}
+
+/// Deliver the message until every group took it, and send the copies given
+/// up to the dead letter queue. A message waiting a member waits a change of
+/// the queue, one to send again waits its retry time.
+async fn deliver_in_flight(
+    queues: Arc<MQueue>,
+    name: String,
+    queue: Weak<InnerQueue>,
+    mut in_flight: InFlight,
+) {
+    loop {
+        let Some(inner) = queue.upgrade() else {
+            return;
+        };
+        let changed = inner.changed();
+        let notified = changed.notified();
+        let given_up = inner.deliver(&mut in_flight, queues.max_deliveries()).await;
+        if in_flight.is_done() {
+            inner.release(&in_flight);
+        }
+        drop(inner);
+        for message in given_up {
+            queues.dead_letter(&name, message).await;
+        }
+        if in_flight.is_done() {
+            return;
+        }
+        match in_flight.retry_at() {
+            Some(retry_at) => time::sleep_until(retry_at).await,
+            None => notified.await,
+        }
+    }
+}
+
+/// Role the connection took with its first commands.
+enum Role {
+    Consumer,
+    Publisher(Bytes),
+    None,
+}
+
+/// Close the connection and drop it from the registry however its handler
+/// ends, the tasks waiting the close end with it.
+struct ConnectionGuard<'a> {
+    connection: Arc<OzesConnection>,
+    registry: &'a Registry,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.connection.close();
+        self.registry.unregister(self.connection.session_id());
+    }
+}
+
+async fn handle_connection(ozes_connection: OzesConnection, broker: Arc<Broker>) -> OzResult<()> {
+    log::info!("handle connection {}", ozes_connection);
 
     let connection = Arc::new(ozes_connection);
-    let message = connection.read_message().await?;
-    match parser::parse(message) {
+    broker.registry.register(&connection);
+    let _guard = ConnectionGuard {
+        connection: Arc::clone(&connection),
+        registry: &broker.registry,
+    };
+    log::debug!("{} live connections", broker.registry.connections().len());
+    let role = loop {
+        let message = connection.read_message().await?;
+        match command::parse(&message) {
+            Some(Ok(commands))
+                if commands.iter().all(|command| {
+                    command.is_handshake() || command.is_admin() || command.is_multiplexing()
+                }) =>
+            {
+                handle_extended_commands(Ok(commands), Arc::clone(&connection), &broker).await?;
+            }
+            _ if connection.ty().await == ConnectionType::Multiplexed => {
+                connection
+                    .send_error_message(
+                        ErrorCode::InvalidState,
+                        Bytes::from_static(
+                            b"roles are given to the channels of a multiplexed connection",
+                        ),
+                    )
+                    .await?;
+            }
+            Some(commands) => {
+                let subscribed =
+                    handle_extended_commands(commands, Arc::clone(&connection), &broker).await?;
+                break if subscribed {
+                    Role::Consumer
+                } else {
+                    Role::None
+                };
+            }
+            None => break handle_commands(message, Arc::clone(&connection), &broker).await?,
+        }
+    };
+    match role {
+        Role::Consumer => {
+            tokio::task::spawn(handle_heartbeat(
+                Arc::clone(&connection),
+                broker.config.heartbeat,
+            ));
+            handle_consumer(Arc::clone(&connection), Arc::clone(&broker.queues)).await;
+        }
+        Role::Publisher(queue_name) => {
+            handle_publisher(
+                Arc::clone(&connection),
+                Arc::clone(&broker.queues),
+                queue_name,
+                broker.config.heartbeat,
+            )
+            .await?;
+        }
+        Role::None => {}
+    }
+    Ok(())
+}
+
+/// Reject and close the connection when the server require authentication
+/// and the client did not send a valid `AUTH` yet.
+async fn ensure_authenticated(connection: &OzesConnection, broker: &Broker) -> OzResult<()> {
+    if broker.auth.is_none() || connection.user().is_some() {
+        return Ok(());
+    }
+    log::info!("reject unauthenticated connection {}", connection);
+    connection
+        .send_error_message(
+            ErrorCode::Unauthorized,
+            Bytes::from_static(b"authentication required"),
+        )
+        .await?;
+    connection.close();
+    Err(OzesError::Unauthorized)
+}
+
+async fn handle_commands(
+    message: Bytes,
+    connection: Arc<OzesConnection>,
+    broker: &Broker,
+) -> OzResult<Role> {
+    ensure_authenticated(&connection, broker).await?;
+    let message_queue = &broker.queues;
+    let mut role = Role::None;
+    match command::parse_base(message) {
         Ok(commands) => {
             for command in commands {
                 let connection = Arc::clone(&connection);
@@ -65,69 +497,522 @@ async fn handle_connection(ozes_connection: OzesConnection, message_queue: Queue
                         queue_name,
                         group_name,
                     } => {
-                        message_queue
+                        let subscribed = message_queue
                             .add_listener(
                                 connection,
                                 &String::from_utf8_lossy(&queue_name),
                                 &String::from_utf8_lossy(&group_name),
+                                SubscribeOptions::default(),
                             )
-                            .await;
+                            .await?;
+                        if subscribed {
+                            role = Role::Consumer;
+                        }
                     }
                     Command::Publisher { queue_name } => {
-                        tokio::task::spawn(handle_publisher(
-                            connection,
-                            Arc::clone(&message_queue),
-                            queue_name,
-                        ));
+                        role = Role::Publisher(queue_name);
                     }
                     Command::Message { .. } => {
                         connection
-                            .send_error_message(Bytes::from_static(
-                                b"have to be a publisher before send a message",
-                            ))
+                            .send_error_message(
+                                ErrorCode::NotPublisher,
+                                Bytes::from_static(b"have to be a publisher before send a message"),
+                            )
                             .await?;
                     }
                     Command::Ok { .. } => {
                         connection
-                            .send_error_message(Bytes::from_static(
-                                b"ok command is able only when client receive a message",
-                            ))
+                            .send_error_message(
+                                ErrorCode::NotConsumer,
+                                Bytes::from_static(
+                                    b"ok command is able only when client receive a message",
+                                ),
+                            )
                             .await?;
                     }
                     Command::Error { .. } => {
                         connection
-                            .send_error_message(Bytes::from_static(
-                                b"cannot send error on first message",
-                            ))
+                            .send_error_message(
+                                ErrorCode::InvalidState,
+                                Bytes::from_static(b"cannot send error on first message"),
+                            )
                             .await?;
                     }
                 }
             }
         }
         Err(error) => {
-            log::error!(
-                "error with connection {}: {error}",
-                connection.socket_address()
-            );
+            log::error!("error with connection {}: {error}", connection);
+            METRICS.parse_error();
             connection
-                .send_error_message(Bytes::copy_from_slice(error.to_string().as_bytes()))
+                .send_error_message(
+                    ErrorCode::ParseError,
+                    Bytes::copy_from_slice(error.to_string().as_bytes()),
+                )
                 .await?;
+        }
+    }
+    Ok(role)
+}
+
+async fn handle_extended_commands(
+    commands: OzResult<Vec<ExtCommand>>,
+    connection: Arc<OzesConnection>,
+    broker: &Broker,
+) -> OzResult<bool> {
+    let message_queue = &broker.queues;
+    let commands = match commands {
+        Ok(commands) => commands,
+        Err(error) => {
+            log::error!("error with connection {}: {error}", connection);
+            METRICS.parse_error();
+            connection.send_error(&error).await?;
+            return Ok(false);
+        }
+    };
+    let mut subscribed = false;
+    for command in commands {
+        if !command.is_handshake() {
+            ensure_authenticated(&connection, broker).await?;
+        }
+        match command {
+            ExtCommand::Subscribe {
+                queue_name,
+                group_name,
+                options,
+            } => {
+                subscribed |= message_queue
+                    .add_listener(Arc::clone(&connection), &queue_name, &group_name, options)
+                    .await?;
+            }
+            ExtCommand::CreateQueue {
+                queue_name,
+                partitions,
+            } => match message_queue
+                .create_queue(&queue_name, partitions, connection.user().as_deref())
+                .await
+            {
+                Ok(()) => {
+                    connection.ok_queue().await?;
+                }
+                Err(error) => {
+                    connection.send_error(&error).await?;
+                }
+            },
+            ExtCommand::Message { .. } => {
+                connection
+                    .send_error_message(
+                        ErrorCode::NotPublisher,
+                        Bytes::from_static(b"have to be a publisher before send a message"),
+                    )
+                    .await?;
+            }
+            ExtCommand::Ack { .. } | ExtCommand::Nack { .. } | ExtCommand::Reject { .. } => {
+                connection
+                    .send_error_message(
+                        ErrorCode::NotConsumer,
+                        Bytes::from_static(b"have to be a consumer before reply a message"),
+                    )
+                    .await?;
+            }
+            ExtCommand::TxBegin | ExtCommand::TxCommit | ExtCommand::TxRollback => {
+                connection
+                    .send_error_message(
+                        ErrorCode::InvalidState,
+                        Bytes::from_static(
+                            b"have to be a publisher or a consumer to use transactions",
+                        ),
+                    )
+                    .await?;
+            }
+            ExtCommand::Pong => {}
+            ExtCommand::Hello { version } => {
+                if connection.protocol_version() != ProtocolVersion::V1 {
+                    connection
+                        .send_error_message(
+                            ErrorCode::InvalidState,
+                            Bytes::from_static(b"protocol version is already negotiated"),
+                        )
+                        .await?;
+                } else {
+                    // the answer goes in the old protocol, the client
+                    // switches after reading it
+                    connection
+                        .ok_reply("hello", version.to_string().as_bytes())
+                        .await?;
+                    if version == ProtocolVersion::V2 {
+                        connection.upgrade();
+                    }
+                }
+            }
+            ExtCommand::OpenChannel { channel } => {
+                open_channel(&connection, channel, broker).await?;
+            }
+            ExtCommand::Client {
+                name,
+                version,
+                heartbeat,
+            } => {
+                connection.set_client(ClientInfo {
+                    name: Some(name),
+                    version,
+                    heartbeat,
+                });
+                connection.ok_client().await?;
+            }
+            ExtCommand::Auth(credentials) => {
+                // without authentication there is no user to be, taking the
+                // one sent would let anyone act as anyone
+                let Some(auth) = &broker.auth else {
+                    connection
+                        .send_error_message(
+                            ErrorCode::InvalidState,
+                            Bytes::from_static(b"authentication is not enabled"),
+                        )
+                        .await?;
+                    continue;
+                };
+                let address = match connection.peer_address() {
+                    PeerAddress::Tcp(address) => Some(address.ip()),
+                    _ => None,
+                };
+                match auth.authenticate(&credentials, address).await {
+                    Some(user) => {
+                        log::info!("connection {} authenticated as {}", connection, user);
+                        connection.set_user(user);
+                        connection.ok_auth().await?;
+                    }
+                    None => {
+                        log::info!("connection {} sent invalid credentials", connection);
+                        connection
+                            .send_error_message(
+                                ErrorCode::Unauthorized,
+                                Bytes::from_static(b"invalid credentials"),
+                            )
+                            .await?;
+                        connection.close();
+                        return Err(OzesError::Unauthorized);
+                    }
+                }
+            }
+            command @ (ExtCommand::Stats { .. }
+            | ExtCommand::Connections
+            | ExtCommand::DeleteQueue { .. }
+            | ExtCommand::PurgeQueue { .. }
+            | ExtCommand::MoveMessages { .. }
+            | ExtCommand::Kick { .. }
+            | ExtCommand::Peek { .. }) => {
+                handle_admin_command(command, &connection, broker).await?;
+            }
+        }
+    }
+    Ok(subscribed)
+}
+
+/// Open a channel of the connection and hand it to `accept_channels`, the
+/// channel starts with the client and user of the connection.
+async fn open_channel(
+    connection: &Arc<OzesConnection>,
+    channel: u16,
+    broker: &Broker,
+) -> OzResult<()> {
+    if connection.protocol_version() != ProtocolVersion::V2 {
+        connection
+            .send_error_message(
+                ErrorCode::InvalidState,
+                Bytes::from_static(b"channels need the protocol version 2"),
+            )
+            .await?;
+        return Ok(());
+    }
+    if connection.has_channel(channel) {
+        connection
+            .send_error_message(
+                ErrorCode::InvalidState,
+                Bytes::from(format!("channel {channel} is already open")),
+            )
+            .await?;
+        return Ok(());
+    }
+    if connection.channel_count() >= broker.config.max_channels {
+        connection
+            .send_error_message(
+                ErrorCode::InvalidState,
+                Bytes::from(format!(
+                    "connection has the maximum of {} open channels",
+                    broker.config.max_channels
+                )),
+            )
+            .await?;
+        return Ok(());
+    }
+    let multiplexed = connection.ty().await == ConnectionType::Multiplexed;
+    let channel_connection = connection::open_channel(connection, channel);
+    channel_connection.set_client(connection.client());
+    if let Some(user) = connection.user() {
+        channel_connection.set_user(user);
+    }
+    connection.ok_channel().await?;
+    if !multiplexed {
+        tokio::task::spawn(handle_heartbeat(
+            Arc::clone(connection),
+            broker.config.heartbeat,
+        ));
+    }
+    log::info!("open channel {} of {}", channel, connection);
+    let _ = broker.channels.send(channel_connection);
+    Ok(())
+}
+
+/// Read everything a consumer send until the connection is lost.
+async fn handle_consumer(connection: Arc<OzesConnection>, message_queue: Queues) {
+    let mut transaction = None;
+    loop {
+        let result = match connection.read().await {
+            Ok(message) => {
+                process_consumer_message(message, &mut transaction, &connection, &message_queue)
+                    .await
+            }
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            log::info!("consumer {} is gone: {error}", connection);
+            connection.close();
+            message_queue.remove_connection(&connection).await;
+            break;
+        }
+    }
+}
+
+/// Replies to delivered messages are routed to the group waiting them and
+/// heartbeats only keep the connection alive. In a transaction the reply is
+/// held until the commit, and messages are published with `MESSAGE TO`.
+async fn process_consumer_message(
+    message: Bytes,
+    transaction: &mut Option<Transaction>,
+    connection: &Arc<OzesConnection>,
+    message_queue: &Queues,
+) -> OzResult<()> {
+    let commands = match command::parse(&message) {
+        // a `PONG` can arrive in the same read as a reply, each statement is
+        // routed by itself
+        Some(Ok(commands)) => commands,
+        None if transaction.is_some() => match command::parse_base(message) {
+            Ok(commands) => match &commands[..] {
+                [Command::Ok { len }] => vec![ExtCommand::Ack { len: *len }],
+                _ => {
+                    connection
+                        .send_error_message(
+                            ErrorCode::InvalidState,
+                            Bytes::from_static(b"cannot be sent by a consumer"),
+                        )
+                        .await?;
+                    return Ok(());
+                }
+            },
+            Err(error) => {
+                METRICS.parse_error();
+                connection
+                    .send_error_message(
+                        ErrorCode::ParseError,
+                        Bytes::copy_from_slice(error.to_string().as_bytes()),
+                    )
+                    .await?;
+                return Ok(());
+            }
+        },
+        _ => {
+            connection.push_reply(message);
             return Ok(());
         }
+    };
+    for command in commands {
+        match command {
+            ExtCommand::Ack { len } => hold_reply(Reply::Ack, len, transaction, connection).await?,
+            ExtCommand::Nack { len } => {
+                hold_reply(Reply::Nack, len, transaction, connection).await?
+            }
+            ExtCommand::Reject { len } => {
+                hold_reply(Reply::Reject, len, transaction, connection).await?
+            }
+            ExtCommand::Message {
+                message,
+                key,
+                headers,
+                queue_name: Some(queue_name),
+            } if transaction.is_some() => {
+                process_message_command(
+                    Message::with_key(message, key).with_headers(headers),
+                    Bytes::from(queue_name),
+                    Arc::clone(connection),
+                    Arc::clone(message_queue),
+                    transaction,
+                )
+                .await?;
+            }
+            ExtCommand::Message { .. } => {
+                connection
+                    .send_error_message(
+                        ErrorCode::NotPublisher,
+                        Bytes::from_static(
+                            b"consumers send messages with MESSAGE TO in a transaction",
+                        ),
+                    )
+                    .await?;
+            }
+            command if command.is_transaction() => {
+                process_transaction_command(command, transaction, connection, message_queue)
+                    .await?;
+            }
+            ExtCommand::Pong => {}
+            _ => {
+                connection
+                    .send_error_message(
+                        ErrorCode::InvalidState,
+                        Bytes::from_static(b"cannot be sent by a consumer"),
+                    )
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hold the reply to the delivery in the transaction, or give it to the
+/// group waiting it.
+async fn hold_reply(
+    reply: Reply,
+    len: usize,
+    transaction: &mut Option<Transaction>,
+    connection: &OzesConnection,
+) -> OzResult<()> {
+    match transaction {
+        Some(transaction) if transaction.is_answered() => {
+            connection
+                .send_error_message(
+                    ErrorCode::InvalidState,
+                    Bytes::from_static(b"the delivery is already answered in the transaction"),
+                )
+                .await?;
+        }
+        Some(transaction) => transaction.answer(reply, len, connection.delivery()),
+        None => connection.push_reply(reply.statement(len)),
     }
     Ok(())
 }
 
+/// `TX BEGIN`, `TX COMMIT` or `TX ROLLBACK`, the transaction is replaced by
+/// `None` once committed or rolled back.
+async fn process_transaction_command(
+    command: ExtCommand,
+    transaction: &mut Option<Transaction>,
+    connection: &OzesConnection,
+    message_queue: &Queues,
+) -> OzResult<()> {
+    if let ExtCommand::TxBegin = command {
+        if transaction.is_some() {
+            connection
+                .send_error_message(
+                    ErrorCode::InvalidState,
+                    Bytes::from_static(b"a transaction is already begun"),
+                )
+                .await?;
+        } else {
+            *transaction = Some(Transaction::default());
+            connection.ok_reply("begin", b"").await?;
+        }
+        return Ok(());
+    }
+    let Some(current) = transaction.take() else {
+        connection
+            .send_error_message(
+                ErrorCode::InvalidState,
+                Bytes::from_static(b"no transaction is begun"),
+            )
+            .await?;
+        return Ok(());
+    };
+    if let ExtCommand::TxRollback = command {
+        current.rollback(connection);
+        connection.ok_reply("rollback", b"").await?;
+        return Ok(());
+    }
+    match current.commit(connection, message_queue).await {
+        Ok(()) => {
+            connection.ok_reply("commit", b"").await?;
+        }
+        Err(OzesError::TimeOut) => {
+            connection
+                .send_error_message(
+                    ErrorCode::Timeout,
+                    Bytes::from_static(
+                        b"the ack timeout expired before the commit, the transaction is rolled back",
+                    ),
+                )
+                .await?;
+        }
+        Err(error) => {
+            connection.send_error(&error).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Ping the connection when it is idle and close it when it stop answering,
+/// closing it wakes up the reads waiting on it. Connections that did not ask
+/// for heartbeats are never pinged nor dropped for being idle.
+async fn handle_heartbeat(connection: Arc<OzesConnection>, heartbeat: HeartbeatConfig) {
+    let mut interval = time::interval(heartbeat.interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = connection.closed() => break,
+        }
+        if !connection.heartbeat() {
+            continue;
+        }
+        let idle_time = connection.idle_time();
+        if idle_time >= heartbeat.timeout() {
+            log::info!(
+                "connection {} missed {} heartbeats, dropping",
+                connection,
+                heartbeat.miss_threshold
+            );
+            connection.close();
+            break;
+        }
+        if idle_time >= heartbeat.interval && connection.ping().await.is_err() {
+            connection.close();
+            break;
+        }
+    }
+}
+
 async fn handle_publisher(
     connection: Arc<OzesConnection>,
     message_queue: Queues,
     queue_name: Bytes,
+    heartbeat: HeartbeatConfig,
 ) -> OzResult<()> {
     if connection.ok_publisher().await.is_ok() {
-        log::info!("handle publisher: {}", connection.socket_address());
+        log::info!("handle publisher: {}", connection);
+        tokio::task::spawn(handle_heartbeat(Arc::clone(&connection), heartbeat));
+        // rolled back when the connection is lost
+        let mut transaction = None;
         loop {
             let message = connection.read_message().await?;
-            let commands = parser::parse(message);
+            if let Some(commands) = command::parse(&message) {
+                process_extended_commands(
+                    commands,
+                    queue_name.clone(),
+                    Arc::clone(&connection),
+                    Arc::clone(&message_queue),
+                    &mut transaction,
+                )
+                .await?;
+                continue;
+            }
+            let commands = command::parse_base(message);
             match commands {
                 Ok(commands) => {
                     process_commands(
@@ -135,12 +1020,17 @@ async fn handle_publisher(
                         queue_name.clone(),
                         Arc::clone(&connection),
                         Arc::clone(&message_queue),
+                        &mut transaction,
                     )
                     .await?;
                 }
                 Err(error) => {
+                    METRICS.parse_error();
                     connection
-                        .send_error_message(Bytes::copy_from_slice(error.to_string().as_bytes()))
+                        .send_error_message(
+                            ErrorCode::ParseError,
+                            Bytes::copy_from_slice(error.to_string().as_bytes()),
+                        )
                         .await?;
                 }
             }
@@ -154,6 +1044,7 @@ async fn process_commands(
     queue_name: Bytes,
     publisher: Arc<OzesConnection>,
     message_queue: Queues,
+    transaction: &mut Option<Transaction>,
 ) -> OzResult<()> {
     for command in commands {
         match command {
@@ -172,54 +1063,376 @@ async fn process_commands(
                 }
 
                 process_message_command(
-                    message,
+                    Message::new(message),
                     queue_name.clone(),
                     Arc::clone(&publisher),
                     Arc::clone(&message_queue),
+                    transaction,
                 )
                 .await?;
             }
             Command::Subscriber { .. } => {
                 publisher
-                    .send_error_message(Bytes::from_static(b"cannot subscribe when is a publisher"))
+                    .send_error_message(
+                        ErrorCode::InvalidState,
+                        Bytes::from_static(b"cannot subscribe when is a publisher"),
+                    )
                     .await?;
             }
             Command::Publisher { .. } => {
                 publisher
-                    .send_error_message(Bytes::from_static(
-                        b"cannot change queue when already is a publisher",
-                    ))
+                    .send_error_message(
+                        ErrorCode::InvalidState,
+                        Bytes::from_static(b"cannot change queue when already is a publisher"),
+                    )
                     .await?;
             }
             Command::Ok { .. } => {
                 publisher
-                    .send_error_message(Bytes::from_static(
-                        b"ok command is only able to subscribers when receive message",
-                    ))
+                    .send_error_message(
+                        ErrorCode::NotConsumer,
+                        Bytes::from_static(
+                            b"ok command is only able to subscribers when receive message",
+                        ),
+                    )
                     .await?;
             }
             Command::Error { message } => Err(OzesError::UnknownError(format!(
                 "error with connection {} dropping with message {:?}",
-                publisher.socket_address(),
-                message
+                publisher, message
             )))?,
         }
     }
     Ok(())
 }
 
+async fn process_extended_commands(
+    commands: OzResult<Vec<ExtCommand>>,
+    queue_name: Bytes,
+    publisher: Arc<OzesConnection>,
+    message_queue: Queues,
+    transaction: &mut Option<Transaction>,
+) -> OzResult<()> {
+    let commands = match commands {
+        Ok(commands) => commands,
+        Err(error) => {
+            METRICS.parse_error();
+            publisher.send_error(&error).await?;
+            return Ok(());
+        }
+    };
+    for command in commands {
+        match command {
+            ExtCommand::Message {
+                message,
+                key,
+                headers,
+                queue_name: to,
+            } => {
+                process_message_command(
+                    Message::with_key(message, key).with_headers(headers),
+                    to.map(Bytes::from).unwrap_or_else(|| queue_name.clone()),
+                    Arc::clone(&publisher),
+                    Arc::clone(&message_queue),
+                    transaction,
+                )
+                .await?;
+            }
+            ExtCommand::Subscribe { .. } => {
+                publisher
+                    .send_error_message(
+                        ErrorCode::InvalidState,
+                        Bytes::from_static(b"cannot subscribe when is a publisher"),
+                    )
+                    .await?;
+            }
+            ExtCommand::CreateQueue { .. } => {
+                publisher
+                    .send_error_message(
+                        ErrorCode::InvalidState,
+                        Bytes::from_static(b"cannot create queue when already is a publisher"),
+                    )
+                    .await?;
+            }
+            ExtCommand::Ack { .. } | ExtCommand::Nack { .. } | ExtCommand::Reject { .. } => {
+                publisher
+                    .send_error_message(
+                        ErrorCode::NotConsumer,
+                        Bytes::from_static(b"have to be a consumer before reply a message"),
+                    )
+                    .await?;
+            }
+            command @ (ExtCommand::TxBegin | ExtCommand::TxCommit | ExtCommand::TxRollback) => {
+                process_transaction_command(command, transaction, &publisher, &message_queue)
+                    .await?;
+            }
+            ExtCommand::Pong => {}
+            ExtCommand::Hello { .. } | ExtCommand::Client { .. } | ExtCommand::Auth(_) => {
+                publisher
+                    .send_error_message(
+                        ErrorCode::InvalidState,
+                        Bytes::from_static(
+                            b"hello, client and auth have to be sent before publisher",
+                        ),
+                    )
+                    .await?;
+            }
+            ExtCommand::Stats { queue_name } => {
+                let user = publisher.user();
+                send_stats(
+                    &publisher,
+                    &message_queue,
+                    queue_name.as_deref(),
+                    user.as_deref(),
+                )
+                .await?;
+            }
+            ExtCommand::Connections
+            | ExtCommand::DeleteQueue { .. }
+            | ExtCommand::PurgeQueue { .. }
+            | ExtCommand::MoveMessages { .. }
+            | ExtCommand::Kick { .. }
+            | ExtCommand::Peek { .. } => {
+                publisher
+                    .send_error_message(
+                        ErrorCode::InvalidState,
+                        Bytes::from_static(b"admin commands have to be sent before publisher"),
+                    )
+                    .await?;
+            }
+            ExtCommand::OpenChannel { .. } => {
+                publisher
+                    .send_error_message(
+                        ErrorCode::InvalidState,
+                        Bytes::from_static(b"cannot open a channel when already is a publisher"),
+                    )
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run a command of `ozesctl` or other admin tools, failures are replied to
+/// the client without closing the connection.
+async fn handle_admin_command(
+    command: ExtCommand,
+    connection: &OzesConnection,
+    broker: &Broker,
+) -> OzResult<()> {
+    if let ExtCommand::Stats { queue_name } = &command {
+        let user = connection.user();
+        return send_stats(
+            connection,
+            &broker.queues,
+            queue_name.as_deref(),
+            user.as_deref(),
+        )
+        .await;
+    }
+    if let Err(error) = check_admin(connection, broker) {
+        connection.send_error(&error).await?;
+        return Ok(());
+    }
+    let user = connection.user();
+    let user = user.as_deref();
+    let reply: OzResult<(&str, Vec<u8>)> = match command {
+        ExtCommand::Connections => match broker.queues.check(user, Permission::Admin, "*", None) {
+            Ok(()) => {
+                let mut connections = vec![];
+                for connection in broker.registry.connections() {
+                    connections.push(connection_info(&connection).await);
+                }
+                serde_json::to_vec(&ConnectionsStats { connections })
+                    .map(|body| ("connections", body))
+                    .map_err(|error| OzesError::UnknownError(error.to_string()))
+            }
+            Err(error) => Err(error),
+        },
+        ExtCommand::DeleteQueue { queue_name } => {
+            match broker.queues.delete_queue(&queue_name, user).await {
+                Ok(consumers) => {
+                    // consumers subscribe again and recreate the queue if they
+                    // still want it
+                    for consumer in consumers {
+                        consumer.close();
+                    }
+                    Ok(("deleted", vec![]))
+                }
+                Err(error) => Err(error),
+            }
+        }
+        ExtCommand::PurgeQueue { queue_name } => broker
+            .queues
+            .purge_queue(&queue_name, user)
+            .await
+            .map(|purged| ("purged", purged.to_string().into_bytes())),
+        ExtCommand::MoveMessages { from, to, limit } => broker
+            .queues
+            .move_messages(&from, &to, limit, user)
+            .await
+            .map(|moved| ("moved", moved.to_string().into_bytes())),
+        ExtCommand::Kick { session_id } => {
+            match broker.queues.check(user, Permission::Admin, "*", None) {
+                Ok(()) => match broker.registry.get(session_id) {
+                    Some(kicked) => {
+                        log::info!("connection {} kicked by {}", kicked, connection);
+                        kicked.close();
+                        Ok(("kicked", vec![]))
+                    }
+                    None => Err(OzesError::UnknownError(format!(
+                        "session {session_id} not found"
+                    ))),
+                },
+                Err(error) => Err(error),
+            }
+        }
+        ExtCommand::Peek {
+            queue_name,
+            offset,
+            count,
+        } => match broker.queues.peek(&queue_name, offset, count, user).await {
+            Ok(messages) => serde_json::to_vec(&PeekStats {
+                queue: queue_name,
+                messages,
+            })
+            .map(|body| ("peek", body))
+            .map_err(|error| OzesError::UnknownError(error.to_string())),
+            Err(error) => Err(error),
+        },
+        _ => Err(OzesError::InvalidCommand(String::from(
+            "not an admin command",
+        ))),
+    };
+    match reply {
+        Ok((reply @ ("connections" | "peek"), body)) => {
+            connection.ok_document(reply, &body).await?;
+        }
+        Ok((reply, body)) => {
+            connection.ok_reply(reply, &body).await?;
+        }
+        Err(error) => {
+            connection.send_error(&error).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Without ACL the admin commands are for the users of `admin_users` and
+/// the clients of the Unix socket, with it each command checks its
+/// permissions.
+fn check_admin(connection: &OzesConnection, broker: &Broker) -> OzResult<()> {
+    if broker.queues.has_acl() || matches!(connection.peer_address(), PeerAddress::Unix(_)) {
+        return Ok(());
+    }
+    match connection.user() {
+        Some(user) if broker.config.admin_users.contains(&user) => Ok(()),
+        _ => Err(OzesError::AccessDenied(String::from(
+            "admin commands are for admin users and the unix socket",
+        ))),
+    }
+}
+
+async fn connection_info(connection: &OzesConnection) -> ConnectionInfo {
+    let client = connection.client();
+    let stats = connection.stats();
+    ConnectionInfo {
+        session_id: connection.session_id(),
+        address: connection.peer_address().to_string(),
+        role: connection.ty().await.to_string(),
+        name: client.name,
+        version: client.version,
+        protocol: connection.protocol_version().to_string(),
+        user: connection.user(),
+        connected_at: connection
+            .connected_at()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default(),
+        messages_in: stats.messages_in(),
+        messages_out: stats.messages_out(),
+        bytes_in: stats.bytes_in(),
+        bytes_out: stats.bytes_out(),
+    }
+}
+
+/// Reply `STATS` with `ok stats` followed by the stats as JSON.
+async fn send_stats(
+    connection: &OzesConnection,
+    message_queue: &MQueue,
+    queue_name: Option<&str>,
+    user: Option<&str>,
+) -> OzResult<()> {
+    match message_queue.stats(queue_name, user).await {
+        Ok(queues) => {
+            let stats = serde_json::to_vec(&BrokerStats { queues })
+                .map_err(|error| OzesError::UnknownError(error.to_string()))?;
+            connection.ok_document("stats", &stats).await?;
+        }
+        Err(error) => {
+            connection.send_error(&error).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Publish the message, or hold it until the commit when in a transaction.
 async fn process_message_command(
-    message: Bytes,
+    message: Message,
     queue_name: Bytes,
     publisher: Arc<OzesConnection>,
     message_queue: Queues,
+    transaction: &mut Option<Transaction>,
 ) -> OzResult<()> {
+    // consumers that do not accept the compression need the broker to
+    // decompress the payload
+    if let Some(compression) = message
+        .compression()
+        .filter(|compression| !compression.is_supported())
+    {
+        publisher
+            .send_error_message(
+                ErrorCode::UnsupportedEncoding,
+                Bytes::from(format!("{compression} compression is not enabled")),
+            )
+            .await?;
+        return Ok(());
+    }
+    let user = publisher.user();
+    if let Some(transaction) = transaction {
+        // failures of the commit would leave it half done, the permissions
+        // are checked before
+        match message_queue
+            .check_publish(user.as_deref(), &String::from_utf8_lossy(&queue_name))
+            .await
+        {
+            Ok(()) => {
+                transaction.publish(message, queue_name);
+                publisher.ok_message().await?;
+            }
+            Err(error) => {
+                publisher.send_error(&error).await?;
+            }
+        }
+        return Ok(());
+    }
     log::info!(
         "send {} bytes to {} queue",
-        message.len(),
+        message.payload().len(),
         String::from_utf8_lossy(&queue_name)
     );
-    publisher.ok_message().await?;
-    message_queue.push_message(message, queue_name).await?;
+    match message_queue
+        .push_message(message, queue_name, user.as_deref())
+        .await
+    {
+        Ok(()) => {
+            publisher.ok_message().await?;
+            publisher.stats().message_in();
+        }
+        Err(error @ (OzesError::AccessDenied(_) | OzesError::QueueFull(_))) => {
+            publisher.send_error(&error).await?;
+        }
+        Err(error) => Err(error)?,
+    }
     Ok(())
 }
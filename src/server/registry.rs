@@ -0,0 +1,45 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock, Weak},
+};
+
+use crate::connection::OzesConnection;
+
+/// Live connections of the server by session id.
+///
+/// The registry only keep weak references, connections are dropped from it
+/// once closed or when nothing else use them.
+#[derive(Default)]
+pub struct Registry(RwLock<HashMap<u64, Weak<OzesConnection>>>);
+
+impl Registry {
+    pub fn register(&self, connection: &Arc<OzesConnection>) {
+        self.0
+            .write()
+            .unwrap()
+            .insert(connection.session_id(), Arc::downgrade(connection));
+    }
+
+    pub fn get(&self, session_id: u64) -> Option<Arc<OzesConnection>> {
+        self.0
+            .read()
+            .unwrap()
+            .get(&session_id)
+            .and_then(Weak::upgrade)
+    }
+
+    pub fn unregister(&self, session_id: u64) {
+        self.0.write().unwrap().remove(&session_id);
+    }
+
+    pub fn connections(&self) -> Vec<Arc<OzesConnection>> {
+        let mut connections = self.0.write().unwrap();
+        connections.retain(
+            |_, connection| matches!(connection.upgrade(), Some(connection) if connection.is_alive()),
+        );
+        let mut live: Vec<Arc<OzesConnection>> =
+            connections.values().filter_map(Weak::upgrade).collect();
+        live.sort_by_key(|connection| connection.session_id());
+        live
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// Reply of `STATS`, sent as JSON.
+#[derive(Serialize)]
+pub(crate) struct BrokerStats {
+    pub queues: Vec<QueueStats>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct QueueStats {
+    pub name: String,
+    pub partitions: Option<usize>,
+    /// Messages waiting to be delivered.
+    pub messages: usize,
+    pub published: u64,
+    pub groups: Vec<GroupStats>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct GroupStats {
+    pub name: String,
+    pub mode: String,
+    pub strategy: String,
+    pub delivered: u64,
+    pub redelivered: u64,
+    pub rejected: u64,
+    pub members: usize,
+    pub consumers: Vec<ConsumerStats>,
+}
+
+/// Reply of `PEEK`, sent as JSON.
+#[derive(Serialize)]
+pub(crate) struct PeekStats {
+    pub queue: String,
+    pub messages: Vec<PeekedMessage>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PeekedMessage {
+    /// Position in the queue, `0` is the next message to be delivered.
+    pub offset: usize,
+    pub size: usize,
+    pub key: Option<String>,
+    pub partition: Option<usize>,
+    /// Unix time in milliseconds.
+    pub published_at: u64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Base64 of the message.
+    pub payload: String,
+}
+
+/// Reply of `CONNECTIONS`, sent as JSON.
+#[derive(Serialize)]
+pub(crate) struct ConnectionsStats {
+    pub connections: Vec<ConnectionInfo>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ConnectionInfo {
+    pub session_id: u64,
+    pub address: String,
+    pub role: String,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    /// Protocol version negotiated with `HELLO`.
+    pub protocol: String,
+    pub user: Option<String>,
+    /// Unix time in seconds.
+    pub connected_at: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ConsumerStats {
+    pub session_id: u64,
+    pub address: String,
+    pub name: Option<String>,
+    pub weight: u32,
+    pub outstanding: usize,
+}
@@ -0,0 +1,128 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{net::TcpStream, sync::RwLock, time};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use super::error::{OzResult, OzesError};
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub port: u16,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// CA used to verify the client certificates, when set clients without a
+    /// valid certificate are refused.
+    pub client_ca_path: Option<PathBuf>,
+    /// How often the PEM files are checked for changes.
+    pub reload_interval: Duration,
+}
+
+/// Acceptor that is rebuilt when the certificate files change on disk, so
+/// certificates can be renewed without restart the server.
+pub(crate) struct TlsReloader {
+    config: TlsConfig,
+    acceptor: RwLock<TlsAcceptor>,
+    modified: StdMutex<Option<SystemTime>>,
+}
+
+impl TlsReloader {
+    pub(crate) fn new(config: TlsConfig) -> OzResult<Self> {
+        let acceptor = TlsAcceptor::from(Arc::new(load_server_config(&config)?));
+        let modified = last_modified(&config);
+        Ok(Self {
+            config,
+            acceptor: RwLock::new(acceptor),
+            modified: StdMutex::new(modified),
+        })
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        self.config.port
+    }
+
+    pub(crate) async fn accept(&self, stream: TcpStream) -> OzResult<TlsStream<TcpStream>> {
+        let acceptor = self.acceptor.read().await.clone();
+        Ok(acceptor.accept(stream).await?)
+    }
+
+    /// Keep the current certificates when the new files are invalid.
+    pub(crate) async fn watch(self: Arc<Self>) {
+        let mut interval = time::interval(self.config.reload_interval);
+        loop {
+            interval.tick().await;
+            let modified = last_modified(&self.config);
+            if modified == *self.modified.lock().unwrap() {
+                continue;
+            }
+            match load_server_config(&self.config) {
+                Ok(server_config) => {
+                    *self.acceptor.write().await = TlsAcceptor::from(Arc::new(server_config));
+                    *self.modified.lock().unwrap() = modified;
+                    log::info!("tls certificates reloaded");
+                }
+                Err(error) => log::error!("error on reload tls certificates: {}", error),
+            }
+        }
+    }
+}
+
+fn last_modified(config: &TlsConfig) -> Option<SystemTime> {
+    [
+        Some(&config.cert_path),
+        Some(&config.key_path),
+        config.client_ca_path.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .filter_map(|path| {
+        path.metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    })
+    .max()
+}
+
+fn load_certs(path: &Path) -> OzResult<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?)
+}
+
+fn load_key(path: &Path) -> OzResult<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| OzesError::Tls(format!("no private key in {}", path.display())))
+}
+
+fn load_server_config(config: &TlsConfig) -> OzResult<ServerConfig> {
+    let certs = load_certs(&config.cert_path)?;
+    let key = load_key(&config.key_path)?;
+    let builder = ServerConfig::builder();
+    let builder = match &config.client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca_path)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|error| OzesError::Tls(error.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(builder.with_single_cert(certs, key)?)
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use crate::connection::OzesConnection;
+
+use super::{
+    error::{OzResult, OzesError},
+    group::Reply,
+    message::Message,
+    message_queue::MQueue,
+};
+
+/// What a connection sends between `TX BEGIN` and `TX COMMIT`, held until
+/// the commit. Dropping it, when the connection is lost, is a rollback.
+#[derive(Default)]
+pub(crate) struct Transaction {
+    /// Messages and the queue they go to.
+    messages: Vec<(Message, Bytes)>,
+    /// Answer of a consumer, the len of the delivery it answers and its
+    /// number in the connection. The group does not deliver other messages
+    /// to the consumer meanwhile.
+    answer: Option<(Reply, usize, u64)>,
+}
+
+impl Transaction {
+    pub(crate) fn publish(&mut self, message: Message, queue_name: Bytes) {
+        self.messages.push((message, queue_name));
+    }
+
+    pub(crate) fn is_answered(&self) -> bool {
+        self.answer.is_some()
+    }
+
+    pub(crate) fn answer(&mut self, reply: Reply, len: usize, delivery: u64) {
+        self.answer = Some((reply, len, delivery));
+    }
+
+    /// Publish the messages and then give the answer to the group. Every
+    /// queue is checked before anything is published, and a check or
+    /// publish that fails rolls the transaction back and nacks the delivery.
+    /// When the group stopped waiting the answer, the ack timeout expired
+    /// and the message was delivered again, nothing is published and the
+    /// error is `OzesError::TimeOut`.
+    pub(crate) async fn commit(
+        self,
+        connection: &OzesConnection,
+        message_queue: &MQueue,
+    ) -> OzResult<()> {
+        let Self { messages, answer } = self;
+        let nack = || {
+            if let Some((_, len, delivery)) = answer {
+                connection.push_awaited_reply(delivery, Reply::Nack.statement(len));
+            }
+        };
+        let user = connection.user();
+        let mut counts: HashMap<&Bytes, usize> = HashMap::new();
+        for (_, queue_name) in &messages {
+            *counts.entry(queue_name).or_default() += 1;
+        }
+        for (queue_name, count) in counts {
+            let queue_name = String::from_utf8_lossy(queue_name);
+            let checked = match message_queue
+                .check_publish(user.as_deref(), &queue_name)
+                .await
+            {
+                Ok(()) => message_queue.check_room(&queue_name, count).await,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = checked {
+                nack();
+                return Err(error);
+            }
+        }
+        if let Some((_, _, delivery)) = answer {
+            if !connection.awaits_reply(delivery) {
+                return Err(OzesError::TimeOut);
+            }
+        }
+        for (message, queue_name) in messages {
+            if let Err(error) = message_queue
+                .push_unbounded(message, queue_name, user.as_deref())
+                .await
+            {
+                nack();
+                return Err(error);
+            }
+            connection.stats().message_in();
+        }
+        if let Some((reply, len, delivery)) = answer {
+            if !connection.push_awaited_reply(delivery, reply.statement(len)) {
+                log::warn!(
+                    "ack timeout of {} expired while committing, the delivery is sent again",
+                    connection
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the messages and deliver again now the message answered in the
+    /// transaction.
+    pub(crate) fn rollback(self, connection: &OzesConnection) {
+        if let Some((_, len, delivery)) = self.answer {
+            connection.push_awaited_reply(delivery, Reply::Nack.statement(len));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        connection::{BoxedStream, PeerAddress},
+        server::{acl::Acl, config::DEFAULT_MAX_DELIVERIES},
+    };
+
+    fn connection() -> OzesConnection {
+        let (stream, _) = tokio::io::duplex(64);
+        let connection = OzesConnection::new(
+            Box::new(stream) as BoxedStream,
+            PeerAddress::Internal(String::from("test")),
+        );
+        connection.set_user(String::from("alice"));
+        connection
+    }
+
+    fn transaction(queues: &[&'static str]) -> Transaction {
+        let mut transaction = Transaction::default();
+        for queue_name in queues {
+            transaction.publish(
+                Message::new(Bytes::from_static(b"hello")),
+                Bytes::from_static(queue_name.as_bytes()),
+            );
+        }
+        transaction
+    }
+
+    async fn depth(message_queue: &MQueue, queue_name: &str) -> usize {
+        match message_queue.get(queue_name).await {
+            Some(queue) => queue.depth().await,
+            None => 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn commit_publishes_every_message() {
+        let message_queue = MQueue::default();
+        let connection = connection();
+        transaction(&["orders", "orders", "invoices"])
+            .commit(&connection, &message_queue)
+            .await
+            .unwrap();
+        assert_eq!(depth(&message_queue, "orders").await, 2);
+        assert_eq!(depth(&message_queue, "invoices").await, 1);
+    }
+
+    #[tokio::test]
+    async fn rollback_drops_the_messages() {
+        let message_queue = MQueue::default();
+        transaction(&["orders"]).rollback(&connection());
+        assert_eq!(depth(&message_queue, "orders").await, 0);
+    }
+
+    #[tokio::test]
+    async fn denied_queue_publishes_nothing() {
+        let path = std::env::temp_dir().join(format!("ozes-tx-acl-{}", std::process::id()));
+        fs::write(&path, "alice publish,create orders\n").unwrap();
+        let acl = Acl::load(&path);
+        let _ = fs::remove_file(&path);
+        let message_queue = MQueue::new(Some(acl.unwrap()), DEFAULT_MAX_DELIVERIES, None, None);
+        let result = transaction(&["orders", "invoices"])
+            .commit(&connection(), &message_queue)
+            .await;
+        assert!(matches!(result, Err(OzesError::AccessDenied(_))));
+        assert_eq!(depth(&message_queue, "orders").await, 0);
+    }
+
+    #[tokio::test]
+    async fn full_queue_publishes_nothing() {
+        let message_queue = MQueue::new(None, DEFAULT_MAX_DELIVERIES, None, Some(2));
+        let result = transaction(&["invoices", "orders", "orders", "orders"])
+            .commit(&connection(), &message_queue)
+            .await;
+        assert!(matches!(result, Err(OzesError::QueueFull(queue)) if queue == "orders"));
+        assert_eq!(depth(&message_queue, "invoices").await, 0);
+    }
+}
@@ -0,0 +1,80 @@
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io::ErrorKind,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::UnixStream,
+    },
+    path::Path,
+};
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+#[cfg(unix)]
+use super::error::{OzResult, OzesError};
+
+#[derive(Clone, Debug)]
+pub struct UnixConfig {
+    pub path: PathBuf,
+    /// File permissions of the socket, like `0o660`.
+    pub mode: u32,
+}
+
+/// Bind the socket, removing the one left by a previous run. The socket is
+/// bound in a private directory and moved in place once it has its
+/// permissions, so no one can connect while it has the default ones.
+#[cfg(unix)]
+pub(crate) fn bind(config: &UnixConfig) -> OzResult<UnixListener> {
+    remove_stale_socket(&config.path)?;
+    let file_name = config
+        .path
+        .file_name()
+        .ok_or_else(|| {
+            OzesError::UnknownError(format!("invalid socket path {}", config.path.display()))
+        })?
+        .to_string_lossy();
+    let private_dir = config
+        .path
+        .with_file_name(format!(".{file_name}.{}", std::process::id()));
+    if private_dir.is_dir() {
+        fs::remove_dir_all(&private_dir)?;
+    }
+    DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let private_path = private_dir.join(file_name.as_ref());
+    let bound = UnixListener::bind(&private_path)
+        .map_err(OzesError::from)
+        .and_then(|listener| {
+            fs::set_permissions(&private_path, Permissions::from_mode(config.mode))?;
+            fs::rename(&private_path, &config.path)?;
+            Ok(listener)
+        });
+    let _ = fs::remove_dir_all(&private_dir);
+    bound
+}
+
+/// Remove the file at the path only when it is a socket nobody answers on.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> OzResult<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error)?,
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(OzesError::UnknownError(format!(
+            "{} exists and is not a socket",
+            path.display()
+        )));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(OzesError::UnknownError(format!(
+            "{} is in use by another server",
+            path.display()
+        )));
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
@@ -0,0 +1,474 @@
+use std::{net::TcpListener, time::Duration};
+
+use ozes::{
+    client::{ClientConfig, Consumer, Publisher},
+    server::{start_server_with_config, Config, HttpConfig, Strategy, SubscribeOptions},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+/// A port free to listen on.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Start a broker on a free port and return the config of its clients.
+async fn start_broker() -> ClientConfig {
+    start_broker_with(Config::default()).await
+}
+
+async fn start_broker_with(config: Config) -> ClientConfig {
+    let port = free_port();
+    let config = Config { port, ..config };
+    tokio::spawn(start_server_with_config(config));
+    let config = ClientConfig {
+        address: format!("127.0.0.1:{port}"),
+        timeout: Duration::from_secs(2),
+        ..Default::default()
+    };
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(&config.address)
+            .await
+            .is_ok()
+        {
+            return config;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("broker did not start on port {port}");
+}
+
+/// Send a HTTP request and return the status and the body of the response.
+async fn http(port: u16, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let request = format!(
+        "{method} {path} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\ncontent-length: {}\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    time::timeout(
+        Duration::from_secs(10),
+        stream.read_to_string(&mut response),
+    )
+    .await
+    .expect("response")
+    .unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+    (status, body)
+}
+
+/// Send the statement and return the first reply.
+async fn request(mut stream: impl AsyncRead + AsyncWrite + Unpin, statement: &str) -> String {
+    stream.write_all(statement.as_bytes()).await.unwrap();
+    let mut reply = vec![0; 64 * 1024];
+    let read = time::timeout(Duration::from_secs(2), stream.read(&mut reply))
+        .await
+        .expect("reply")
+        .unwrap();
+    String::from_utf8_lossy(&reply[..read]).into_owned()
+}
+
+#[tokio::test]
+async fn publish_consume_ack() {
+    let config = start_broker().await;
+    let mut publisher = Publisher::connect(config.clone(), "orders").await.unwrap();
+    publisher.publish("first").await.unwrap();
+    publisher.publish("second").await.unwrap();
+
+    let mut consumer =
+        Consumer::subscribe(config, "orders", "billing", SubscribeOptions::default())
+            .await
+            .unwrap();
+    for expected in ["first", "second"] {
+        let delivery = time::timeout(Duration::from_secs(5), consumer.recv())
+            .await
+            .expect("delivery")
+            .unwrap();
+        assert_eq!(&delivery.payload()[..], expected.as_bytes());
+        delivery.ack().await.unwrap();
+    }
+    // acked messages are not delivered again
+    assert!(time::timeout(Duration::from_secs(1), consumer.recv())
+        .await
+        .is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn closed_connections_leave_the_registry() {
+    use ozes::server::UnixConfig;
+    use tokio::net::UnixStream;
+
+    let path = std::env::temp_dir().join(format!("ozes-test-{}.sock", free_port()));
+    let config = start_broker_with(Config {
+        unix: Some(UnixConfig {
+            path: path.clone(),
+            mode: 0o600,
+        }),
+        ..Default::default()
+    })
+    .await;
+    let connections = || async {
+        let stream = UnixStream::connect(&path).await.unwrap();
+        request(stream, "CONNECTIONS;").await
+    };
+    let publisher = Publisher::connect(config.clone(), "orders").await.unwrap();
+    assert!(connections().await.contains(r#""role":"publisher""#));
+    drop(publisher);
+    for _ in 0..50 {
+        if !connections().await.contains(r#""role":"publisher""#) {
+            return;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the closed publisher is still registered");
+}
+
+#[tokio::test]
+async fn admin_commands_need_an_admin() {
+    let config = start_broker().await;
+    let stream = TcpStream::connect(&config.address).await.unwrap();
+    assert!(request(stream, "CONNECTIONS;")
+        .await
+        .starts_with("error #ACCESS_DENIED"));
+    let stream = TcpStream::connect(&config.address).await.unwrap();
+    assert!(request(stream, "STATS;").await.starts_with("ok stats"));
+}
+
+#[tokio::test]
+async fn slow_consumer_does_not_hold_the_group() {
+    let config = start_broker().await;
+    let options = SubscribeOptions {
+        strategy: Strategy::LeastOutstanding,
+        ack_timeout: Duration::from_secs(30),
+        ..Default::default()
+    };
+    let mut first = Consumer::subscribe(config.clone(), "orders", "billing", options)
+        .await
+        .unwrap();
+    let mut second = Consumer::subscribe(config.clone(), "orders", "billing", options)
+        .await
+        .unwrap();
+    let mut publisher = Publisher::connect(config, "orders").await.unwrap();
+    publisher.publish("slow").await.unwrap();
+    publisher.publish("fast").await.unwrap();
+
+    // the consumer of a message holds it without answer, the other one
+    // still receives the next message
+    let (mut held, mut other) = time::timeout(Duration::from_secs(5), async {
+        (first.recv().await.unwrap(), second.recv().await.unwrap())
+    })
+    .await
+    .expect("a delivery while the other consumer holds its message");
+    if held.payload() == "fast" {
+        std::mem::swap(&mut held, &mut other);
+    }
+    assert_eq!(&held.payload()[..], b"slow");
+    assert_eq!(&other.payload()[..], b"fast");
+    other.ack().await.unwrap();
+    held.ack().await.unwrap();
+}
+
+#[tokio::test]
+async fn slow_partition_does_not_hold_the_others() {
+    let config = start_broker().await;
+    let options = SubscribeOptions {
+        strategy: Strategy::LeastOutstanding,
+        ack_timeout: Duration::from_secs(30),
+        partitions: Some(64),
+        ..Default::default()
+    };
+    let mut first = Consumer::subscribe(config.clone(), "orders", "billing", options)
+        .await
+        .unwrap();
+    let mut second = Consumer::subscribe(config.clone(), "orders", "billing", options)
+        .await
+        .unwrap();
+    let mut publisher = Publisher::connect(config, "orders").await.unwrap();
+    publisher.publish_with_key("a1", Some("a")).await.unwrap();
+    publisher.publish_with_key("a2", Some("a")).await.unwrap();
+    publisher.publish_with_key("b1", Some("b")).await.unwrap();
+
+    // the other partition is delivered while the first message of the key
+    // waits its answer, the next message of the key is not
+    let (mut held, mut other) = time::timeout(Duration::from_secs(5), async {
+        (first.recv().await.unwrap(), second.recv().await.unwrap())
+    })
+    .await
+    .expect("a delivery of each partition");
+    if held.payload() == "b1" {
+        std::mem::swap(&mut held, &mut other);
+    }
+    assert_eq!(&held.payload()[..], b"a1");
+    assert_eq!(&other.payload()[..], b"b1");
+    other.ack().await.unwrap();
+    let next = time::timeout(Duration::from_millis(500), async {
+        tokio::select! {
+            delivery = first.recv() => delivery,
+            delivery = second.recv() => delivery,
+        }
+    });
+    assert!(next.await.is_err());
+    held.ack().await.unwrap();
+    let delivery = time::timeout(Duration::from_secs(5), async {
+        tokio::select! {
+            delivery = first.recv() => delivery.unwrap(),
+            delivery = second.recv() => delivery.unwrap(),
+        }
+    })
+    .await
+    .expect("the next message of the key");
+    assert_eq!(&delivery.payload()[..], b"a2");
+    delivery.ack().await.unwrap();
+}
+
+#[tokio::test]
+async fn http_gateway_acks_on_the_http_ack() {
+    let port = free_port();
+    let http_config = HttpConfig {
+        port,
+        ack_timeout: Duration::from_secs(1),
+    };
+    start_broker_with(Config {
+        http: Some(http_config),
+        ..Default::default()
+    })
+    .await;
+    let messages = "/queues/orders/groups/billing/messages";
+    assert_eq!(http(port, "GET", messages, "").await.0, 404);
+    let (status, _) = http(
+        port,
+        "POST",
+        "/queues/orders/messages",
+        r#"{"payload":"Zm9v"}"#,
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    let (status, body) = http(port, "GET", &format!("{messages}?wait=5"), "").await;
+    assert_eq!(status, 200);
+    assert!(
+        body.contains(r#""payload":"Zm9v","redelivered":false"#),
+        "{body}"
+    );
+    // not acked in time, the message is sent again
+    let (_, body) = http(port, "GET", &format!("{messages}?wait=10"), "").await;
+    assert!(
+        body.contains(r#""payload":"Zm9v","redelivered":true"#),
+        "{body}"
+    );
+    let id = body
+        .split_once(r#""id":"#)
+        .and_then(|(_, rest)| rest.split_once(','))
+        .unwrap()
+        .0;
+    let ack = format!("{messages}/{id}/ack");
+    assert_eq!(http(port, "POST", &ack, "").await.0, 200);
+    assert_eq!(http(port, "POST", &ack, "").await.0, 404);
+    let (_, body) = http(port, "GET", &format!("{messages}?wait=2"), "").await;
+    assert_eq!(body, r#"{"messages":[]}"#);
+}
+
+#[cfg(feature = "gzip")]
+#[tokio::test]
+async fn compressed_messages() {
+    use ozes::{compression::Compression, server::ErrorCode};
+
+    let config = start_broker().await;
+    let publisher = Publisher::connect(config.clone(), "orders").await.unwrap();
+    let mut publisher = publisher.with_compression(Compression::Gzip);
+    publisher.publish("compressed").await.unwrap();
+
+    // a consumer without ACCEPT ENCODING receives the payload decompressed
+    let mut stream = TcpStream::connect(&config.address).await.unwrap();
+    stream
+        .write_all(b"SUBSCRIBE orders WITH GROUP billing;")
+        .await
+        .unwrap();
+    let mut received = vec![];
+    time::timeout(Duration::from_secs(5), async {
+        while !received.ends_with(b" #compressed") {
+            stream.read_buf(&mut received).await.unwrap();
+        }
+    })
+    .await
+    .expect("decompressed delivery");
+
+    // the broker cannot decompress a compression it is built without
+    if !Compression::Zstd.is_supported() {
+        let mut publisher = Publisher::connect(config, "orders").await.unwrap();
+        let error = publisher
+            .publish_with_headers("hello", None, &[("content-encoding", "zstd")])
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), Some(ErrorCode::UnsupportedEncoding));
+    }
+}
+
+#[tokio::test]
+async fn auth_failures_lock_the_address_out() {
+    use ozes::server::AuthConfig;
+
+    let auth = AuthConfig {
+        tokens: [(String::from("secret"), String::from("alice"))].into(),
+        ..Default::default()
+    };
+    let config = start_broker_with(Config {
+        auth: Some(auth),
+        ..Default::default()
+    })
+    .await;
+    let auth = |token: &'static str| {
+        let address = config.address.clone();
+        async move {
+            let stream = TcpStream::connect(address).await.unwrap();
+            request(stream, &format!("AUTH TOKEN {token};")).await
+        }
+    };
+    assert_eq!(auth("secret").await, "ok auth");
+    for _ in 0..10 {
+        assert!(auth("wrong").await.starts_with("error #UNAUTHORIZED"));
+    }
+    assert!(auth("secret").await.starts_with("error #UNAUTHORIZED"));
+}
+
+#[tokio::test]
+async fn full_queue_refuses_messages() {
+    use ozes::client::ClientError;
+
+    let config = start_broker_with(Config {
+        max_queue_messages: Some(1),
+        ..Default::default()
+    })
+    .await;
+    let mut publisher = Publisher::connect(config, "orders").await.unwrap();
+    publisher.publish("first").await.unwrap();
+    let error = publisher.publish("second").await.unwrap_err();
+    assert!(matches!(error, ClientError::QueueFull(queue) if queue == "orders"));
+}
+
+#[tokio::test]
+async fn transactions_publish_on_commit_only() {
+    let config = start_broker().await;
+    let mut publisher = Publisher::connect(config.clone(), "orders").await.unwrap();
+    let mut consumer =
+        Consumer::subscribe(config, "orders", "billing", SubscribeOptions::default())
+            .await
+            .unwrap();
+
+    publisher.begin().await.unwrap();
+    publisher.publish("dropped").await.unwrap();
+    assert!(time::timeout(Duration::from_millis(500), consumer.recv())
+        .await
+        .is_err());
+    publisher.rollback().await.unwrap();
+
+    publisher.begin().await.unwrap();
+    publisher.publish("committed").await.unwrap();
+    publisher.commit().await.unwrap();
+    let delivery = time::timeout(Duration::from_secs(5), consumer.recv())
+        .await
+        .expect("delivery")
+        .unwrap();
+    assert_eq!(&delivery.payload()[..], b"committed");
+    delivery.ack().await.unwrap();
+    // the rolled back message is never delivered
+    assert!(time::timeout(Duration::from_secs(1), consumer.recv())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn given_up_messages_are_dead_lettered() {
+    let config = start_broker_with(Config {
+        max_deliveries: 1,
+        dead_letter_suffix: Some(".dlq".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let mut publisher = Publisher::connect(config.clone(), "orders").await.unwrap();
+    publisher.publish("poison").await.unwrap();
+
+    let mut consumer = Consumer::subscribe(
+        config.clone(),
+        "orders",
+        "billing",
+        SubscribeOptions::default(),
+    )
+    .await
+    .unwrap();
+    let delivery = time::timeout(Duration::from_secs(5), consumer.recv())
+        .await
+        .expect("delivery")
+        .unwrap();
+    delivery.nack(true).await.unwrap();
+
+    let mut dead_letters =
+        Consumer::subscribe(config, "orders.dlq", "billing", SubscribeOptions::default())
+            .await
+            .unwrap();
+    let delivery = time::timeout(Duration::from_secs(5), dead_letters.recv())
+        .await
+        .expect("dead letter")
+        .unwrap();
+    assert_eq!(&delivery.payload()[..], b"poison");
+    delivery.ack().await.unwrap();
+    // the given up message is not sent to the group again
+    assert!(time::timeout(Duration::from_secs(1), consumer.recv())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn silent_consumers_are_dropped() {
+    use ozes::server::HeartbeatConfig;
+
+    let config = start_broker_with(Config {
+        heartbeat: HeartbeatConfig {
+            interval: Duration::from_millis(100),
+            miss_threshold: 3,
+        },
+        ..Default::default()
+    })
+    .await;
+    // a consumer asking for heartbeats that never answers them
+    let mut silent = TcpStream::connect(&config.address).await.unwrap();
+    assert!(
+        request(&mut silent, "CLIENT NAME silent VERSION 1 HEARTBEAT;")
+            .await
+            .starts_with("ok")
+    );
+    assert!(request(&mut silent, "SUBSCRIBE orders WITH GROUP billing;")
+        .await
+        .starts_with("ok"));
+    let mut publisher = Publisher::connect(config.clone(), "orders").await.unwrap();
+    publisher.publish("first").await.unwrap();
+
+    let mut consumer =
+        Consumer::subscribe(config, "orders", "billing", SubscribeOptions::default())
+            .await
+            .unwrap();
+    let delivery = time::timeout(Duration::from_secs(5), consumer.recv())
+        .await
+        .expect("delivery")
+        .unwrap();
+    assert_eq!(&delivery.payload()[..], b"first");
+    delivery.ack().await.unwrap();
+
+    // the broker closes the silent connection after its pings
+    let mut buffer = vec![0; 1024];
+    let closed = time::timeout(Duration::from_secs(5), async {
+        while silent.read(&mut buffer).await.unwrap_or(0) > 0 {}
+    })
+    .await;
+    assert!(closed.is_ok());
+}